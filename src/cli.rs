@@ -1,10 +1,11 @@
 use std::{
-    net::{IpAddr, Ipv6Addr, SocketAddr},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
     path::PathBuf,
     time::Duration,
 };
 
 use clap::{arg, command, Parser, Subcommand};
+use moproxy::policy::AppProtocol;
 use tracing::metadata::LevelFilter;
 
 #[derive(Parser, Debug)]
@@ -15,15 +16,26 @@ pub(crate) struct CliArgs {
     #[arg(default_value_t = Ipv6Addr::UNSPECIFIED.into())]
     pub(crate) host: IpAddr,
 
-    /// Port number to bind on. Multiple ports can be delimited by comma (,)
-    #[arg(
-        short = 'p',
-        long,
-        value_name = "PORTS",
-        required = true,
-        value_delimiter = ','
-    )]
-    pub(crate) port: Vec<u16>,
+    /// Port number to bind on, or path of Unix domain socket for SOCKS &
+    /// HTTP proxy. Multiple ports can be delimited by comma (,). Can be
+    /// omitted if sockets are passed by systemd (socket activation).
+    #[arg(short = 'p', long, value_name = "PORTS", value_delimiter = ',')]
+    #[arg(value_parser = parse_port_or_path)]
+    pub(crate) port: Vec<PortOrPath>,
+
+    /// Port number to bind on for connections with PROXY protocol v1/v2
+    /// header (e.g. from HAProxy), which is required on every connection.
+    /// Destination and client address are taken from the header. Multiple
+    /// ports can be delimited by comma (,)
+    #[arg(long, value_name = "PORTS", value_delimiter = ',')]
+    pub(crate) proxy_protocol_port: Vec<u16>,
+
+    /// Port number to bind on for TPROXY (`iptables -j TPROXY` or
+    /// `nft tproxy`), both TCP & UDP. Multiple ports can be delimited by
+    /// comma (,)
+    #[cfg(target_os = "linux")]
+    #[arg(long, value_name = "PORTS", value_delimiter = ',')]
+    pub(crate) tproxy_port: Vec<u16>,
 
     /// SOCKSv5 server list. IP address can omit for localhost.
     #[arg(
@@ -53,11 +65,23 @@ pub(crate) struct CliArgs {
     #[arg(long = "policy", value_name = "POLICY")]
     pub(crate) policy: Option<PathBuf>,
 
+    /// File of SOCKSv5 users, `username:password` per line.
+    /// Once set, SOCKSv5 clients must authenticate with username/password
+    /// (RFC 1929), and HTTP proxy clients with basic auth. Reload on SIGHUP.
+    #[arg(long = "socks-users", value_name = "USERS")]
+    pub(crate) socks_users: Option<PathBuf>,
+
     /// Period of time to make one probe.
     #[arg(short = 'i', long = "probe", value_name = "SECONDS")]
     #[arg(default_value_t = 30)]
     pub(crate) probe_secs: u64,
 
+    /// Period of time to re-resolve upstreams whose address is a host
+    /// name. Set 0 to disable.
+    #[arg(long = "resolve-interval", value_name = "SECONDS")]
+    #[arg(default_value_t = 300)]
+    pub(crate) resolve_secs: u64,
+
     /// Address of a DNS server with TCP support to do delay probing.
     #[arg(long, value_name = "IP-ADDR:PORT", default_value = "8.8.8.8:53")]
     pub(crate) test_dns: SocketAddr,
@@ -67,11 +91,25 @@ pub(crate) struct CliArgs {
     #[arg(long = "stats-bind", value_name = "IP-ADDR:PORT")]
     pub(crate) web_bind: Option<String>,
 
-    /// Try to obtain domain name from TLS SNI, and sent it to remote
-    /// proxy server. Only apply for port number 443.
+    /// Try to obtain domain name from TLS SNI or HTTP Host header, and
+    /// sent it to remote proxy server. Only apply for ports listed in
+    /// --sniff-ports.
     #[arg(long)]
     pub(crate) remote_dns: bool,
 
+    /// Destination ports on which TLS SNI and HTTP Host header are sniffed
+    /// for --remote-dns, --n-parallel and domain name, protocol and ALPN
+    /// policy rules.
+    /// Multiple ports can be delimited by comma (,), or "all" for any port.
+    #[arg(
+        long,
+        value_name = "PORTS",
+        value_delimiter = ',',
+        default_value = "443"
+    )]
+    #[arg(value_parser = parse_port_or_all)]
+    pub(crate) sniff_ports: Vec<PortOrAll>,
+
     /// Connect and send application data to N proxies in parallel, use
     /// the first proxy that return valid data. Currently only support
     /// TLS as application layer. Must turn on --remote-dns otherwise it
@@ -79,6 +117,59 @@ pub(crate) struct CliArgs {
     #[arg(long, value_name = "N", default_value_t = 0)]
     pub(crate) n_parallel: usize,
 
+    /// Hedged connect: start with the best proxy, then the next one if
+    /// it hasn't connected (or responded, for TLS) within its 90th
+    /// percentile probe delay. Up to max(2, --n-parallel) proxies are tried
+    /// at once for TLS, or 2 for other connections, the slower ones are
+    /// cancelled. Non-TLS connections with data pending (e.g. HTTP) are
+    /// never hedged.
+    #[arg(long)]
+    pub(crate) hedge: bool,
+
+    /// If a proxy closes the connection without sending anything back
+    /// after client's first data (e.g. TLS client hello) was sent, replay
+    /// that data to at most N other proxies. Only TLS client hello without
+    /// early data or HTTP request of idempotent method is replayed.
+    /// Disabled (0) by default.
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    pub(crate) max_retries: usize,
+
+    /// Where the fake-IP DNS server (UDP) bind. It answers every A/AAAA
+    /// query with an address from --fake-ip-pool, so connections to that
+    /// address can be mapped back to the domain name.
+    #[arg(long, value_name = "IP-ADDR:PORT")]
+    pub(crate) fake_dns_bind: Option<SocketAddr>,
+
+    /// IPv4 network reserved for fake-IP DNS.
+    #[arg(long, value_name = "CIDR", default_value = "198.18.0.0/15")]
+    #[arg(value_parser = parse_ipv4_net)]
+    pub(crate) fake_ip_pool: (Ipv4Addr, u8),
+
+    /// IPv6 network reserved for fake-IP DNS. AAAA queries get empty
+    /// answer if not set.
+    #[arg(long, value_name = "CIDR", value_parser = parse_ipv6_net)]
+    pub(crate) fake_ipv6_pool: Option<(Ipv6Addr, u8)>,
+
+    /// Max number of domain names kept by fake-IP DNS for each IP version,
+    /// least recently used ones are recycled.
+    #[arg(long, value_name = "N", default_value_t = 65536)]
+    pub(crate) fake_ip_capacity: usize,
+
+    /// Where the DNS forwarder (UDP & TCP) bind. Queries are forwarded to
+    /// --dns-upstream, addresses in answers are recorded with the queried
+    /// domain name, so domain name policy rules apply to connections to
+    /// these addresses.
+    #[arg(long, value_name = "IP-ADDR:PORT", requires = "dns_upstream")]
+    pub(crate) dns_snoop_bind: Option<SocketAddr>,
+
+    /// DNS server which --dns-snoop-bind forwards queries to.
+    #[arg(long, value_name = "IP-ADDR:PORT")]
+    pub(crate) dns_upstream: Option<SocketAddr>,
+
+    /// Max number of addresses recorded by DNS snooping.
+    #[arg(long, value_name = "N", default_value_t = 65536)]
+    pub(crate) dns_cache_capacity: usize,
+
     /// Set TCP congestion control algorithm on local (client) side.
     #[cfg(target_os = "linux")]
     #[arg(long = "congestion-local", value_name = "ALG-NAME")]
@@ -88,6 +179,30 @@ pub(crate) struct CliArgs {
     #[arg(long)]
     pub(crate) allow_direct: bool,
 
+    /// Send PROXY protocol v2 header with client's address on direct
+    /// connections, for destinations that accept it.
+    #[arg(long)]
+    pub(crate) direct_proxy_protocol: bool,
+
+    /// Source address of outbound connections (TCP & UDP), to proxies or
+    /// direct to destinations, also DNS snooping upstream. Overridden by
+    /// `bind address` on the server list.
+    #[arg(long, value_name = "IP-ADDRESS")]
+    pub(crate) bind_address: Option<IpAddr>,
+
+    /// Bind outbound connections to the interface (SO_BINDTODEVICE).
+    /// Overridden by `bind interface` on the server list.
+    #[cfg(target_os = "linux")]
+    #[arg(long, value_name = "INTERFACE")]
+    pub(crate) bind_interface: Option<String>,
+
+    /// Set firewall mark (SO_MARK) on outbound connections, e.g. to avoid
+    /// routing loops with redirect rules. Decimal or hex (0x...).
+    /// Overridden by `fwmark` on the server list.
+    #[cfg(target_os = "linux")]
+    #[arg(long, value_name = "MARK", value_parser = parse_fwmark)]
+    pub(crate) fwmark: Option<u32>,
+
     /// Send metrics to graphite (carbon) daemon in plaintext format with
     /// TCP.
     #[arg(long, value_name = "IP-ADDR:PORT")]
@@ -112,6 +227,28 @@ pub(crate) struct CliArgs {
     pub(crate) command: Option<Commands>,
 }
 
+impl CliArgs {
+    pub(crate) fn is_sniff_port(&self, port: u16) -> bool {
+        self.sniff_ports
+            .iter()
+            .any(|p| matches!(p, PortOrAll::All) || *p == PortOrAll::Port(port))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) enum PortOrPath {
+    Port(u16),
+    /// Unix domain socket
+    #[cfg(unix)]
+    Path(PathBuf),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum PortOrAll {
+    All,
+    Port(u16),
+}
+
 #[derive(Debug, Subcommand)]
 pub(crate) enum Commands {
     /// Load & check configure and then exit
@@ -138,6 +275,12 @@ pub(crate) enum PolicyCommands {
         dst_ip: Option<IpAddr>,
         #[arg(long)]
         dst_domain: Option<String>,
+        #[arg(long)]
+        user: Option<String>,
+        #[arg(long)]
+        protocol: Option<AppProtocol>,
+        #[arg(long, value_delimiter = ',')]
+        alpn: Vec<String>,
     },
 }
 
@@ -147,6 +290,53 @@ fn parse_duration_in_seconds(s: &str) -> Result<Duration, String> {
         .map(Duration::from_secs)
 }
 
+fn parse_port_or_path(s: &str) -> Result<PortOrPath, String> {
+    if let Ok(port) = s.parse() {
+        return Ok(PortOrPath::Port(port));
+    }
+    #[cfg(unix)]
+    if s.contains('/') {
+        return Ok(PortOrPath::Path(s.into()));
+    }
+    Err(format!("`{}` is neither a port number nor a path", s))
+}
+
+fn parse_port_or_all(s: &str) -> Result<PortOrAll, String> {
+    if s.eq_ignore_ascii_case("all") {
+        Ok(PortOrAll::All)
+    } else {
+        s.parse()
+            .map(PortOrAll::Port)
+            .map_err(|_| format!("`{}` is neither a port number nor `all`", s))
+    }
+}
+
+pub(crate) fn parse_fwmark(s: &str) -> Result<u32, String> {
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => s.parse(),
+    }
+    .map_err(|_| format!("`{}` isn't a valid mark", s))
+}
+
+fn parse_ip_net<T: std::str::FromStr>(s: &str, max_len: u8) -> Result<(T, u8), String> {
+    let err = || format!("`{}` isn't a valid CIDR", s);
+    let (ip, len) = s.split_once('/').ok_or_else(err)?;
+    let len: u8 = len.parse().map_err(|_| err())?;
+    if len > max_len {
+        return Err(err());
+    }
+    Ok((ip.parse().map_err(|_| err())?, len))
+}
+
+fn parse_ipv4_net(s: &str) -> Result<(Ipv4Addr, u8), String> {
+    parse_ip_net(s, 32)
+}
+
+fn parse_ipv6_net(s: &str) -> Result<(Ipv6Addr, u8), String> {
+    parse_ip_net(s, 128)
+}
+
 fn parse_socket_addr_default_on_localhost(addr: &str) -> Result<SocketAddr, String> {
     if addr.contains(':') {
         addr.parse()
@@ -161,3 +351,11 @@ fn verify_cli() {
     use clap::CommandFactory;
     CliArgs::command().debug_assert()
 }
+
+#[test]
+fn test_parse_fwmark() {
+    assert_eq!(Ok(100), parse_fwmark("100"));
+    assert_eq!(Ok(0xff00), parse_fwmark("0xff00"));
+    assert!(parse_fwmark("0x").is_err());
+    assert!(parse_fwmark("mark").is_err());
+}
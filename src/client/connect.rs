@@ -7,67 +7,99 @@ use std::{
     sync::Arc,
     task::{Context, Poll},
 };
-use tokio::{net::TcpStream, time::timeout};
-use tracing::{info, instrument};
+use tokio::time::{sleep, timeout, Duration, Sleep};
+use tracing::{debug, info, instrument};
 
-use crate::proxy::{Destination, ProxyServer};
+use super::tls_parser::is_tls_response;
+use crate::proxy::{proxy_protocol::ProxyHeader, stream::ServerStream, Destination, ProxyServer};
+
+/// Hedging delay for servers without probe history.
+const DEFAULT_HEDGE_DELAY: Duration = Duration::from_millis(500);
 
 #[derive(Debug, Clone)]
 struct Request {
     dest: Destination,
     pending_data: Option<Bytes>,
     wait_response: bool,
+    client: Option<ProxyHeader>,
 }
 
 #[instrument(skip_all, fields(proxy = %server.tag))]
-async fn try_connect(request: Request, server: Arc<ProxyServer>) -> io::Result<TcpStream> {
+async fn try_connect(request: Request, server: Arc<ProxyServer>) -> io::Result<ServerStream> {
     let max_wait = server.max_wait();
     // waiting for proxy server connected
-    let stream = timeout(
+    let mut stream = timeout(
         max_wait,
-        server.connect(&request.dest, request.pending_data),
+        server.connect(&request.dest, request.pending_data, request.client.as_ref()),
     )
     .await??;
 
     // waiting for response data
     if request.wait_response {
-        let mut buf = [0u8; 4];
+        let mut buf = [0u8; 5];
         let len = timeout(max_wait, stream.peek(&mut buf)).await??;
         if len == 0 {
             return Err(io::Error::new(ErrorKind::UnexpectedEof, "no response data"));
         }
+        // Only TLS is raced, reject error pages, captive portals, etc.
+        if !is_tls_response(&buf[..len]) {
+            server.update_stats_bad_response();
+            return Err(io::Error::new(ErrorKind::InvalidData, "bad response"));
+        }
     }
     Ok(stream)
 }
 
-type PinnedConnectFuture = Pin<Box<dyn Future<Output = io::Result<TcpStream>> + Send>>;
+type PinnedConnectFuture = Pin<Box<dyn Future<Output = io::Result<ServerStream>> + Send>>;
 
 /// Try to connect one of the proxy servers.
 /// Pick `parallel_n` servers from `queue` to `connecting` and wait for
 /// connect. Once any of them connected, move that to `reading` and wait
 /// for read respone. Once any of handshakings done, return it and cancel
 /// others.
+///
+/// In hedging mode, servers are started one by one instead: the next one
+/// is started only if the last one hasn't done within its p90 probe delay,
+/// or any of them failed. Still, at most `parallel_n` are tried at once.
 pub struct TryConnectAll {
     request: Request,
     parallel_n: usize,
     standby: VecDeque<Arc<ProxyServer>>,
     connects: VecDeque<(Arc<ProxyServer>, PinnedConnectFuture)>,
     last_error: Option<io::Error>,
+    hedge: bool,
+    /// Timer to start the next server, `None` to start it right now.
+    hedge_timer: Option<Pin<Box<Sleep>>>,
 }
 
+/// Hedging is only safe if no application data is sent, or the data
+/// can be duplicated (TLS client hello) and the winner is decided by
+/// response.
 pub fn try_connect_all(
     dest: &Destination,
     servers: Vec<Arc<ProxyServer>>,
     parallel_n: usize,
     wait_response: bool,
     pending_data: Option<Bytes>,
+    client: Option<ProxyHeader>,
+    hedge: bool,
 ) -> TryConnectAll {
-    let parallel_n = parallel_n.clamp(1, if wait_response { servers.len() } else { 1 });
+    let hedge = hedge && (wait_response || pending_data.is_none());
+    let parallel_n = if hedge {
+        parallel_n.max(2)
+    } else if wait_response {
+        parallel_n
+    } else {
+        1
+    }
+    .min(servers.len())
+    .max(1);
     let servers = servers.into_iter().collect();
     let request = Request {
         dest: dest.clone(),
         pending_data,
         wait_response,
+        client,
     };
     TryConnectAll {
         request,
@@ -75,18 +107,32 @@ pub fn try_connect_all(
         standby: servers,
         connects: VecDeque::with_capacity(parallel_n),
         last_error: None,
+        hedge,
+        hedge_timer: None,
     }
 }
 
 impl Future for TryConnectAll {
-    type Output = io::Result<(Arc<ProxyServer>, TcpStream)>;
+    type Output = io::Result<(Arc<ProxyServer>, ServerStream)>;
 
     fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
         loop {
             // if current connections less than parallel_n,
             // pick servers from queue to connect.
             while !self.standby.is_empty() && self.connects.len() < self.parallel_n {
+                if let Some(timer) = &mut self.hedge_timer {
+                    if timer.as_mut().poll(cx).is_pending() {
+                        break;
+                    }
+                }
                 let server = self.standby.pop_front().unwrap();
+                if self.hedge {
+                    let delay = server.p90_delay().unwrap_or(DEFAULT_HEDGE_DELAY);
+                    if !self.connects.is_empty() {
+                        debug!(proxy = %server.tag, "Hedging with next proxy");
+                    }
+                    self.hedge_timer = Some(Box::pin(sleep(delay)));
+                }
                 let conn = try_connect(self.request.clone(), server.clone());
                 self.connects.push_back((server, Box::pin(conn)));
             }
@@ -101,6 +147,8 @@ impl Future for TryConnectAll {
                         info!(proxy = %server.tag, ?err, "Failed to connect upstream proxy");
                         self.last_error = Some(err);
                         drop(self.connects.remove(i));
+                        // start next one without waiting
+                        self.hedge_timer = None;
                     }
                     // not ready, keep here, poll next one.
                     Poll::Pending => i += 1,
@@ -118,9 +166,60 @@ impl Future for TryConnectAll {
             }
 
             // if not need to connect standby server, wait for events.
-            if self.connects.len() >= self.parallel_n || self.standby.is_empty() {
+            if self.connects.len() >= self.parallel_n
+                || self.standby.is_empty()
+                || self.hedge_timer.is_some()
+            {
                 return Poll::Pending;
             }
         }
     }
 }
+
+#[tokio::test]
+async fn test_hedge() {
+    use crate::test_util::{fake_socks4, refused_addr, socks4_server};
+    use std::net::SocketAddr;
+    use tokio::{io::AsyncReadExt, time::Instant};
+
+    // Whether the connection is then closed by us
+    let closed = |mut stream: tokio::net::TcpStream| async move {
+        matches!(stream.read(&mut [0u8; 1]).await, Ok(0))
+    };
+
+    let dest: Destination = "192.0.2.1:80".parse::<SocketAddr>().unwrap().into();
+
+    // The next one is started after the delay, the slow one is dropped
+    let (slow, slow_task) = fake_socks4(false, closed).await;
+    let (fast, _) = fake_socks4(true, closed).await;
+    let start = Instant::now();
+    let (server, _stream) =
+        try_connect_all(&dest, vec![slow, fast.clone()], 1, false, None, None, true)
+            .await
+            .unwrap();
+    assert!(Arc::ptr_eq(&fast, &server));
+    assert!(start.elapsed() >= DEFAULT_HEDGE_DELAY);
+    assert!(timeout(Duration::from_secs(1), slow_task)
+        .await
+        .unwrap()
+        .unwrap());
+
+    // The next one is started right away if the first one failed
+    let (refused, _socket) = refused_addr();
+    let refused = socks4_server(refused);
+    let (fast, _) = fake_socks4(true, closed).await;
+    let start = Instant::now();
+    let (server, _stream) = try_connect_all(
+        &dest,
+        vec![refused, fast.clone()],
+        1,
+        false,
+        None,
+        None,
+        true,
+    )
+    .await
+    .unwrap();
+    assert!(Arc::ptr_eq(&fast, &server));
+    assert!(start.elapsed() < DEFAULT_HEDGE_DELAY);
+}
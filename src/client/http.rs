@@ -0,0 +1,299 @@
+use base64::prelude::{Engine, BASE64_STANDARD};
+use bytes::{BufMut, Bytes, BytesMut};
+use flexstr::SharedStr;
+use http::{uri::Authority, Uri};
+use httparse::{Header, Request, Status, EMPTY_HEADER};
+use std::{io, net::IpAddr};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tracing::{info, instrument, trace};
+
+use super::{error_invalid_input, users::UserList, ClientStream};
+use crate::proxy::{Address, Destination};
+
+const MAX_HEADER_LEN: usize = 8192;
+const MAX_HEADERS: usize = 64;
+
+const STATUS_BAD_REQUEST: &str = "400 Bad Request";
+const STATUS_FORBIDDEN: &str = "403 Forbidden";
+const STATUS_PROXY_AUTH_REQUIRED: &str = "407 Proxy Authentication Required";
+const STATUS_HEADER_TOO_LARGE: &str = "431 Request Header Fields Too Large";
+const STATUS_BAD_GATEWAY: &str = "502 Bad Gateway";
+const STATUS_GATEWAY_TIMEOUT: &str = "504 Gateway Timeout";
+
+/// Hop-by-hop headers that must not be forwarded to the destination.
+const HOP_BY_HOP_HEADERS: [&str; 4] = [
+    "connection",
+    "keep-alive",
+    "proxy-authorization",
+    "proxy-connection",
+];
+
+#[derive(Debug)]
+pub(super) struct HttpRequest {
+    pub dest: Destination,
+    pub username: Option<SharedStr>,
+    /// True for CONNECT, false for plain HTTP forwarding.
+    pub tunnel: bool,
+    /// Data to be sent to the destination once connected. For plain HTTP,
+    /// it's the rewritten request.
+    pub pending_data: Option<Bytes>,
+}
+
+type Rejection = (&'static str, &'static str);
+
+/// Is `byte` possibly the first one of an HTTP request line?
+pub(super) fn is_request_line(byte: u8) -> bool {
+    byte.is_ascii_uppercase()
+}
+
+/// Accept HTTP proxy request, `first` byte has been consumed by the caller.
+#[instrument(skip_all)]
+pub(super) async fn accept_http(
+    client: &mut ClientStream,
+    first: u8,
+    users: Option<&UserList>,
+) -> io::Result<HttpRequest> {
+    // TODO: add timeout
+    let mut buf = BytesMut::with_capacity(1024);
+    buf.put_u8(first);
+    loop {
+        if client.read_buf(&mut buf).await? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let result = match parse_request(&buf, users) {
+            Ok(None) if buf.len() < MAX_HEADER_LEN => continue,
+            Ok(None) => Err((STATUS_HEADER_TOO_LARGE, "HTTP: request header too large")),
+            Ok(Some(request)) => Ok(request),
+            Err(rejection) => Err(rejection),
+        };
+        return match result {
+            Ok(request) => Ok(request),
+            Err((status, msg)) => {
+                client.write_all(&build_response(status)).await?;
+                error_invalid_input(msg)
+            }
+        };
+    }
+}
+
+/// Parse request from client, return `None` if it's incomplete.
+fn parse_request(data: &[u8], users: Option<&UserList>) -> Result<Option<HttpRequest>, Rejection> {
+    let mut headers = [EMPTY_HEADER; MAX_HEADERS];
+    let mut request = Request::new(&mut headers);
+    let header_len = match request.parse(data) {
+        Ok(Status::Complete(len)) => len,
+        Ok(Status::Partial) => return Ok(None),
+        Err(_) => return Err((STATUS_BAD_REQUEST, "HTTP: malformed request")),
+    };
+    let method = request.method.unwrap_or_default();
+    let target = request.path.unwrap_or_default();
+    trace!(method, target, "HTTP request");
+
+    let username = match users {
+        None => None,
+        Some(users) => Some(verify_proxy_auth(request.headers, users).ok_or((
+            STATUS_PROXY_AUTH_REQUIRED,
+            "HTTP: proxy authentication required",
+        ))?),
+    };
+    let body = &data[header_len..];
+
+    if method.eq_ignore_ascii_case("CONNECT") {
+        let authority: Authority = target
+            .parse()
+            .map_err(|_| (STATUS_BAD_REQUEST, "HTTP: invalid CONNECT target"))?;
+        let port = authority
+            .port_u16()
+            .ok_or((STATUS_BAD_REQUEST, "HTTP: missing port in CONNECT target"))?;
+        return Ok(Some(HttpRequest {
+            dest: (parse_host(authority.host()), port).into(),
+            username,
+            tunnel: true,
+            pending_data: (!body.is_empty()).then(|| Bytes::copy_from_slice(body)),
+        }));
+    }
+
+    // Plain HTTP, absolute URI is expected
+    let uri: Uri = target
+        .parse()
+        .map_err(|_| (STATUS_BAD_REQUEST, "HTTP: invalid request target"))?;
+    let authority = match (uri.scheme_str(), uri.authority()) {
+        (Some("http"), Some(authority)) => authority,
+        _ => return Err((STATUS_BAD_REQUEST, "HTTP: absolute http URI is required")),
+    };
+    let port = authority.port_u16().unwrap_or(80);
+
+    // Rewrite into origin-form, one request per connection
+    let path = uri.path_and_query().map(|p| p.as_str()).unwrap_or("/");
+    let mut buf = BytesMut::with_capacity(data.len());
+    buf.extend_from_slice(method.as_bytes());
+    buf.extend_from_slice(b" ");
+    buf.extend_from_slice(path.as_bytes());
+    buf.extend_from_slice(match request.version {
+        Some(0) => b" HTTP/1.0\r\n",
+        _ => b" HTTP/1.1\r\n",
+    });
+    let mut has_host = false;
+    for header in request.headers.iter() {
+        if HOP_BY_HOP_HEADERS
+            .iter()
+            .any(|name| header.name.eq_ignore_ascii_case(name))
+        {
+            continue;
+        }
+        has_host |= header.name.eq_ignore_ascii_case("host");
+        buf.extend_from_slice(header.name.as_bytes());
+        buf.extend_from_slice(b": ");
+        buf.extend_from_slice(header.value);
+        buf.extend_from_slice(b"\r\n");
+    }
+    if !has_host {
+        buf.extend_from_slice(format!("Host: {}\r\n", authority).as_bytes());
+    }
+    buf.extend_from_slice(b"Connection: close\r\n\r\n");
+    buf.extend_from_slice(body);
+
+    Ok(Some(HttpRequest {
+        dest: (parse_host(authority.host()), port).into(),
+        username,
+        tunnel: false,
+        pending_data: Some(buf.freeze()),
+    }))
+}
+
+/// Check `Proxy-Authorization` (basic), return the username if passed.
+fn verify_proxy_auth(headers: &[Header], users: &UserList) -> Option<SharedStr> {
+    let value = headers
+        .iter()
+        .find(|h| h.name.eq_ignore_ascii_case("proxy-authorization"))?
+        .value;
+    let value = std::str::from_utf8(value).ok()?;
+    let (scheme, credential) = value.trim().split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+    let credential = BASE64_STANDARD.decode(credential.trim()).ok()?;
+    let credential = String::from_utf8_lossy(&credential);
+    let (username, password) = credential.split_once(':')?;
+    let result = users.verify(username, password);
+    if result.is_none() {
+        info!(%username, "HTTP proxy auth failed");
+    }
+    result
+}
+
+fn parse_host(host: &str) -> Address {
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    match host.parse::<IpAddr>() {
+        Ok(ip) => Address::Ip(ip),
+        Err(_) => Address::Domain(host.into()),
+    }
+}
+
+pub(super) fn build_response(status: &str) -> Vec<u8> {
+    let auth = if status == STATUS_PROXY_AUTH_REQUIRED {
+        "Proxy-Authenticate: Basic realm=\"moproxy\"\r\n"
+    } else {
+        ""
+    };
+    format!(
+        "HTTP/1.1 {}\r\n{}Content-Length: 0\r\nConnection: close\r\n\r\n",
+        status, auth
+    )
+    .into_bytes()
+}
+
+pub(super) fn build_connect_established() -> &'static [u8] {
+    b"HTTP/1.1 200 Connection established\r\n\r\n"
+}
+
+/// Pick the status code that best describes `err` for HTTP client.
+pub(super) fn error_status(err: &io::Error) -> &'static str {
+    match err.kind() {
+        io::ErrorKind::PermissionDenied => STATUS_FORBIDDEN,
+        io::ErrorKind::TimedOut => STATUS_GATEWAY_TIMEOUT,
+        _ => STATUS_BAD_GATEWAY,
+    }
+}
+
+#[test]
+fn test_parse_connect_request() {
+    let request = parse_request(b"CONNECT [::1]:443 HTTP/1.1\r\n", None).unwrap();
+    assert!(request.is_none());
+    let request = parse_request(
+        b"CONNECT [::1]:443 HTTP/1.1\r\nHost: [::1]:443\r\n\r\n",
+        None,
+    )
+    .unwrap()
+    .unwrap();
+    assert!(request.tunnel);
+    assert_eq!(
+        Destination::from("[::1]:443".parse::<std::net::SocketAddr>().unwrap()),
+        request.dest
+    );
+    assert!(request.pending_data.is_none());
+
+    let request = parse_request(b"CONNECT example.com:443 HTTP/1.1\r\n\r\nhello", None)
+        .unwrap()
+        .unwrap();
+    assert_eq!(Destination::from(("example.com", 443)), request.dest);
+    assert_eq!(Some(Bytes::from_static(b"hello")), request.pending_data);
+
+    let rejection = parse_request(b"CONNECT example.com HTTP/1.1\r\n\r\n", None).unwrap_err();
+    assert_eq!(STATUS_BAD_REQUEST, rejection.0);
+}
+
+#[test]
+fn test_parse_forward_request() {
+    let request = parse_request(
+        b"POST http://example.com:8080/a?b=c HTTP/1.1\r\n\
+          Host: example.com:8080\r\n\
+          Proxy-Connection: keep-alive\r\n\
+          Content-Length: 4\r\n\r\nbody",
+        None,
+    )
+    .unwrap()
+    .unwrap();
+    assert!(!request.tunnel);
+    assert_eq!(Destination::from(("example.com", 8080)), request.dest);
+    assert_eq!(
+        &b"POST /a?b=c HTTP/1.1\r\n\
+           Host: example.com:8080\r\n\
+           Content-Length: 4\r\n\
+           Connection: close\r\n\r\nbody"[..],
+        request.pending_data.unwrap()
+    );
+
+    let request = parse_request(b"GET http://192.0.2.1 HTTP/1.0\r\n\r\n", None)
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        Destination::from("192.0.2.1:80".parse::<std::net::SocketAddr>().unwrap()),
+        request.dest
+    );
+    assert_eq!(
+        &b"GET / HTTP/1.0\r\nHost: 192.0.2.1\r\nConnection: close\r\n\r\n"[..],
+        request.pending_data.unwrap()
+    );
+
+    let rejection = parse_request(b"GET / HTTP/1.1\r\n\r\n", None).unwrap_err();
+    assert_eq!(STATUS_BAD_REQUEST, rejection.0);
+}
+
+#[test]
+fn test_parse_request_with_auth() {
+    let users = UserList::load("alice:secret".as_bytes()).unwrap();
+    let request = b"CONNECT example.com:443 HTTP/1.1\r\n\r\n";
+    let rejection = parse_request(request, Some(&users)).unwrap_err();
+    assert_eq!(STATUS_PROXY_AUTH_REQUIRED, rejection.0);
+
+    let auth = BASE64_STANDARD.encode("alice:secret");
+    let request = format!(
+        "CONNECT example.com:443 HTTP/1.1\r\nProxy-Authorization: Basic {}\r\n\r\n",
+        auth
+    );
+    let request = parse_request(request.as_bytes(), Some(&users))
+        .unwrap()
+        .unwrap();
+    assert_eq!(Some("alice".into()), request.username);
+}
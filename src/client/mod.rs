@@ -1,9 +1,18 @@
 mod connect;
+mod http;
+mod sniff;
+mod socks4;
+mod stream;
 mod tls_parser;
+#[cfg(target_os = "linux")]
+mod tproxy;
+mod udp;
+pub mod users;
 use bytes::{Bytes, BytesMut};
 use flexstr::SharedStr;
 use std::{
     borrow::Cow,
+    collections::VecDeque,
     io,
     net::{IpAddr, SocketAddr},
     sync::Arc,
@@ -11,8 +20,7 @@ use std::{
 };
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
-    net::TcpStream,
-    time::timeout,
+    time::{timeout_at, Instant},
 };
 use tracing::{debug, info, instrument, warn};
 
@@ -20,42 +28,100 @@ use tracing::{debug, info, instrument, warn};
 use crate::linux::tcp::TcpStreamExt;
 use crate::{
     client::connect::try_connect_all,
-    policy::RequestFeatures,
-    proxy::{copy::pipe, Traffic},
-    proxy::{Address, Destination, ProxyServer},
+    dns::{fakeip::FakeIpPool, snoop::DnsCache},
+    policy::{AppProtocol, RequestFeatures},
+    proxy::{copy::pipe, AtomicTraffic, Traffic},
+    proxy::{
+        proxy_protocol::{self, ProxyHeader},
+        socks5::{self, build_reply},
+        stream::ServerStream,
+        Address, Destination, ProxyServer,
+    },
 };
+use sniff::Sniffed;
+pub use stream::ClientStream;
+#[cfg(target_os = "linux")]
+pub use tproxy::serve_tproxy_udp;
+pub use udp::UdpRoute;
+use users::UserList;
 
+/// Give up sniffing TLS ClientHello or HTTP request if it exceeds this size.
+const MAX_SNIFF_LEN: usize = 32 * 1024;
+
+/// Result of peeking into the first bytes sent by client.
 #[derive(Debug, Default)]
-pub struct TlsData {
+pub struct SniffedData {
     pending_data: Option<Bytes>,
     has_full_tls_hello: bool,
-    pub sni: Option<SharedStr>,
+    /// Domain name from TLS SNI or HTTP `Host` header.
+    pub domain: Option<SharedStr>,
+    pub protocol: AppProtocol,
+    /// Protocols offered by TLS ALPN.
+    pub alpn: Vec<SharedStr>,
+    /// TLS versions supported by client.
+    pub tls_versions: Vec<u16>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    Connect,
+    /// SOCKSv5 UDP ASSOCIATE. `dest` is the address client expects to
+    /// send datagrams from, usually unspecified.
+    UdpAssociate,
+}
+
+/// Reply that client is waiting for before it can send anything.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PendingReply {
+    Socks4,
+    Socks5,
+    HttpConnect,
+    /// Plain HTTP request, response comes from the destination.
+    HttpForward,
 }
 
 #[derive(Debug)]
 pub struct NewClient {
-    left: TcpStream,
+    left: ClientStream,
+    pub command: Command,
+    /// Deferred until the outbound connection is settled.
+    pending_reply: Option<PendingReply>,
     /// Destination IP address or domain name with port number.
-    /// Retrived from firewall or SOCKSv5 request initially, may be override
-    /// by TLS SNI.
+    /// Retrived from firewall, SOCKS or HTTP request initially, may be override
+    /// by TLS SNI or HTTP `Host`.
     pub dest: Destination,
-    /// Destination IP address. Unlike `dest`, it won't be override by SNI.
+    /// Destination IP address. Unlike `dest`, it won't be override by sniffing.
     dest_ip_addr: Option<IpAddr>,
-    /// Server's TCP port number.
-    from_port: u16,
-    pub tls: Option<TlsData>,
+    /// Server's TCP port number, `None` for Unix socket.
+    from_port: Option<u16>,
+    /// Client's address carried by PROXY protocol, if it's not the peer.
+    pub src_addr: Option<SocketAddr>,
+    /// Username authenticated by SOCKSv5 or HTTP proxy.
+    pub username: Option<SharedStr>,
+    user_traffic: Option<Arc<AtomicTraffic>>,
+    /// Data already read from client, e.g. the rewritten HTTP request.
+    early_data: Option<Bytes>,
+    pub sniffed: Option<SniffedData>,
+    /// Domain name that destination IP was answered for, from DNS snooping.
+    dns_domain: Option<SharedStr>,
 }
 
 #[derive(Debug)]
 pub struct ConnectedClient {
     orig: NewClient,
-    right: TcpStream,
+    right: ServerStream,
     server: Arc<ProxyServer>,
+    /// Other proxies to replay pending data to, if `server` closed the
+    /// connection without any response.
+    fallbacks: VecDeque<Arc<ProxyServer>>,
+    /// Max number of replays.
+    max_retries: usize,
 }
 
 #[derive(Debug)]
 pub enum FailedClient {
-    Recoverable(NewClient),
+    /// Failed with the last error, the client can still be served.
+    Recoverable(NewClient, io::Error),
     Unrecoverable(io::Error),
 }
 
@@ -86,31 +152,53 @@ impl SocketAddrExt for SocketAddr {
     }
 }
 
+struct Socks5Request {
+    command: Command,
+    dest: Destination,
+    username: Option<SharedStr>,
+}
+
+/// Accept SOCKSv5 request, version byte has been consumed by the caller.
 #[instrument(skip_all)]
-async fn accept_socks5(client: &mut TcpStream) -> io::Result<Destination> {
-    // Not a NATed connection, treated as SOCKSv5
-    // Parse version
+async fn accept_socks5(
+    client: &mut ClientStream,
+    users: Option<&UserList>,
+) -> io::Result<Socks5Request> {
     // TODO: add timeout
     // TODO: use buffered reader
-    let ver = client.read_u8().await?;
-    if ver != 0x05 {
-        return error_invalid_input("Neither a NATed or SOCKSv5 connection");
-    }
     // Parse auth methods
     let n_methods = client.read_u8().await?;
     let mut buf = vec![0u8; n_methods as usize];
     client.read_exact(&mut buf).await?;
-    if !buf.iter().any(|&m| m == 0) {
-        return error_invalid_input("SOCKSv5: No auth is required");
-    }
-    // Select no auth
-    client.write_all(&[0x05, 0x00]).await?;
+    let username = if let Some(users) = users {
+        if !buf.iter().any(|&m| m == 0x02) {
+            client.write_all(&[0x05, 0xff]).await?;
+            return error_invalid_input("SOCKSv5: username/password auth is required");
+        }
+        // Select username/password auth
+        client.write_all(&[0x05, 0x02]).await?;
+        Some(accept_user_pass_auth(client, users).await?)
+    } else {
+        if !buf.iter().any(|&m| m == 0) {
+            client.write_all(&[0x05, 0xff]).await?;
+            return error_invalid_input("SOCKSv5: No auth is required");
+        }
+        // Select no auth
+        client.write_all(&[0x05, 0x00]).await?;
+        None
+    };
     // Parse request
     buf.resize(4, 0);
     client.read_exact(&mut buf).await?;
-    if buf[0..2] != [0x05, 0x01] {
-        return error_invalid_input("SOCKSv5: CONNECT is required");
-    }
+    let command = match buf[0..2] {
+        [0x05, 0x01] => Command::Connect,
+        [0x05, 0x03] => Command::UdpAssociate,
+        _ => {
+            let rep = socks5::REP_COMMAND_NOT_SUPPORTED;
+            client.write_all(&[5, rep, 0, 1, 0, 0, 0, 0, 0, 0]).await?;
+            return error_invalid_input("SOCKSv5: CONNECT or UDP ASSOCIATE is required");
+        }
+    };
     let addr: Address = match buf[3] {
         0x01 => {
             // IPv4
@@ -138,35 +226,118 @@ async fn accept_socks5(client: &mut TcpStream) -> io::Result<Destination> {
         _ => return error_invalid_input("SOCKSv5: unknown address type"),
     };
     let port = client.read_u16().await?;
-    // Send response
-    client.write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0]).await?;
-    Ok((addr, port).into())
+    // Reply is deferred, see `NewClient::reply_ok()` & `reply_err()`
+    Ok(Socks5Request {
+        command,
+        dest: (addr, port).into(),
+        username,
+    })
+}
+
+/// Username/password authentication for SOCKSv5 (RFC 1929).
+/// Return the username if succeed.
+async fn accept_user_pass_auth(
+    client: &mut ClientStream,
+    users: &UserList,
+) -> io::Result<SharedStr> {
+    let ver = client.read_u8().await?;
+    if ver != 0x01 {
+        return error_invalid_input("SOCKSv5: unknown auth version");
+    }
+    let len = client.read_u8().await? as usize;
+    let mut username = vec![0u8; len];
+    client.read_exact(&mut username).await?;
+    let len = client.read_u8().await? as usize;
+    let mut password = vec![0u8; len];
+    client.read_exact(&mut password).await?;
+
+    let username = String::from_utf8_lossy(&username);
+    let password = String::from_utf8_lossy(&password);
+    match users.verify(&username, &password) {
+        Some(username) => {
+            client.write_all(&[0x01, 0x00]).await?;
+            Ok(username)
+        }
+        None => {
+            client.write_all(&[0x01, 0x01]).await?;
+            info!(%username, "SOCKSv5 auth failed");
+            Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "SOCKSv5: wrong username/password",
+            ))
+        }
+    }
 }
 
 impl NewClient {
     #[instrument(name = "retrieve_dest", skip_all)]
-    pub async fn from_socket(mut left: TcpStream) -> io::Result<Self> {
-        let from_port = left.local_addr()?.port();
+    pub async fn from_socket(mut left: ClientStream, users: Option<&UserList>) -> io::Result<Self> {
+        let from_port = left.local_port();
 
         // Try to get original destination before NAT
         #[cfg(target_os = "linux")]
-        let dest = match left.get_original_dest()? {
-            // Redirecting to itself is possible. Treat it as non-redirect.
-            Some(dest) if dest.normalize() != left.local_addr()?.normalize() => Some(dest),
-            _ => None,
+        let dest = match left.as_tcp() {
+            Some(tcp) => match tcp.get_original_dest()? {
+                // Redirecting to itself is possible. Treat it as non-redirect.
+                Some(dest) if dest.normalize() != tcp.local_addr()?.normalize() => Some(dest),
+                _ => None,
+            },
+            None => None,
         };
 
         // No NAT supported
         #[cfg(not(target_os = "linux"))]
         let dest: Option<SocketAddr> = None;
 
-        let dest = if let Some(dest) = dest {
+        let mut early_data = None;
+        let (command, dest, username, pending_reply) = if let Some(dest) = dest {
             debug!(?dest, "Retrived destination via NAT info");
-            dest.into()
+            (Command::Connect, dest.into(), None, None)
         } else {
-            let dest = accept_socks5(&mut left).await?;
-            debug!(?dest, "Retrived destination via SOCKSv5");
-            dest
+            // First byte is consumed here, handlers continue from the next.
+            match left.read_u8().await? {
+                0x04 => {
+                    let dest = socks4::accept_socks4(&mut left, users).await?;
+                    debug!(?dest, "Retrived destination via SOCKS4");
+                    (Command::Connect, dest, None, Some(PendingReply::Socks4))
+                }
+                0x05 => {
+                    let Socks5Request {
+                        command,
+                        dest,
+                        username,
+                    } = accept_socks5(&mut left, users).await?;
+                    debug!(
+                        ?command,
+                        ?dest,
+                        ?username,
+                        "Retrived destination via SOCKSv5"
+                    );
+                    (command, dest, username, Some(PendingReply::Socks5))
+                }
+                byte if http::is_request_line(byte) => {
+                    let request = http::accept_http(&mut left, byte, users).await?;
+                    debug!(
+                        tunnel = request.tunnel,
+                        dest = ?request.dest,
+                        username = ?request.username,
+                        "Retrived destination via HTTP"
+                    );
+                    let reply = if request.tunnel {
+                        PendingReply::HttpConnect
+                    } else {
+                        PendingReply::HttpForward
+                    };
+                    early_data = request.pending_data;
+                    (
+                        Command::Connect,
+                        request.dest,
+                        request.username,
+                        Some(reply),
+                    )
+                }
+                _ => return error_invalid_input("Neither a NATed, SOCKS or HTTP connection"),
+            }
         };
 
         let dest_ip_addr = match dest.host {
@@ -176,29 +347,171 @@ impl NewClient {
 
         Ok(NewClient {
             left,
+            command,
+            pending_reply,
             dest,
             dest_ip_addr,
             from_port,
-            tls: None,
+            src_addr: None,
+            username,
+            user_traffic: None,
+            early_data,
+            sniffed: None,
+            dns_domain: None,
         })
     }
 
+    /// Accept connection from TPROXY listener, whose local address is the
+    /// original destination.
+    pub fn from_tproxy_socket(left: ClientStream, from_port: u16) -> io::Result<Self> {
+        let local = left.tcp()?.local_addr()?;
+        let dest = udp::canonical(local);
+        debug!(?dest, "Retrived destination via TPROXY");
+        Ok(Self::from_transparent(left, dest, from_port))
+    }
+
+    /// Accept connection that must start with PROXY protocol header. The
+    /// source and destination it carries take place of the socket's.
+    #[instrument(name = "retrieve_dest", skip_all)]
+    pub async fn from_proxy_protocol_socket(mut left: ClientStream) -> io::Result<Self> {
+        let local = left.tcp()?.local_addr()?;
+        let (src, dest) = match proxy_protocol::read_header(&mut left).await? {
+            Some(header) => {
+                debug!(dst = ?header.dst, src = ?header.src, "Retrived destination via PROXY protocol");
+                (header.src, header.dst)
+            }
+            None => {
+                // LOCAL or UNKNOWN, made by the load balancer itself (e.g.
+                // health checks). Use addresses of the connection as-is.
+                debug!("PROXY protocol header without address, use socket's");
+                (left.tcp()?.peer_addr()?, local)
+            }
+        };
+        let mut client = Self::from_transparent(left, udp::canonical(dest), local.port());
+        client.src_addr = Some(udp::canonical(src));
+        Ok(client)
+    }
+
+    fn from_transparent(left: ClientStream, dest: SocketAddr, from_port: u16) -> Self {
+        NewClient {
+            left,
+            command: Command::Connect,
+            pending_reply: None,
+            dest: dest.into(),
+            dest_ip_addr: Some(dest.ip()),
+            from_port: Some(from_port),
+            src_addr: None,
+            username: None,
+            user_traffic: None,
+            early_data: None,
+            sniffed: None,
+            dns_domain: None,
+        }
+    }
+
+    /// Send succeeded reply with the bound address, if client is waiting
+    /// for one.
+    async fn reply_ok(&mut self, bind_addr: SocketAddr) -> io::Result<()> {
+        match self.pending_reply.take() {
+            None => Ok(()),
+            Some(PendingReply::Socks4) => {
+                let buf = socks4::build_reply(socks4::REP_GRANTED, Some(bind_addr));
+                self.left.write_all(&buf).await
+            }
+            Some(PendingReply::Socks5) => {
+                let mut buf = Vec::with_capacity(22);
+                build_reply(&mut buf, socks5::REP_SUCCEEDED, &bind_addr.into());
+                self.left.write_all(&buf).await
+            }
+            Some(PendingReply::HttpConnect) => {
+                self.left.write_all(http::build_connect_established()).await
+            }
+            Some(PendingReply::HttpForward) => Ok(()),
+        }
+    }
+
+    /// Tell client why the connection cannot be made, if client is still
+    /// waiting for the reply.
+    pub async fn reply_err(&mut self, err: &io::Error) {
+        let result = match self.pending_reply.take() {
+            None => return,
+            Some(PendingReply::Socks4) => {
+                debug!("Send SOCKS4 failure reply");
+                let buf = socks4::build_reply(socks4::REP_REJECTED, None);
+                self.left.write_all(&buf).await
+            }
+            Some(PendingReply::Socks5) => {
+                let rep = socks5::reply_code(err);
+                debug!(rep, "Send SOCKSv5 failure reply");
+                let mut buf = Vec::with_capacity(10);
+                build_reply(&mut buf, rep, &SocketAddr::from(([0; 4], 0)).into());
+                self.left.write_all(&buf).await
+            }
+            Some(PendingReply::HttpConnect | PendingReply::HttpForward) => {
+                let status = http::error_status(err);
+                debug!(status, "Send HTTP failure response");
+                self.left.write_all(&http::build_response(status)).await
+            }
+        };
+        if let Err(err) = result {
+            debug!(?err, "fail to send reply");
+        }
+    }
+
     fn pending_data(&self) -> Option<Bytes> {
-        Some(self.tls.as_ref()?.pending_data.as_ref()?.clone())
+        if let Some(data) = &self.early_data {
+            return Some(data.clone());
+        }
+        Some(self.sniffed.as_ref()?.pending_data.as_ref()?.clone())
     }
 
     pub fn features(&self) -> RequestFeatures<SharedStr> {
         RequestFeatures {
-            listen_port: Some(self.from_port),
-            dst_domain: self.dest.host.domain(),
+            listen_port: self.from_port,
+            dst_domain: self
+                .dest
+                .host
+                .domain()
+                .or_else(|| {
+                    self.sniffed
+                        .as_ref()
+                        .and_then(|sniffed| sniffed.domain.clone())
+                })
+                .or_else(|| self.dns_domain.clone()),
             dst_ip: self.dest_ip_addr,
+            username: self.username.clone(),
+            protocol: self.sniffed.as_ref().map(|sniffed| sniffed.protocol),
+            alpn: self
+                .sniffed
+                .as_ref()
+                .map(|sniffed| sniffed.alpn.clone())
+                .unwrap_or_default(),
         }
     }
 
-    pub fn override_dest_with_sni(&mut self) -> bool {
+    /// Client's source and destination, to be sent via PROXY protocol.
+    fn proxy_header(&self) -> io::Result<ProxyHeader> {
+        let src = match self.src_addr {
+            Some(addr) => addr,
+            None => udp::canonical(self.left.tcp()?.peer_addr()?),
+        };
+        let dst = match self.dest_ip_addr {
+            Some(ip) => SocketAddr::new(ip, self.dest.port),
+            None => udp::canonical(self.left.tcp()?.local_addr()?),
+        };
+        Ok(ProxyHeader { src, dst })
+    }
+
+    /// Count traffic of this connection into `traffic` as well.
+    /// Used for per-user statistics.
+    pub fn count_traffic_into(&mut self, traffic: Arc<AtomicTraffic>) {
+        self.user_traffic = Some(traffic);
+    }
+
+    pub fn override_dest_with_sniffed(&mut self) -> bool {
         match (
             &mut self.dest.host,
-            &self.tls.as_ref().and_then(|tls| tls.sni.clone()),
+            &self.sniffed.as_ref().and_then(|s| s.domain.clone()),
         ) {
             (Address::Domain(_), _) => false,
             (_, None) => false,
@@ -209,122 +522,297 @@ impl NewClient {
         }
     }
 
+    /// Replace destination with the domain name if it's an address from
+    /// fake-IP DNS.
+    pub fn translate_fake_ip(&mut self, pool: &FakeIpPool) -> bool {
+        let ip = match self.dest.host {
+            Address::Ip(ip) => ip,
+            Address::Domain(_) => return false,
+        };
+        match pool.lookup(ip) {
+            Some(domain) => {
+                debug!(%ip, %domain, "fake IP translated");
+                self.dest.host = Address::Domain(domain);
+                self.dest_ip_addr = None;
+                true
+            }
+            None => {
+                if pool.contains(ip) {
+                    warn!(%ip, "fake IP not found, may have expired");
+                }
+                false
+            }
+        }
+    }
+
+    /// Find out the domain name of destination IP from DNS snooping, it's
+    /// used for policy only, destination is not changed.
+    pub fn lookup_dns_cache(&mut self, cache: &DnsCache) {
+        if let Some(ip) = self.dest_ip_addr {
+            self.dns_domain = cache.lookup(ip);
+            if let Some(domain) = &self.dns_domain {
+                debug!(%ip, %domain, "domain found in DNS cache");
+            }
+        }
+    }
+
     #[instrument(level = "error", skip_all, fields(dest=?self.dest))]
     pub async fn direct_connect(
-        self,
+        mut self,
         pseudo_server: Arc<ProxyServer>,
     ) -> io::Result<ConnectedClient> {
-        let mut right = match self.dest.host {
-            Address::Ip(addr) => TcpStream::connect((addr, self.dest.port)).await?,
+        let bind = pseudo_server.bind();
+        let max_wait = pseudo_server.max_wait();
+        let result = match self.dest.host {
+            Address::Ip(addr) => bind.connect((addr, self.dest.port), max_wait).await,
             Address::Domain(ref name) => {
-                TcpStream::connect((name.as_ref(), self.dest.port)).await?
+                bind.connect((name.as_ref(), self.dest.port), max_wait)
+                    .await
             }
         };
-        right.set_nodelay(true)?;
-
-        if let Some(data) = self.pending_data() {
-            right.write_all(&data).await?;
+        let mut right = match result {
+            Ok(right) => right,
+            Err(err) => {
+                self.reply_err(&err).await;
+                return Err(err);
+            }
+        };
+        let result: io::Result<_> = async {
+            right.set_nodelay(true)?;
+            if pseudo_server.send_proxy_protocol() {
+                let header = self.proxy_header()?;
+                right.write_all(&proxy_protocol::build_v2(&header)).await?;
+            }
+            if let Some(data) = self.pending_data() {
+                right.write_all(&data).await?;
+            }
+            right.peer_addr()
         }
+        .await;
+        let remote = match result {
+            Ok(remote) => remote,
+            Err(err) => {
+                self.reply_err(&err).await;
+                return Err(err);
+            }
+        };
 
-        info!(remote = %right.peer_addr()?, "Connected w/o proxy");
+        info!(%remote, "Connected w/o proxy");
         Ok(ConnectedClient {
             orig: self,
-            right,
+            right: right.into(),
             server: pseudo_server,
+            fallbacks: Default::default(),
+            max_retries: 0,
         })
     }
 
+    /// Whether [`Self::sniff_dest`] is worth it, as it replies to client
+    /// before the upstream result is known. `for_domain` if the domain
+    /// name is wanted (remote DNS, domain rules, etc.), `for_protocol` if
+    /// protocol or ALPN is wanted by policy.
+    pub fn should_sniff(&self, for_domain: bool, for_protocol: bool) -> bool {
+        match self.dest.host {
+            Address::Ip(_) => for_domain || for_protocol,
+            // Already named by the request
+            Address::Domain(_) => for_protocol,
+        }
+    }
+
+    /// Read the first bytes from client to find out the domain name from
+    /// TLS SNI or HTTP `Host` header. Data read is kept and sent to the
+    /// upstream later. See [`Self::should_sniff`].
     #[instrument(level = "error", skip_all, fields(dest=?self.dest))]
-    pub async fn retrieve_dest_from_sni(&mut self) -> io::Result<()> {
-        if self.tls.is_some() {
+    pub async fn sniff_dest(&mut self) -> io::Result<()> {
+        if self.sniffed.is_some() || self.early_data.is_some() {
             return Ok(());
         }
-        let mut tls = TlsData::default();
-        let wait = Duration::from_millis(500);
+        // Client won't send anything until replied, so we have to reply
+        // before knowing the result. Bound address is unknown yet.
+        self.reply_ok(SocketAddr::from(([0; 4], 0))).await?;
+        let mut sniffed = SniffedData::default();
+        let deadline = Instant::now() + Duration::from_millis(500);
         let mut buf = BytesMut::with_capacity(2048);
-        buf.resize(buf.capacity(), 0);
-        if let Ok(len) = timeout(wait, self.left.read(&mut buf)).await {
-            buf.truncate(len?);
-            // only TLS is safe to duplicate requests.
-            match tls_parser::parse_client_hello(&buf) {
-                Err(err) => info!("fail to parse hello: {}", err),
-                Ok(hello) => {
-                    tls.has_full_tls_hello = true;
-                    if let Some(name) = hello.server_name {
-                        tls.sni = Some(name.into());
-                        debug!(sni = name, "SNI found");
+        loop {
+            // ClientHello or request header may be larger than one TCP
+            // segment, keep reading until it's complete.
+            buf.reserve(2048);
+            match timeout_at(deadline, self.left.read_buf(&mut buf)).await {
+                Err(_) => {
+                    info!("no complete tls hello or http request received before timeout");
+                    break;
+                }
+                Ok(Ok(0)) => break,
+                Ok(Ok(_)) => (),
+                Ok(Err(err)) => return Err(err),
+            }
+            let result = sniff::sniff(&buf);
+            sniffed.protocol = result.protocol();
+            match result {
+                Sniffed::Incomplete if buf.len() < MAX_SNIFF_LEN => continue,
+                Sniffed::Incomplete => info!("tls hello or http request too large"),
+                Sniffed::Unknown(reason) => debug!("nothing sniffed: {}", reason),
+                Sniffed::Ssh | Sniffed::BitTorrent => {
+                    debug!(protocol = %sniffed.protocol, "protocol found")
+                }
+                Sniffed::Tls {
+                    sni,
+                    early_data,
+                    alpn,
+                    versions,
+                } => {
+                    // only TLS is safe to duplicate requests.
+                    sniffed.has_full_tls_hello = true;
+                    if let Some(name) = &sni {
+                        debug!(sni = %name, "SNI found");
                     }
-                    if hello.early_data {
+                    if early_data {
                         debug!("TLS with early data");
                     }
+                    debug!(?alpn, ?versions, "TLS client hello");
+                    sniffed.domain = sni;
+                    sniffed.alpn = alpn;
+                    sniffed.tls_versions = versions;
+                }
+                Sniffed::Http { host } => {
+                    if let Some(name) = &host {
+                        debug!(host = %name, "HTTP host found");
+                    }
+                    sniffed.domain = host;
                 }
             }
-            tls.pending_data = Some(buf.freeze());
-        } else {
-            info!("no tls request received before timeout");
+            break;
+        }
+        if !buf.is_empty() {
+            sniffed.pending_data = Some(buf.freeze());
         }
-        self.tls = Some(tls);
+        self.sniffed = Some(sniffed);
         Ok(())
     }
 
+    /// Connect to one of `proxies`. Once connected, up to `max_retries`
+    /// other proxies may be tried later if it closes the connection before
+    /// sending any response, see [`ConnectedClient::serve`].
+    /// With `hedge`, the next proxy is started if the last one is slower
+    /// than usual, see [`try_connect_all`].
     #[instrument(level = "error", skip_all, fields(dest=?self.dest))]
     pub async fn connect_server(
         self,
         proxies: Vec<Arc<ProxyServer>>,
         n_parallel: usize,
+        max_retries: usize,
+        hedge: bool,
     ) -> Result<ConnectedClient, FailedClient> {
         if proxies.is_empty() {
             warn!("No avaiable proxy");
-            return Err(FailedClient::Recoverable(self));
+            let err = io::Error::new(io::ErrorKind::NotFound, "no avaiable proxy");
+            return Err(FailedClient::Recoverable(self, err));
         }
-        let (n_parallel, wait_response) = match self.tls {
-            Some(ref tls) if tls.has_full_tls_hello => (n_parallel.clamp(1, proxies.len()), true),
+        let (n_parallel, wait_response) = match self.sniffed {
+            Some(ref sniffed) if sniffed.has_full_tls_hello => {
+                (n_parallel.clamp(1, proxies.len()), true)
+            }
             _ => (1, false),
         };
         let proxies_len = proxies.len();
         match try_connect_all(
             &self.dest,
-            proxies,
+            proxies.clone(),
             n_parallel,
             wait_response,
             self.pending_data(),
+            self.proxy_header().ok(),
+            hedge,
         )
         .await
         {
             Ok((server, right)) => {
                 info!(proxy = %server.tag, "Proxy connected");
+                let fallbacks = proxies
+                    .into_iter()
+                    .filter(|p| !Arc::ptr_eq(p, &server))
+                    .collect();
                 Ok(ConnectedClient {
                     orig: self,
                     right,
                     server,
+                    fallbacks,
+                    max_retries,
                 })
             }
             Err(err) => {
                 warn!("Tried {} proxies but failed: {}", proxies_len, err);
-                Err(FailedClient::Recoverable(self))
+                Err(FailedClient::Recoverable(self, err))
             }
         }
     }
 }
 
+/// Wait until upstream responds or client sends more data. Return `false`
+/// if upstream closed (or reset) the connection before that.
+async fn wait_first_response(left: &ClientStream, right: &mut ServerStream) -> bool {
+    let mut buf = [0u8; 1];
+    tokio::select! {
+        result = right.peek(&mut buf) => matches!(result, Ok(n) if n > 0),
+        _ = left.readable() => true,
+    }
+}
+
 impl FailedClient {
     pub fn recovery(self) -> io::Result<NewClient> {
         match self {
-            Self::Recoverable(client) => Ok(client),
+            Self::Recoverable(client, _) => Ok(client),
             Self::Unrecoverable(err) => Err(err),
         }
     }
 }
 
 impl ConnectedClient {
+    /// Relay data between client and upstream until both closed.
+    /// If the client's first flight was already sent and upstream closes
+    /// without any response, it's replayed to the next fallback proxy,
+    /// only if it's safe to do so (see [`sniff::is_replayable`]).
     #[instrument(level = "error", skip_all, fields(dest=?self.orig.dest, proxy=%self.server.tag))]
     pub async fn serve(self) -> io::Result<()> {
         let ConnectedClient {
-            orig,
-            right,
-            server,
-            ..
+            mut orig,
+            mut right,
+            mut server,
+            mut fallbacks,
+            mut max_retries,
         } = self;
+        orig.reply_ok(right.local_addr()?).await?;
+        let replayable = orig
+            .pending_data()
+            .filter(|data| sniff::is_replayable(data));
+        if let Some(data) = replayable {
+            while max_retries > 0
+                && !fallbacks.is_empty()
+                && !wait_first_response(&orig.left, &mut right).await
+            {
+                info!(proxy = %server.tag, "Proxy closed w/o response, try next one");
+                server.update_stats_conn_open();
+                server.update_stats_conn_close(true);
+                max_retries -= 1;
+                (server, right) = try_connect_all(
+                    &orig.dest,
+                    fallbacks.iter().cloned().collect(),
+                    1,
+                    false,
+                    Some(data.clone()),
+                    orig.proxy_header().ok(),
+                    false,
+                )
+                .await?;
+                // Drop the connected one and those failed before it
+                while let Some(tried) = fallbacks.pop_front() {
+                    if Arc::ptr_eq(&tried, &server) {
+                        break;
+                    }
+                }
+                info!(proxy = %server.tag, "Proxy connected");
+            }
+        }
         // TODO: make keepalive configurable
         // FIXME: set_cookies
         /*
@@ -340,7 +828,7 @@ impl ConnectedClient {
         }
         */
         server.update_stats_conn_open();
-        match pipe(orig.left, right, server.clone()).await {
+        match pipe(orig.left, right, server.clone(), orig.user_traffic).await {
             Ok(Traffic { tx_bytes, rx_bytes }) => {
                 server.update_stats_conn_close(false);
                 debug!(tx_bytes, rx_bytes, "Closed");
@@ -354,3 +842,116 @@ impl ConnectedClient {
         }
     }
 }
+
+#[tokio::test]
+async fn test_replay_on_empty_response() {
+    use crate::test_util::fake_socks4;
+    use tokio::net::{TcpListener, TcpStream};
+
+    // Read the first flight, then send back `response`, or close w/o any
+    // response if it's `None`. Return the first flight.
+    async fn fake_proxy(
+        response: Option<&'static [u8]>,
+    ) -> (Arc<ProxyServer>, tokio::task::JoinHandle<Vec<u8>>) {
+        fake_socks4(true, move |mut stream: TcpStream| async move {
+            let mut buf = [0u8; 64];
+            let n = stream.read(&mut buf).await.unwrap();
+            if let Some(response) = response {
+                stream.write_all(response).await.unwrap();
+            }
+            buf[..n].to_vec()
+        })
+        .await
+    }
+
+    // Returns what client received
+    async fn serve(request: &'static [u8], proxies: Vec<Arc<ProxyServer>>) -> Vec<u8> {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut user = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (left, _) = listener.accept().await.unwrap();
+        let mut client =
+            NewClient::from_transparent(left.into(), "192.0.2.1:80".parse().unwrap(), 80);
+        client.early_data = Some(Bytes::from_static(request));
+        let client = client.connect_server(proxies, 1, 1, false).await.unwrap();
+        let task = tokio::spawn(client.serve());
+        let mut buf = Vec::new();
+        user.read_to_end(&mut buf).await.unwrap();
+        drop(user);
+        task.await.unwrap().unwrap();
+        buf
+    }
+
+    let request = b"GET / HTTP/1.1\r\n\r\n";
+    let (closing, closing_task) = fake_proxy(None).await;
+    let (good, good_task) = fake_proxy(Some(b"HTTP/1.1 200 OK\r\n\r\n")).await;
+    let received = serve(request, vec![closing, good]).await;
+    assert_eq!(b"HTTP/1.1 200 OK\r\n\r\n", &received[..]);
+    assert_eq!(request, &closing_task.await.unwrap()[..]);
+    assert_eq!(request, &good_task.await.unwrap()[..]);
+
+    // Not idempotent, never replayed
+    let request = b"POST / HTTP/1.1\r\n\r\n";
+    let (closing, closing_task) = fake_proxy(None).await;
+    let (good, good_task) = fake_proxy(Some(b"HTTP/1.1 200 OK\r\n\r\n")).await;
+    let received = serve(request, vec![closing, good]).await;
+    assert!(received.is_empty());
+    assert_eq!(request, &closing_task.await.unwrap()[..]);
+    good_task.abort();
+}
+
+#[tokio::test]
+async fn test_socks5_domain_reply_deferred() {
+    use crate::{policy::Policy, proxy::ProxyProto};
+    use tokio::{
+        net::{TcpListener, TcpStream},
+        time::timeout,
+    };
+
+    let policy = Policy::load("dst domain example.com require a".as_bytes()).unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let mut user = TcpStream::connect(listener.local_addr().unwrap())
+        .await
+        .unwrap();
+    let (left, _) = listener.accept().await.unwrap();
+    // As if SOCKSv5 CONNECT to example.com:443 was accepted
+    let mut client =
+        NewClient::from_transparent(left.into(), "192.0.2.1:443".parse().unwrap(), 443);
+    client.dest = ("example.com", 443).into();
+    client.dest_ip_addr = None;
+    client.pending_reply = Some(PendingReply::Socks5);
+    assert!(!client.should_sniff(policy.has_dst_domain_rules(), policy.has_sniff_rules()));
+    assert!(client.should_sniff(false, true));
+
+    // Upstream closes right after accept
+    let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let server = ProxyServer::new(
+        upstream.local_addr().unwrap(),
+        ProxyProto::socks5(false),
+        "127.0.0.1:53".parse().unwrap(),
+        Duration::from_secs(1),
+        None,
+        None,
+        None,
+    );
+    tokio::spawn(async move { drop(upstream.accept().await) });
+
+    let mut buf = [0u8; 10];
+    let read = timeout(Duration::from_millis(100), user.read(&mut buf)).await;
+    assert!(read.is_err(), "replied before upstream result");
+    let err = match client
+        .connect_server(vec![server.into()], 1, 0, false)
+        .await
+    {
+        Err(FailedClient::Recoverable(mut client, err)) => {
+            client.reply_err(&err).await;
+            err
+        }
+        _ => panic!("upstream should fail"),
+    };
+    user.read_exact(&mut buf).await.unwrap();
+    assert_eq!(5, buf[0]);
+    assert_ne!(0, buf[1]);
+    assert_eq!(socks5::reply_code(&err), buf[1]);
+}
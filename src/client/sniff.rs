@@ -0,0 +1,195 @@
+use flexstr::SharedStr;
+use httparse::{Request, Status, EMPTY_HEADER};
+use std::{net::IpAddr, str::from_utf8};
+
+use super::{http::is_request_line, tls_parser};
+use crate::policy::AppProtocol;
+
+const SSH_PREFIX: &[u8] = b"SSH-";
+const BITTORRENT_PREFIX: &[u8] = b"\x13BitTorrent protocol";
+/// HTTP methods that are idempotent (RFC 9110).
+const IDEMPOTENT_METHODS: &[&[u8]] = &[b"GET", b"HEAD", b"OPTIONS", b"TRACE", b"PUT", b"DELETE"];
+
+/// What we learn from the first bytes sent by client.
+#[derive(Debug, PartialEq, Eq)]
+pub(super) enum Sniffed {
+    /// Need more data to tell.
+    Incomplete,
+    /// A complete TLS ClientHello.
+    Tls {
+        sni: Option<SharedStr>,
+        early_data: bool,
+        alpn: Vec<SharedStr>,
+        versions: Vec<u16>,
+    },
+    /// A complete HTTP/1.x request header.
+    Http { host: Option<SharedStr> },
+    /// SSH identification string.
+    Ssh,
+    /// BitTorrent peer handshake.
+    BitTorrent,
+    /// None of above, or malformed.
+    Unknown(&'static str),
+}
+
+impl Sniffed {
+    pub(super) fn protocol(&self) -> AppProtocol {
+        match self {
+            Self::Tls { .. } => AppProtocol::Tls,
+            Self::Http { .. } => AppProtocol::Http,
+            Self::Ssh => AppProtocol::Ssh,
+            Self::BitTorrent => AppProtocol::BitTorrent,
+            Self::Incomplete | Self::Unknown(_) => AppProtocol::Unknown,
+        }
+    }
+}
+
+/// Return `Some(true)` if `data` starts with `prefix`, `None` if it's
+/// too short to tell.
+fn has_prefix(data: &[u8], prefix: &[u8]) -> Option<bool> {
+    if data.len() >= prefix.len() {
+        Some(data.starts_with(prefix))
+    } else if prefix.starts_with(data) {
+        None
+    } else {
+        Some(false)
+    }
+}
+
+pub(super) fn sniff(data: &[u8]) -> Sniffed {
+    match data.first() {
+        None => Sniffed::Incomplete,
+        Some(22) => match tls_parser::parse_client_hello(data) {
+            Ok(None) => Sniffed::Incomplete,
+            Ok(Some(hello)) => Sniffed::Tls {
+                sni: hello.server_name.map(SharedStr::from),
+                early_data: hello.early_data,
+                alpn: hello.alpn.into_iter().map(SharedStr::from).collect(),
+                versions: hello.versions,
+            },
+            Err(err) => Sniffed::Unknown(err),
+        },
+        // Both SSH and HTTP methods like `SEARCH`
+        Some(b'S') => match has_prefix(data, SSH_PREFIX) {
+            Some(true) => Sniffed::Ssh,
+            Some(false) => sniff_http(data),
+            None => Sniffed::Incomplete,
+        },
+        Some(0x13) => match has_prefix(data, BITTORRENT_PREFIX) {
+            Some(true) => Sniffed::BitTorrent,
+            Some(false) => Sniffed::Unknown("unknown protocol"),
+            None => Sniffed::Incomplete,
+        },
+        Some(&byte) if is_request_line(byte) => sniff_http(data),
+        Some(_) => Sniffed::Unknown("unknown protocol"),
+    }
+}
+
+/// Whether `data` is safe to send again to another upstream: a TLS
+/// ClientHello without early data (0-RTT), or an HTTP request of idempotent
+/// method.
+pub(super) fn is_replayable(data: &[u8]) -> bool {
+    match sniff(data) {
+        Sniffed::Tls { early_data, .. } => !early_data,
+        Sniffed::Http { .. } => {
+            let method = data.split(|&b| b == b' ').next().unwrap_or_default();
+            IDEMPOTENT_METHODS.contains(&method)
+        }
+        _ => false,
+    }
+}
+
+fn sniff_http(data: &[u8]) -> Sniffed {
+    let mut headers = [EMPTY_HEADER; 64];
+    let mut request = Request::new(&mut headers);
+    match request.parse(data) {
+        Ok(Status::Partial) => Sniffed::Incomplete,
+        Err(_) => Sniffed::Unknown("malformed HTTP request"),
+        Ok(Status::Complete(_)) => {
+            let host = request
+                .headers
+                .iter()
+                .find(|h| h.name.eq_ignore_ascii_case("host"))
+                .and_then(|h| from_utf8(h.value).ok())
+                .and_then(host_to_domain);
+            Sniffed::Http { host }
+        }
+    }
+}
+
+/// Strip port number off `Host`. Return `None` if it's an IP address or
+/// invalid domain name.
+fn host_to_domain(host: &str) -> Option<SharedStr> {
+    let host = host.trim();
+    if host.starts_with('[') {
+        // IPv6 literal
+        return None;
+    }
+    let name = match host.rsplit_once(':') {
+        Some((name, port)) if port.parse::<u16>().is_ok() => name,
+        Some(_) => return None,
+        None => host,
+    };
+    if name.is_empty() || name.len() > 255 || name.parse::<IpAddr>().is_ok() {
+        return None;
+    }
+    if !name
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-' || c == '_')
+    {
+        return None;
+    }
+    Some(name.into())
+}
+
+#[test]
+fn test_sniff_http() {
+    assert_eq!(Sniffed::Incomplete, sniff(b""));
+    assert_eq!(Sniffed::Incomplete, sniff(b"GET / HTTP/1.1\r\nHost: exa"));
+    assert_eq!(
+        Sniffed::Http {
+            host: Some("example.com".into())
+        },
+        sniff(b"GET / HTTP/1.1\r\nHost: example.com:8080\r\n\r\n")
+    );
+    assert_eq!(
+        Sniffed::Http { host: None },
+        sniff(b"GET / HTTP/1.1\r\nHost: 192.0.2.1\r\n\r\n")
+    );
+    assert_eq!(
+        Sniffed::Http { host: None },
+        sniff(b"GET / HTTP/1.1\r\nHost: [::1]:80\r\n\r\n")
+    );
+    assert!(matches!(sniff(b"\x00\x01"), Sniffed::Unknown(_)));
+}
+
+#[test]
+fn test_sniff_other_protocols() {
+    assert_eq!(Sniffed::Ssh, sniff(b"SSH-2.0-OpenSSH_9.0\r\n"));
+    assert_eq!(Sniffed::Incomplete, sniff(b"SS"));
+    assert_eq!(
+        Sniffed::Http { host: None },
+        sniff(b"SEARCH / HTTP/1.1\r\n\r\n")
+    );
+    assert_eq!(
+        Sniffed::BitTorrent,
+        sniff(b"\x13BitTorrent protocol\x00\x00\x00\x00\x00\x10\x00\x05")
+    );
+    assert_eq!(Sniffed::Incomplete, sniff(b"\x13BitTorrent"));
+    assert!(matches!(
+        sniff(b"\x13Bittorrent protocol"),
+        Sniffed::Unknown(_)
+    ));
+}
+
+#[test]
+fn test_is_replayable() {
+    assert!(is_replayable(
+        b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n"
+    ));
+    assert!(is_replayable(b"PUT /a HTTP/1.1\r\n\r\nbody"));
+    assert!(!is_replayable(b"POST / HTTP/1.1\r\n\r\nbody"));
+    assert!(!is_replayable(b"GET / HTTP/1.1\r\nHost: exa"));
+    assert!(!is_replayable(b"SSH-2.0-OpenSSH_9.0\r\n"));
+    assert!(!is_replayable(b""));
+}
@@ -0,0 +1,121 @@
+use std::{
+    io,
+    net::{Ipv4Addr, SocketAddr},
+};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tracing::instrument;
+
+use super::{error_invalid_input, users::UserList, ClientStream};
+use crate::proxy::{Address, Destination};
+
+const CMD_CONNECT: u8 = 0x01;
+pub(super) const REP_GRANTED: u8 = 0x5a;
+pub(super) const REP_REJECTED: u8 = 0x5b;
+
+/// Max length of USERID and domain name we accept.
+const MAX_FIELD_LEN: usize = 255;
+
+/// Accept SOCKS4 or SOCKS4a CONNECT request, return the destination.
+/// Version byte has been consumed by the caller.
+/// Reply is deferred, see `build_reply()`.
+#[instrument(skip_all)]
+pub(super) async fn accept_socks4(
+    client: &mut ClientStream,
+    users: Option<&UserList>,
+) -> io::Result<Destination> {
+    // TODO: add timeout
+    let mut buf = [0x04u8; 8];
+    client.read_exact(&mut buf[1..]).await?;
+    let port = u16::from_be_bytes([buf[2], buf[3]]);
+    let ip = Ipv4Addr::new(buf[4], buf[5], buf[6], buf[7]);
+    // USERID, not authenticated so ignored
+    read_null_terminated(client).await?;
+
+    if buf[1] != CMD_CONNECT {
+        client.write_all(&build_reply(REP_REJECTED, None)).await?;
+        return error_invalid_input("SOCKS4: CONNECT is required");
+    }
+    // No password on SOCKS4
+    if users.is_some() {
+        client.write_all(&build_reply(REP_REJECTED, None)).await?;
+        return error_invalid_input("SOCKS4: username/password auth is required");
+    }
+
+    // SOCKS4a: 0.0.0.x (x != 0) indicates a domain name follows
+    let host = match ip.octets() {
+        [0, 0, 0, x] if x != 0 => {
+            let name = read_null_terminated(client).await?;
+            let name = String::from_utf8(name).map_err(|_| {
+                io::Error::new(io::ErrorKind::InvalidInput, "SOCKS4a: Invalid domain name")
+            })?;
+            Address::Domain(name.as_str().into())
+        }
+        _ => Address::Ip(ip.into()),
+    };
+    Ok((host, port).into())
+}
+
+async fn read_null_terminated(client: &mut ClientStream) -> io::Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    loop {
+        match client.read_u8().await? {
+            0 => return Ok(buf),
+            _ if buf.len() >= MAX_FIELD_LEN => {
+                return error_invalid_input("SOCKS4: USERID or domain name too long")
+            }
+            byte => buf.push(byte),
+        }
+    }
+}
+
+/// Build the reply to SOCKS4 client. Only IPv4 address is carried.
+pub(super) fn build_reply(rep: u8, bind_addr: Option<SocketAddr>) -> [u8; 8] {
+    let mut buf = [0u8; 8];
+    buf[1] = rep;
+    if let Some(SocketAddr::V4(addr)) = bind_addr {
+        buf[2..4].copy_from_slice(&addr.port().to_be_bytes());
+        buf[4..].copy_from_slice(&addr.ip().octets());
+    }
+    buf
+}
+
+#[tokio::test]
+async fn test_accept_socks4a() {
+    use tokio::net::{TcpListener, TcpStream};
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"\x04\x01\x01\xbb\x00\x00\x00\x01user\x00example.com\x00")
+            .await
+            .unwrap();
+        stream
+            .write_all(b"\x04\x01\x00\x50\xc0\x00\x02\x01\x00")
+            .await
+            .unwrap();
+    });
+    let (stream, _) = listener.accept().await.unwrap();
+    let mut stream = ClientStream::from(stream);
+    assert_eq!(0x04, stream.read_u8().await.unwrap());
+    let dest = accept_socks4(&mut stream, None).await.unwrap();
+    assert_eq!(Destination::from(("example.com", 443)), dest);
+    assert_eq!(0x04, stream.read_u8().await.unwrap());
+    let dest = accept_socks4(&mut stream, None).await.unwrap();
+    assert_eq!(
+        Destination::from("192.0.2.1:80".parse::<SocketAddr>().unwrap()),
+        dest
+    );
+}
+
+#[test]
+fn test_build_socks4_reply() {
+    let addr = "192.0.2.1:1080".parse().ok();
+    assert_eq!(
+        [0, 0x5a, 0x04, 0x38, 192, 0, 2, 1],
+        build_reply(REP_GRANTED, addr)
+    );
+    let addr = "[::1]:1080".parse().ok();
+    assert_eq!([0, 0x5b, 0, 0, 0, 0, 0, 0], build_reply(REP_REJECTED, addr));
+}
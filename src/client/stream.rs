@@ -0,0 +1,118 @@
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+#[cfg(unix)]
+use tokio::net::UnixStream;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::TcpStream,
+};
+
+/// Connection accepted from downstream client.
+#[derive(Debug)]
+pub enum ClientStream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl ClientStream {
+    pub fn as_tcp(&self) -> Option<&TcpStream> {
+        match self {
+            Self::Tcp(stream) => Some(stream),
+            #[cfg(unix)]
+            Self::Unix(_) => None,
+        }
+    }
+
+    /// Return TCP stream, or error if it's not.
+    pub fn tcp(&self) -> io::Result<&TcpStream> {
+        self.as_tcp()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::Unsupported, "not a TCP connection"))
+    }
+
+    /// Port number client connected to, `None` for Unix socket.
+    pub fn local_port(&self) -> Option<u16> {
+        Some(self.as_tcp()?.local_addr().ok()?.port())
+    }
+
+    /// Wait until there is something to read, or the peer closed.
+    pub async fn readable(&self) -> io::Result<()> {
+        match self {
+            Self::Tcp(stream) => stream.readable().await,
+            #[cfg(unix)]
+            Self::Unix(stream) => stream.readable().await,
+        }
+    }
+
+    /// Describe the peer for logging.
+    pub fn peer_name(&self) -> String {
+        match self {
+            Self::Tcp(stream) => match stream.peer_addr() {
+                Ok(addr) => addr.to_string(),
+                Err(_) => "unknown".to_string(),
+            },
+            #[cfg(unix)]
+            Self::Unix(stream) => match stream.peer_cred() {
+                Ok(cred) => match cred.pid() {
+                    Some(pid) => format!("unix:uid={},pid={}", cred.uid(), pid),
+                    None => format!("unix:uid={}", cred.uid()),
+                },
+                Err(_) => "unix".to_string(),
+            },
+        }
+    }
+}
+
+impl From<TcpStream> for ClientStream {
+    fn from(stream: TcpStream) -> Self {
+        Self::Tcp(stream)
+    }
+}
+
+#[cfg(unix)]
+impl From<UnixStream> for ClientStream {
+    fn from(stream: UnixStream) -> Self {
+        Self::Unix(stream)
+    }
+}
+
+macro_rules! delegate {
+    ($self:ident, $stream:ident => $expr:expr) => {
+        match $self.get_mut() {
+            ClientStream::Tcp($stream) => $expr,
+            #[cfg(unix)]
+            ClientStream::Unix($stream) => $expr,
+        }
+    };
+}
+
+impl AsyncRead for ClientStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        delegate!(self, stream => Pin::new(stream).poll_read(cx, buf))
+    }
+}
+
+impl AsyncWrite for ClientStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        delegate!(self, stream => Pin::new(stream).poll_write(cx, buf))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        delegate!(self, stream => Pin::new(stream).poll_flush(cx))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        delegate!(self, stream => Pin::new(stream).poll_shutdown(cx))
+    }
+}
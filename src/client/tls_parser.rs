@@ -2,11 +2,19 @@ use std::ops::Range;
 use std::str::from_utf8;
 
 const EXT_SERVER_NAME: &[u8] = &[0, 0];
+const EXT_ALPN: &[u8] = &[0, 16];
 const EXT_EARLY_DATA: &[u8] = &[0, 42];
+const EXT_SUPPORTED_VERSIONS: &[u8] = &[0, 43];
 
-pub struct TlsClientHello<'a> {
-    pub server_name: Option<&'a str>,
+pub struct TlsClientHello {
+    pub server_name: Option<String>,
     pub early_data: bool,
+    /// Application protocols offered, e.g. `h2`, `http/1.1`.
+    pub alpn: Vec<String>,
+    /// TLS versions supported by client, e.g. `0x0304` for TLS 1.3.
+    /// Taken from `supported_versions` extension if present, otherwise
+    /// it's the legacy client version. GREASE values are ignored.
+    pub versions: Vec<u16>,
 }
 
 struct TlsRecord<'a> {
@@ -44,29 +52,78 @@ fn parse_tls_record(data: &[u8]) -> Result<TlsRecord, &'static str> {
     })
 }
 
-pub fn parse_client_hello(data: &[u8]) -> Result<TlsClientHello, &'static str> {
-    let TlsRecord {
-        content_type: &ctype,
-        version_major: &version,
-        fragment,
-        ..
-    } = parse_tls_record(data)?;
-    if version != 3 {
-        return Err("unknown tls version");
+/// Reassemble the first handshake message, which may be fragmented into
+/// multiple TLS records. Return `None` if more data is needed.
+fn reassemble_handshake(mut data: &[u8]) -> Result<Option<Vec<u8>>, &'static str> {
+    let mut message = Vec::new();
+    while data.len() >= 5 {
+        let TlsRecord {
+            content_type: &ctype,
+            version_major: &version,
+            fragment,
+            ..
+        } = match parse_tls_record(data) {
+            Ok(record) => record,
+            // Incomplete record, check its header only
+            Err(_) if data[0] == 22 && data[1] == 3 => return Ok(None),
+            Err(_) => return Err("not tls handshake"),
+        };
+        if version != 3 {
+            return Err("unknown tls version");
+        }
+        if ctype != 22 {
+            return Err("not handshake");
+        }
+        message.extend_from_slice(fragment);
+        data = &data[5 + fragment.len()..];
+
+        // 0: handshake type, 1..4: length
+        if let Ok(body) = truncate(&message, 1..4) {
+            let len = body.len() + 4;
+            message.truncate(len);
+            return Ok(Some(message));
+        }
     }
-    if ctype != 22 {
-        return Err("not handshake");
+    match data {
+        [] | [22] | [22, 3, ..] => Ok(None),
+        _ => Err("not tls handshake"),
     }
+}
 
+/// Check whether `data`, the beginning of a server's response, is possibly
+/// a TLS handshake or alert record. Only available bytes of the record
+/// header are checked.
+pub fn is_tls_response(data: &[u8]) -> bool {
+    // 22: handshake, 21: alert
+    let is_valid_type = |t| t == 22 || t == 21;
+    match *data {
+        [] => false,
+        [ctype] => is_valid_type(ctype),
+        [ctype, major] => is_valid_type(ctype) && major == 3,
+        [ctype, major, minor, ..] => is_valid_type(ctype) && major == 3 && (1..=4).contains(&minor),
+    }
+}
+
+/// Parse TLS ClientHello from the beginning of a TLS stream.
+/// Return `None` if it's incomplete yet.
+pub fn parse_client_hello(data: &[u8]) -> Result<Option<TlsClientHello>, &'static str> {
+    match reassemble_handshake(data)? {
+        Some(message) => parse_client_hello_message(&message).map(Some),
+        None => Ok(None),
+    }
+}
+
+fn parse_client_hello_message(message: &[u8]) -> Result<TlsClientHello, &'static str> {
     // 0: handshake type
-    if fragment.first() != Some(&1) {
+    if message.first() != Some(&1) {
         return Err("not client hello");
     }
-    let hello = truncate(fragment, 1..4)?;
+    let hello = truncate(message, 1..4)?;
     // 0..2: client version
     if hello.first() != Some(&3) {
         return Err("unsupported client version");
     }
+    let client_version = u16::from_be_bytes([hello[0], *hello.get(1).ok_or("not enough data")?]);
     // 2..34: 32-bytes random, dropped
     // 34+: session id, dropped
     let remaining = drop_before(hello, 34..35)?;
@@ -78,6 +135,8 @@ pub fn parse_client_hello(data: &[u8]) -> Result<TlsClientHello, &'static str> {
     let mut exts = truncate(remaining, 0..2)?;
     let mut server_name = None;
     let mut early_data = false;
+    let mut alpn = Vec::new();
+    let mut versions = Vec::new();
     while exts.len() >= 4 {
         // 0..2: extension type
         let ext_type = &exts[0..2];
@@ -88,15 +147,49 @@ pub fn parse_client_hello(data: &[u8]) -> Result<TlsClientHello, &'static str> {
             server_name = parse_server_name_ext(ext_data)?;
         } else if ext_type == EXT_EARLY_DATA {
             early_data = true;
+        } else if ext_type == EXT_ALPN {
+            alpn = parse_alpn_ext(ext_data)?;
+        } else if ext_type == EXT_SUPPORTED_VERSIONS {
+            versions = parse_supported_versions_ext(ext_data)?;
         }
     }
+    if versions.is_empty() {
+        versions.push(client_version);
+    }
 
     Ok(TlsClientHello {
-        server_name,
+        server_name: server_name.map(|name| name.to_string()),
         early_data,
+        alpn,
+        versions,
     })
 }
 
+/// Parse ALPN data, return protocol names.
+fn parse_alpn_ext(ext_data: &[u8]) -> Result<Vec<String>, &'static str> {
+    let mut data = truncate(ext_data, 0..2)?;
+    let mut protocols = Vec::new();
+    while !data.is_empty() {
+        let name = truncate(data, 0..1)?;
+        data = drop_before(data, 0..1)?;
+        // Non-printable names are useless for policy matching
+        if !name.is_empty() && name.iter().all(u8::is_ascii_graphic) {
+            protocols.push(String::from_utf8_lossy(name).into_owned());
+        }
+    }
+    Ok(protocols)
+}
+
+/// Parse supported_versions data, return versions without GREASE.
+fn parse_supported_versions_ext(ext_data: &[u8]) -> Result<Vec<u16>, &'static str> {
+    let data = truncate(ext_data, 0..1)?;
+    Ok(data
+        .chunks_exact(2)
+        .map(|v| u16::from_be_bytes([v[0], v[1]]))
+        .filter(|v| v & 0x0f0f != 0x0a0a)
+        .collect())
+}
+
 /// Parse SNI data, return hostname.
 fn parse_server_name_ext(ext_data: &[u8]) -> Result<Option<&str>, &'static str> {
     let mut data = truncate(ext_data, 0..2)?;
@@ -151,7 +244,7 @@ fn test_parse_without_server_name() {
     assert_eq!(1, fragment[0]);
     assert_eq!(Some(&1), fragment.last());
 
-    let TlsClientHello { server_name, .. } = parse_client_hello(&data).unwrap();
+    let TlsClientHello { server_name, .. } = parse_client_hello(&data).unwrap().unwrap();
     assert_eq!(None, server_name);
 }
 
@@ -172,6 +265,53 @@ fn test_parse_with_server_name() {
         0x00, 0x18, 0x00, 0x16, 0x04, 0x03, 0x05, 0x03, 0x06, 0x03, 0x08, 0x04, 0x08, 0x05, 0x08,
         0x06, 0x04, 0x01, 0x05, 0x01, 0x06, 0x01, 0x02, 0x03, 0x02, 0x01,
     ];
-    let TlsClientHello { server_name, .. } = parse_client_hello(&data).unwrap();
-    assert_eq!(Some("www.google.com"), server_name);
+    let TlsClientHello {
+        server_name,
+        alpn,
+        versions,
+        ..
+    } = parse_client_hello(&data).unwrap().unwrap();
+    assert_eq!(Some("www.google.com"), server_name.as_deref());
+    assert_eq!(vec!["h2", "http/1.1"], alpn);
+    assert_eq!(vec![0x0303], versions);
+
+    // Incomplete
+    assert!(parse_client_hello(&data[..4]).unwrap().is_none());
+    assert!(parse_client_hello(&data[..100]).unwrap().is_none());
+
+    // Fragmented into two records
+    let (first, second) = data[5..].split_at(50);
+    let mut fragmented = vec![0x16, 0x03, 0x01, 0x00, first.len() as u8];
+    fragmented.extend_from_slice(first);
+    assert!(parse_client_hello(&fragmented).unwrap().is_none());
+    fragmented.extend_from_slice(&[0x16, 0x03, 0x01, 0x00, second.len() as u8]);
+    fragmented.extend_from_slice(second);
+    let TlsClientHello { server_name, .. } = parse_client_hello(&fragmented).unwrap().unwrap();
+    assert_eq!(Some("www.google.com"), server_name.as_deref());
+
+    // Not TLS
+    assert!(parse_client_hello(b"GET / HTTP/1.1\r\n").is_err());
+}
+
+#[test]
+fn test_parse_extensions() {
+    assert_eq!(
+        vec!["h2", "acme-tls/1"],
+        parse_alpn_ext(b"\x00\x0e\x02h2\x0aacme-tls/1").unwrap()
+    );
+    assert!(parse_alpn_ext(b"\x00\x04\x05h2").is_err());
+    assert_eq!(
+        vec![0x0304, 0x0303],
+        parse_supported_versions_ext(&[6, 0x3a, 0x3a, 3, 4, 3, 3]).unwrap()
+    );
+}
+
+#[test]
+fn test_is_tls_response() {
+    assert!(is_tls_response(&[22, 3, 3, 0, 0x7a]));
+    assert!(is_tls_response(&[21, 3, 1]));
+    assert!(is_tls_response(&[22]));
+    assert!(!is_tls_response(b""));
+    assert!(!is_tls_response(b"HTTP/1.1 302 Found\r\n"));
+    assert!(!is_tls_response(&[22, 3, 9]));
 }
@@ -0,0 +1,364 @@
+use flexstr::SharedStr;
+use futures_util::{future, FutureExt};
+use parking_lot::Mutex;
+use std::{
+    collections::HashMap,
+    io,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::AsyncReadExt,
+    net::{lookup_host, TcpStream, UdpSocket},
+    sync::mpsc,
+    task::JoinHandle,
+    time::{interval, timeout},
+};
+use tracing::{debug, info, instrument, trace, warn};
+
+use super::{udp::canonical, UdpRoute};
+use crate::{
+    dns::fakeip::FakeIpPool,
+    linux::tproxy::{bind_udp_nonlocal, recv_with_orig_dst},
+    policy::RequestFeatures,
+    proxy::{
+        socks5::{build_udp_header, parse_udp_header},
+        Address, Destination, ProxyServer,
+    },
+};
+
+const MAX_DATAGRAM_SIZE: usize = 65535;
+/// Max number of concurrent UDP flows on each listener.
+const MAX_FLOWS: usize = 4096;
+/// Flows without any traffic in this duration are closed.
+const FLOW_IDLE_TIMEOUT: Duration = Duration::from_secs(120);
+/// Max number of datagrams queued for a flow being set up.
+const MAX_PENDING: usize = 16;
+/// Datagrams of a flow failed to set up are dropped in this duration,
+/// instead of trying again on each one.
+const FAILED_FLOW_TTL: Duration = Duration::from_secs(10);
+
+/// Client address and original destination.
+type FlowKey = (SocketAddr, SocketAddr);
+
+/// A UDP flow, identified by client address and original destination.
+struct Flow {
+    server: Arc<ProxyServer>,
+    /// Outbound socket, connected to upstream's relay or the destination.
+    socket: Arc<UdpSocket>,
+    /// Encapsulate with SOCKSv5 UDP header if relay via upstream proxy.
+    header: Option<Vec<u8>>,
+    last_active: Arc<Mutex<Instant>>,
+    /// Return true if the UDP association is closed by upstream.
+    task: JoinHandle<bool>,
+}
+
+impl Flow {
+    fn is_idle(&self, now: Instant) -> bool {
+        self.task.is_finished() || now - *self.last_active.lock() > FLOW_IDLE_TIMEOUT
+    }
+
+    async fn send(&self, payload: &[u8]) {
+        *self.last_active.lock() = Instant::now();
+        self.server.add_traffic((payload.len(), 0).into());
+        let result = match &self.header {
+            None => self.socket.send(payload).await,
+            Some(header) => {
+                let mut datagram = Vec::with_capacity(header.len() + payload.len());
+                datagram.extend_from_slice(header);
+                datagram.extend_from_slice(payload);
+                self.socket.send(&datagram).await
+            }
+        };
+        if let Err(err) = result {
+            debug!(?err, "fail to send datagram");
+        }
+    }
+
+    fn close(mut self) {
+        let has_error = if self.task.is_finished() {
+            matches!((&mut self.task).now_or_never(), Some(Ok(true)))
+        } else {
+            self.task.abort();
+            false
+        };
+        self.server.update_stats_conn_close(has_error);
+    }
+}
+
+/// Serve UDP datagrams TPROXY-ed to `socket`.
+/// `route` is called on each new flow to decide where it goes. Flows are
+/// set up in other tasks, datagrams are queued meanwhile.
+/// Destinations in `fake_ip` pool are translated back to domain names.
+#[instrument(level = "error", skip_all, fields(on_port=socket.local_addr()?.port()))]
+pub async fn serve_tproxy_udp<F>(
+    socket: UdpSocket,
+    fake_ip: Option<Arc<FakeIpPool>>,
+    route: F,
+) -> io::Result<()>
+where
+    F: Fn(&RequestFeatures<SharedStr>) -> UdpRoute,
+{
+    let from_port = socket.local_addr()?.port();
+    let mut flows: HashMap<FlowKey, Flow> = HashMap::new();
+    let mut pending: HashMap<FlowKey, Vec<Vec<u8>>> = HashMap::new();
+    let mut failed: HashMap<FlowKey, Instant> = HashMap::new();
+    let (flow_tx, mut flow_rx) = mpsc::channel(16);
+    let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+    let mut cleanup = interval(FLOW_IDLE_TIMEOUT / 4);
+    loop {
+        tokio::select! {
+            _ = cleanup.tick() => {
+                let now = Instant::now();
+                let idle: Vec<_> = flows
+                    .iter()
+                    .filter(|(_, flow)| flow.is_idle(now))
+                    .map(|(key, _)| *key)
+                    .collect();
+                for key in idle {
+                    trace!(src = %key.0, dst = %key.1, "close idle UDP flow");
+                    if let Some(flow) = flows.remove(&key) {
+                        flow.close();
+                    }
+                }
+                failed.retain(|_, since| now - *since < FAILED_FLOW_TTL);
+            }
+            Some((key, result)) = flow_rx.recv() => {
+                let (src, dst): FlowKey = key;
+                let datagrams = pending.remove(&key).unwrap_or_default();
+                match result {
+                    Ok(Some(flow)) => {
+                        for datagram in datagrams {
+                            flow.send(&datagram).await;
+                        }
+                        flows.insert(key, flow);
+                    }
+                    Ok(None) => {
+                        failed.insert(key, Instant::now());
+                    }
+                    Err(err) => {
+                        info!(%src, %dst, ?err, "fail to open UDP flow");
+                        failed.insert(key, Instant::now());
+                    }
+                }
+            }
+            result = recv_with_orig_dst(&socket, &mut buf) => {
+                let (len, src, dst) = match result {
+                    Ok(result) => result,
+                    Err(err) => {
+                        debug!(?err, "fail to receive datagram");
+                        continue;
+                    }
+                };
+                let (src, dst) = (canonical(src), canonical(dst));
+                let key = (src, dst);
+                if flows.get(&key).map_or(false, |flow| flow.task.is_finished()) {
+                    // Closed by either side, open a new one
+                    trace!(%src, %dst, "UDP flow closed");
+                    flows.remove(&key).expect("flow not found").close();
+                }
+                if let Some(flow) = flows.get(&key) {
+                    flow.send(&buf[..len]).await;
+                    continue;
+                }
+                if let Some(queue) = pending.get_mut(&key) {
+                    if queue.len() < MAX_PENDING {
+                        queue.push(buf[..len].to_vec());
+                    }
+                    continue;
+                }
+                if failed.get(&key).map_or(false, |since| since.elapsed() < FAILED_FLOW_TTL) {
+                    trace!(%src, %dst, "UDP flow failed recently, drop datagram");
+                    continue;
+                }
+                if flows.len() + pending.len() >= MAX_FLOWS {
+                    warn!(%src, %dst, "too many UDP flows, drop datagram");
+                    continue;
+                }
+                let domain = fake_ip.as_ref().and_then(|pool| pool.lookup(dst.ip()));
+                let dest: Destination = match domain {
+                    Some(domain) => {
+                        debug!(%dst, %domain, "fake IP translated");
+                        (Address::Domain(domain), dst.port()).into()
+                    }
+                    None => dst.into(),
+                };
+                let features = RequestFeatures {
+                    listen_port: Some(from_port),
+                    dst_ip: match dest.host {
+                        Address::Ip(ip) => Some(ip),
+                        Address::Domain(_) => None,
+                    },
+                    dst_domain: dest.host.domain(),
+                    ..Default::default()
+                };
+                let route = route(&features);
+                pending.insert(key, vec![buf[..len].to_vec()]);
+                let flow_tx = flow_tx.clone();
+                tokio::spawn(async move {
+                    let result = new_flow(src, dst, dest, route).await;
+                    let _ = flow_tx.send((key, result)).await;
+                });
+            }
+        }
+    }
+}
+
+/// `dst` is the original destination, `dest` is where it actually goes,
+/// which differ if `dst` is a fake IP.
+#[instrument(level = "error", skip_all, fields(%src, %dst))]
+async fn new_flow(
+    src: SocketAddr,
+    dst: SocketAddr,
+    dest: Destination,
+    route: UdpRoute,
+) -> io::Result<Option<Flow>> {
+    let (server, socket, header, control) = match route {
+        UdpRoute::Reject => {
+            info!("UDP rejected by policy");
+            return Ok(None);
+        }
+        UdpRoute::Direct(server) => {
+            let addr = match &dest.host {
+                Address::Ip(_) => dst,
+                Address::Domain(name) => lookup_host((name.as_str(), dest.port))
+                    .await?
+                    .next()
+                    .ok_or_else(|| {
+                        io::Error::new(io::ErrorKind::NotFound, "no address resolved")
+                    })?,
+            };
+            let socket = server.bind().bind_udp(&addr).await?;
+            socket.connect(addr).await?;
+            debug!("UDP flow sent directly");
+            (server, socket, None, None)
+        }
+        UdpRoute::Proxy(servers) => {
+            let mut associated = None;
+            for server in servers {
+                let result = timeout(server.max_wait(), server.udp_associate()).await;
+                match result.unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into())) {
+                    Ok(r) => {
+                        info!(proxy = %server.tag, "UDP flow relayed via upstream");
+                        associated = Some((server, r));
+                        break;
+                    }
+                    Err(err) => {
+                        info!(proxy = %server.tag, ?err, "fail to do UDP associate");
+                        server.update_stats_conn_open();
+                        server.update_stats_conn_close(true);
+                    }
+                }
+            }
+            let (server, relay) = match associated {
+                Some(associated) => associated,
+                None => {
+                    warn!("no available proxy for UDP");
+                    return Ok(None);
+                }
+            };
+            let mut header = Vec::with_capacity(22);
+            build_udp_header(&mut header, &dest);
+            (server, relay.socket, Some(header), Some(relay.control))
+        }
+    };
+    // Reply to client as if we are the destination. Once connected, later
+    // datagrams of this flow would be delivered to it instead of listener.
+    let reply = bind_udp_nonlocal(dst)?;
+    reply.connect(src).await?;
+    let socket = Arc::new(socket);
+    let last_active = Arc::new(Mutex::new(Instant::now()));
+    server.update_stats_conn_open();
+    let task = tokio::spawn(relay(
+        socket.clone(),
+        reply,
+        header.clone(),
+        control,
+        server.clone(),
+        last_active.clone(),
+    ));
+    Ok(Some(Flow {
+        server,
+        socket,
+        header,
+        last_active,
+        task,
+    }))
+}
+
+/// Return once the control connection of UDP association closed, never
+/// if there isn't one.
+async fn control_closed(control: &mut Option<TcpStream>) -> io::Error {
+    let control = match control {
+        Some(control) => control,
+        None => return future::pending().await,
+    };
+    let mut buf = [0u8; 64];
+    loop {
+        match control.read(&mut buf).await {
+            Ok(0) => return io::ErrorKind::UnexpectedEof.into(),
+            Ok(_) => continue,
+            Err(err) => return err,
+        }
+    }
+}
+
+/// Return true if the upstream closed the UDP association.
+async fn relay(
+    socket: Arc<UdpSocket>,
+    reply: UdpSocket,
+    header: Option<Vec<u8>>,
+    mut control: Option<TcpStream>,
+    server: Arc<ProxyServer>,
+    last_active: Arc<Mutex<Instant>>,
+) -> bool {
+    let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+    // Leave room for the header
+    let header_len = header.as_ref().map(|h| h.len()).unwrap_or(0);
+    let mut client_buf = vec![0u8; header_len + MAX_DATAGRAM_SIZE];
+    if let Some(header) = &header {
+        client_buf[..header_len].copy_from_slice(header);
+    }
+    loop {
+        let result = tokio::select! {
+            err = control_closed(&mut control) => {
+                debug!(proxy = %server.tag, ?err, "UDP association closed by upstream");
+                return true;
+            }
+            // client => destination
+            result = reply.recv(&mut client_buf[header_len..]) => match result {
+                Ok(len) => {
+                    server.add_traffic((len, 0).into());
+                    socket.send(&client_buf[..header_len + len]).await
+                }
+                Err(err) => Err(err),
+            },
+            // destination => client
+            result = socket.recv(&mut buf) => match result {
+                Ok(len) => {
+                    let payload = match &header {
+                        None => &buf[..len],
+                        Some(_) => match parse_udp_header(&buf[..len]) {
+                            Some((_, header_len)) => &buf[header_len..len],
+                            None => {
+                                debug!("drop malformed datagram from upstream");
+                                continue;
+                            }
+                        },
+                    };
+                    server.add_traffic((0, payload.len()).into());
+                    reply.send(payload).await
+                }
+                Err(err) => Err(err),
+            },
+        };
+        match result {
+            Ok(_) => *last_active.lock() = Instant::now(),
+            Err(err) => {
+                debug!(?err, "UDP flow closed");
+                return false;
+            }
+        }
+    }
+}
@@ -0,0 +1,532 @@
+use flexstr::SharedStr;
+use futures_util::future::BoxFuture;
+use parking_lot::Mutex;
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{lookup_host, UdpSocket},
+    sync::mpsc,
+    task::JoinHandle,
+    time::timeout,
+};
+use tracing::{debug, info, instrument, trace, warn};
+
+use super::{NewClient, SocketAddrExt};
+use crate::{
+    policy::RequestFeatures,
+    proxy::{
+        socks5::{build_udp_header, parse_udp_header, UdpRelay},
+        Address, AtomicTraffic, Destination, ProxyServer, Traffic,
+    },
+};
+
+/// Max size of UDP datagram we handle.
+const MAX_DATAGRAM_SIZE: usize = 65535;
+/// Max number of remembered destinations for each association.
+const MAX_ROUTES: usize = 1024;
+/// Max number of datagrams queued for a destination whose route is
+/// being set up, later ones are dropped.
+const MAX_PENDING: usize = 16;
+
+/// Where datagrams to a destination should go.
+#[derive(Debug)]
+pub enum UdpRoute {
+    /// Send directly, traffic is counted on the given (pseudo) server.
+    Direct(Arc<ProxyServer>),
+    /// Relay via the first available upstream proxy.
+    Proxy(Vec<Arc<ProxyServer>>),
+    Reject,
+}
+
+#[derive(Debug, Clone)]
+enum Outbound {
+    Direct(SocketAddr),
+    Proxy(SharedStr),
+    Reject,
+}
+
+/// Result of setting up a route, which is done off the receiving loop.
+enum Setup {
+    Direct(Arc<ProxyServer>, SocketAddr),
+    /// New UDP association on the upstream.
+    Associated(Arc<ProxyServer>, UdpRelay),
+    /// Use the existing association on the upstream.
+    Existing(SharedStr),
+    Reject,
+}
+
+/// UDP association on an upstream proxy, with a task that copy datagrams
+/// from it back to our client.
+struct Upstream {
+    server: Arc<ProxyServer>,
+    socket: Arc<UdpSocket>,
+    task: JoinHandle<()>,
+}
+
+/// Sockets sending datagrams directly, bound on demand for each address
+/// family, with tasks that copy replies back to our client.
+struct Direct {
+    server: Arc<ProxyServer>,
+    /// Destinations sent to, datagrams from any others are dropped.
+    peers: Arc<Mutex<HashSet<SocketAddr>>>,
+    v4: Option<(Arc<UdpSocket>, JoinHandle<()>)>,
+    v6: Option<(Arc<UdpSocket>, JoinHandle<()>)>,
+}
+
+impl Direct {
+    fn slot(&mut self, addr: &SocketAddr) -> &mut Option<(Arc<UdpSocket>, JoinHandle<()>)> {
+        match addr {
+            SocketAddr::V4(_) => &mut self.v4,
+            SocketAddr::V6(_) => &mut self.v6,
+        }
+    }
+
+    fn close(self) {
+        for (_, task) in self.v4.into_iter().chain(self.v6) {
+            task.abort();
+        }
+    }
+}
+
+/// State of one UDP ASSOCIATE, shared with the receiving tasks.
+struct Association {
+    socket: Arc<UdpSocket>,
+    client_addr: SocketAddr,
+    user_traffic: Option<Arc<AtomicTraffic>>,
+}
+
+impl Association {
+    fn count(&self, server: &ProxyServer, amt: Traffic) {
+        server.add_traffic(amt);
+        if let Some(user_traffic) = &self.user_traffic {
+            user_traffic.add(amt);
+        }
+    }
+}
+
+fn error_unexpected_reply() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "SOCKSv5: unexpected UDP reply")
+}
+
+impl NewClient {
+    /// Serve SOCKSv5 UDP ASSOCIATE until the TCP connection is closed.
+    /// `route` is called on each new destination to decide where it goes.
+    #[instrument(level = "error", skip_all)]
+    pub async fn serve_udp_associate<F>(mut self, route: F) -> io::Result<()>
+    where
+        F: Fn(&RequestFeatures<SharedStr>) -> UdpRoute,
+    {
+        // Bind relay socket on the same address client connected to
+        let local_ip = match self.left.tcp() {
+            Ok(tcp) => tcp.local_addr()?.ip(),
+            Err(err) => {
+                self.reply_err(&err).await;
+                return Err(err);
+            }
+        };
+        let socket = match UdpSocket::bind((local_ip, 0)).await {
+            Ok(socket) => Arc::new(socket),
+            Err(err) => {
+                self.reply_err(&err).await;
+                return Err(err);
+            }
+        };
+        let relay_addr = canonical(socket.local_addr()?);
+        info!(%relay_addr, "UDP associated");
+        self.reply_ok(relay_addr).await?;
+
+        let client_ip = self.left.tcp()?.peer_addr()?.normalize().ip();
+        let mut relay = UdpSession {
+            client: self,
+            assoc: None,
+            direct: None,
+            upstreams: HashMap::new(),
+            routes: HashMap::new(),
+            pending: HashMap::new(),
+        };
+        let result = relay.run(&socket, client_ip, route).await;
+        relay.close();
+        result
+    }
+}
+
+struct UdpSession {
+    client: NewClient,
+    assoc: Option<Arc<Association>>,
+    direct: Option<Direct>,
+    upstreams: HashMap<SharedStr, Upstream>,
+    routes: HashMap<Destination, Outbound>,
+    /// Datagrams (with header length) waiting for their route set up.
+    pending: HashMap<Destination, Vec<(Vec<u8>, usize)>>,
+}
+
+impl UdpSession {
+    async fn run<F>(
+        &mut self,
+        socket: &Arc<UdpSocket>,
+        client_ip: IpAddr,
+        route: F,
+    ) -> io::Result<()>
+    where
+        F: Fn(&RequestFeatures<SharedStr>) -> UdpRoute,
+    {
+        let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+        let mut control_buf = [0u8; 64];
+        let (setup_tx, mut setup_rx) = mpsc::channel(16);
+        loop {
+            tokio::select! {
+                result = self.client.left.read(&mut control_buf) => {
+                    // Association terminates once the TCP connection closed
+                    if result? == 0 {
+                        debug!("TCP connection closed");
+                        return Ok(());
+                    }
+                }
+                Some((dest, setup)) = setup_rx.recv() => {
+                    self.apply_setup(dest, setup).await;
+                }
+                result = socket.recv_from(&mut buf) => {
+                    let (len, from) = result?;
+                    if from.normalize().ip() != client_ip {
+                        trace!(%from, "drop datagram from unknown source");
+                        continue;
+                    }
+                    match &self.assoc {
+                        Some(assoc) if assoc.client_addr == from => (),
+                        Some(_) => {
+                            trace!(%from, "drop datagram from other port");
+                            continue;
+                        }
+                        None => {
+                            self.assoc = Some(Arc::new(Association {
+                                socket: socket.clone(),
+                                client_addr: from,
+                                user_traffic: self.client.user_traffic.clone(),
+                            }));
+                        }
+                    };
+                    self.forward(&buf[..len], &route, &setup_tx).await;
+                }
+            }
+        }
+    }
+
+    /// Forward a datagram from client to its destination. If the route is
+    /// unknown yet, queue it and set up the route in another task.
+    async fn forward<F>(
+        &mut self,
+        datagram: &[u8],
+        route: &F,
+        setup_tx: &mpsc::Sender<(Destination, Setup)>,
+    ) where
+        F: Fn(&RequestFeatures<SharedStr>) -> UdpRoute,
+    {
+        let (dest, header_len) = match parse_udp_header(datagram) {
+            Some(header) => header,
+            None => {
+                debug!("drop malformed or fragmented datagram");
+                return;
+            }
+        };
+        if self.routes.contains_key(&dest) {
+            self.send(&dest, datagram, header_len).await;
+            return;
+        }
+        if let Some(queue) = self.pending.get_mut(&dest) {
+            if queue.len() < MAX_PENDING {
+                queue.push((datagram.to_vec(), header_len));
+            } else {
+                trace!(?dest, "too many pending datagrams, drop it");
+            }
+            return;
+        }
+        let features = RequestFeatures {
+            listen_port: self.client.from_port,
+            dst_ip: match dest.host {
+                Address::Ip(ip) => Some(ip),
+                Address::Domain(_) => None,
+            },
+            dst_domain: dest.host.domain(),
+            username: self.client.username.clone(),
+            ..Default::default()
+        };
+        let existing: Vec<_> = self.upstreams.keys().cloned().collect();
+        let task: BoxFuture<'static, Setup> = match route(&features) {
+            UdpRoute::Reject => {
+                info!(?dest, "UDP rejected by policy");
+                self.insert_route(dest, Outbound::Reject);
+                return;
+            }
+            UdpRoute::Direct(server) => Box::pin(setup_direct(dest.clone(), server)),
+            UdpRoute::Proxy(servers) => Box::pin(setup_proxy(dest.clone(), servers, existing)),
+        };
+        self.pending
+            .insert(dest.clone(), vec![(datagram.to_vec(), header_len)]);
+        let setup_tx = setup_tx.clone();
+        tokio::spawn(async move {
+            let setup = task.await;
+            let _ = setup_tx.send((dest, setup)).await;
+        });
+    }
+
+    /// Send a datagram to `dest`, whose route must be known.
+    async fn send(&mut self, dest: &Destination, datagram: &[u8], header_len: usize) {
+        let assoc = self.assoc.clone().expect("no association");
+        let payload = &datagram[header_len..];
+        let result = match self.routes.get(dest).cloned() {
+            None | Some(Outbound::Reject) => return,
+            Some(Outbound::Direct(addr)) => {
+                let direct = self.direct.as_mut().expect("no direct socket");
+                let server = direct.server.clone();
+                match direct.slot(&addr) {
+                    Some((socket, task)) if !task.is_finished() => {
+                        assoc.count(&server, (payload.len(), 0).into());
+                        socket.send_to(payload, addr).await
+                    }
+                    slot => {
+                        // Socket closed, re-route on next datagram
+                        debug!("direct UDP socket closed");
+                        *slot = None;
+                        self.routes.remove(dest);
+                        return;
+                    }
+                }
+            }
+            Some(Outbound::Proxy(tag)) => match self.upstreams.get(&tag) {
+                Some(upstream) if !upstream.task.is_finished() => {
+                    assoc.count(&upstream.server, (payload.len(), 0).into());
+                    // Same header on both sides, forward as it is
+                    upstream.socket.send(datagram).await
+                }
+                _ => {
+                    // Upstream closed, re-route on next datagram
+                    debug!(proxy = %tag, "UDP association on upstream closed");
+                    if let Some(upstream) = self.upstreams.remove(&tag) {
+                        upstream.server.update_stats_conn_close(true);
+                    }
+                    self.routes
+                        .retain(|_, outbound| !matches!(outbound, Outbound::Proxy(t) if t == &tag));
+                    return;
+                }
+            },
+        };
+        if let Err(err) = result {
+            debug!(?dest, ?err, "fail to send datagram");
+        }
+    }
+
+    /// Apply the route set up, then flush datagrams queued for it.
+    async fn apply_setup(&mut self, dest: Destination, setup: Setup) {
+        let outbound = match setup {
+            Setup::Reject => Outbound::Reject,
+            Setup::Direct(server, addr) => match self.bind_direct(server, &addr).await {
+                Ok(()) => Outbound::Direct(addr),
+                Err(err) => {
+                    info!(?dest, ?err, "fail to send UDP directly");
+                    Outbound::Reject
+                }
+            },
+            Setup::Existing(tag) => Outbound::Proxy(tag),
+            Setup::Associated(server, relay) => {
+                let tag = server.tag.clone();
+                // Another route may have associated on it meanwhile
+                if !self.upstreams.contains_key(&tag) {
+                    info!(proxy = %tag, ?dest, "UDP associated on upstream");
+                    let assoc = self.assoc.clone().expect("no association");
+                    let upstream = spawn_upstream(assoc, server, relay);
+                    self.upstreams.insert(tag.clone(), upstream);
+                }
+                Outbound::Proxy(tag)
+            }
+        };
+        self.insert_route(dest.clone(), outbound);
+        for (datagram, header_len) in self.pending.remove(&dest).unwrap_or_default() {
+            self.send(&dest, &datagram, header_len).await;
+        }
+    }
+
+    fn insert_route(&mut self, dest: Destination, outbound: Outbound) {
+        if self.routes.len() >= MAX_ROUTES {
+            self.routes.clear();
+            if let Some(direct) = &self.direct {
+                direct.peers.lock().clear();
+            }
+        }
+        if let (Outbound::Direct(addr), Some(direct)) = (&outbound, &self.direct) {
+            direct.peers.lock().insert(*addr);
+        }
+        self.routes.insert(dest, outbound);
+    }
+
+    /// Bind the direct socket for the family of `addr` if not yet.
+    async fn bind_direct(&mut self, server: Arc<ProxyServer>, addr: &SocketAddr) -> io::Result<()> {
+        let assoc = self.assoc.clone().expect("no association");
+        let direct = self.direct.get_or_insert_with(|| Direct {
+            server,
+            peers: Default::default(),
+            v4: None,
+            v6: None,
+        });
+        let (server, peers) = (direct.server.clone(), direct.peers.clone());
+        let slot = direct.slot(addr);
+        if slot.is_none() {
+            let socket = Arc::new(server.bind().bind_udp(addr).await?);
+            let task = tokio::spawn(copy_direct_to_client(assoc, socket.clone(), server, peers));
+            *slot = Some((socket, task));
+        }
+        Ok(())
+    }
+
+    fn close(&mut self) {
+        if let Some(direct) = self.direct.take() {
+            direct.close();
+        }
+        for (_, upstream) in self.upstreams.drain() {
+            upstream.task.abort();
+            upstream.server.update_stats_conn_close(false);
+        }
+    }
+}
+
+async fn setup_direct(dest: Destination, server: Arc<ProxyServer>) -> Setup {
+    let addr = match &dest.host {
+        Address::Ip(ip) => Ok(SocketAddr::new(*ip, dest.port)),
+        Address::Domain(name) => match lookup_host((name.as_str(), dest.port)).await {
+            Ok(mut addrs) => addrs
+                .next()
+                .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no address resolved")),
+            Err(err) => Err(err),
+        },
+    };
+    match addr {
+        Ok(addr) => Setup::Direct(server, canonical(addr)),
+        Err(err) => {
+            info!(?dest, ?err, "fail to resolve UDP destination");
+            Setup::Reject
+        }
+    }
+}
+
+/// Try `servers` in order, reuse the association if `existing` on it.
+async fn setup_proxy(
+    dest: Destination,
+    servers: Vec<Arc<ProxyServer>>,
+    existing: Vec<SharedStr>,
+) -> Setup {
+    for server in servers {
+        if existing.contains(&server.tag) {
+            return Setup::Existing(server.tag.clone());
+        }
+        let result = timeout(server.max_wait(), server.udp_associate()).await;
+        match result.unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into())) {
+            Ok(relay) => return Setup::Associated(server, relay),
+            Err(err) => {
+                info!(proxy = %server.tag, ?err, "fail to do UDP associate");
+                server.update_stats_conn_open();
+                server.update_stats_conn_close(true);
+            }
+        }
+    }
+    warn!(?dest, "no available proxy for UDP");
+    Setup::Reject
+}
+
+fn spawn_upstream(assoc: Arc<Association>, server: Arc<ProxyServer>, relay: UdpRelay) -> Upstream {
+    let UdpRelay {
+        mut control,
+        socket,
+    } = relay;
+    let socket = Arc::new(socket);
+    server.update_stats_conn_open();
+    let task = {
+        let server = server.clone();
+        let socket = socket.clone();
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+            let mut control_buf = [0u8; 64];
+            loop {
+                let result = tokio::select! {
+                    result = control.read(&mut control_buf) => match result {
+                        Ok(0) => Err(io::ErrorKind::UnexpectedEof.into()),
+                        Ok(_) => continue,
+                        Err(err) => Err(err),
+                    },
+                    result = socket.recv(&mut buf) => match result {
+                        Ok(len) => copy_upstream_to_client(&assoc, &server, &buf[..len]).await,
+                        Err(err) => Err(err),
+                    },
+                };
+                if let Err(err) = result {
+                    debug!(proxy = %server.tag, ?err, "UDP upstream closed");
+                    let _ = control.shutdown().await;
+                    return;
+                }
+            }
+        })
+    };
+    Upstream {
+        server,
+        socket,
+        task,
+    }
+}
+
+async fn copy_upstream_to_client(
+    assoc: &Association,
+    server: &ProxyServer,
+    datagram: &[u8],
+) -> io::Result<()> {
+    let (_, header_len) = parse_udp_header(datagram).ok_or_else(error_unexpected_reply)?;
+    assoc.count(server, (0, datagram.len() - header_len).into());
+    assoc.socket.send_to(datagram, assoc.client_addr).await?;
+    Ok(())
+}
+
+/// Copy datagrams from `peers` back to client, until error on `socket`.
+async fn copy_direct_to_client(
+    assoc: Arc<Association>,
+    socket: Arc<UdpSocket>,
+    server: Arc<ProxyServer>,
+    peers: Arc<Mutex<HashSet<SocketAddr>>>,
+) {
+    let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+    let mut datagram = Vec::with_capacity(MAX_DATAGRAM_SIZE);
+    loop {
+        let (len, from) = match socket.recv_from(&mut buf).await {
+            Ok(result) => result,
+            Err(err) => {
+                debug!(?err, "fail to receive datagram, close direct socket");
+                return;
+            }
+        };
+        let from = canonical(from);
+        // Not a full-cone NAT, only the destinations can reply
+        if !peers.lock().contains(&from) {
+            trace!(%from, "drop datagram from unknown source");
+            continue;
+        }
+        datagram.clear();
+        build_udp_header(&mut datagram, &from.into());
+        datagram.extend_from_slice(&buf[..len]);
+        assoc.count(&server, (0, len).into());
+        if let Err(err) = assoc.socket.send_to(&datagram, assoc.client_addr).await {
+            debug!(?err, "fail to send datagram to client");
+        }
+    }
+}
+
+/// Convert IPv4-mapped IPv6 address back to IPv4.
+pub(super) fn canonical(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V6(v6) => match v6.ip().to_ipv4_mapped() {
+            Some(ip) => SocketAddr::new(ip.into(), v6.port()),
+            None => addr,
+        },
+        _ => addr,
+    }
+}
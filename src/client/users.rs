@@ -0,0 +1,99 @@
+use flexstr::SharedStr;
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, BufRead, BufReader},
+    path::Path,
+};
+use tracing::info;
+
+/// Accounts for downstream SOCKSv5 username/password authentication
+/// (RFC 1929) and HTTP proxy basic authentication.
+#[derive(Debug, Default)]
+pub struct UserList {
+    users: HashMap<SharedStr, SharedStr>,
+}
+
+fn error_invalid_data<T>(line_no: usize, msg: &str) -> io::Result<T> {
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("line {}: {}", line_no, msg),
+    ))
+}
+
+impl UserList {
+    /// Load users from lines of `username:password`.
+    /// Empty lines and lines start with `#` are ignored.
+    pub fn load<R: BufRead>(read: R) -> io::Result<Self> {
+        let mut users = HashMap::new();
+        for (i, line) in read.lines().enumerate() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (username, password) = match line.split_once(':') {
+                Some(pair) => pair,
+                None => return error_invalid_data(i + 1, "expect username:password"),
+            };
+            if username.is_empty() {
+                return error_invalid_data(i + 1, "empty username");
+            }
+            if username.len() > 255 || password.len() > 255 {
+                return error_invalid_data(i + 1, "username/password exceeds 255 bytes");
+            }
+            if users.insert(username.into(), password.into()).is_some() {
+                return error_invalid_data(i + 1, "duplicated username");
+            }
+        }
+        Ok(Self { users })
+    }
+
+    pub fn load_from_file<T: AsRef<Path>>(path: T) -> io::Result<Self> {
+        let file = File::open(path)?;
+        let reader = BufReader::new(file);
+        let this = Self::load(reader)?;
+        info!("{} SOCKSv5 user(s) loaded", this.len());
+        Ok(this)
+    }
+
+    pub fn len(&self) -> usize {
+        self.users.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.users.is_empty()
+    }
+
+    /// Return the username if the username/password pair is correct.
+    pub fn verify(&self, username: &str, password: &str) -> Option<SharedStr> {
+        let (name, pass) = self.users.get_key_value(username)?;
+        if pass.as_bytes() == password.as_bytes() {
+            Some(name.clone())
+        } else {
+            None
+        }
+    }
+}
+
+#[test]
+fn test_load_user_list() {
+    let users = UserList::load(
+        "
+        # comment
+        alice:pAsS:word
+        bob:
+    "
+        .as_bytes(),
+    )
+    .unwrap();
+    assert_eq!(2, users.len());
+    assert_eq!(Some("alice".into()), users.verify("alice", "pAsS:word"));
+    assert_eq!(Some("bob".into()), users.verify("bob", ""));
+    assert_eq!(None, users.verify("alice", "password"));
+    assert_eq!(None, users.verify("carol", ""));
+
+    assert!(UserList::load("alice".as_bytes()).is_err());
+    assert!(UserList::load(":password".as_bytes()).is_err());
+    assert!(UserList::load("a:1\na:2".as_bytes()).is_err());
+}
@@ -0,0 +1,229 @@
+//! Fake-IP DNS: answer each domain name with an address from a reserved
+//! pool, so that transparent connections to that address can be mapped
+//! back to the domain name.
+use flexstr::SharedStr;
+use parking_lot::Mutex;
+use std::{
+    collections::{BTreeMap, HashMap},
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    sync::Arc,
+};
+use tokio::net::UdpSocket;
+use tracing::{debug, instrument, trace};
+
+use super::{
+    build_error, build_response, parse_query, RCODE_FORMAT_ERROR, RCODE_NOT_IMPLEMENTED,
+    RCODE_NO_ERROR, TYPE_A, TYPE_AAAA,
+};
+
+/// TTL of fake records. Keep it short so that clients come back before
+/// the address get reused.
+const FAKE_TTL: u32 = 1;
+
+/// Map between domain names and addresses in one IP network, least
+/// recently used ones are evicted once it's full.
+struct FakeIpTable {
+    base: u128,
+    /// Number of usable addresses, offset 0 (network address) is skipped.
+    capacity: u32,
+    domains: HashMap<SharedStr, u32>,
+    /// Offset => (domain, last used)
+    offsets: HashMap<u32, (SharedStr, u64)>,
+    /// Last used => offset
+    lru: BTreeMap<u64, u32>,
+    clock: u64,
+}
+
+impl FakeIpTable {
+    fn new(base: u128, prefix_len: u8, max_bits: u8, limit: usize) -> Self {
+        let host_bits = (max_bits - prefix_len) as u32;
+        let size = if host_bits >= 32 {
+            u32::MAX
+        } else {
+            (1u32 << host_bits).saturating_sub(2)
+        };
+        let mask = if prefix_len == 0 {
+            0
+        } else {
+            u128::MAX << (128 - prefix_len as u32 - (128 - max_bits as u32))
+        };
+        Self {
+            base: base & mask,
+            capacity: size.min(limit as u32),
+            domains: HashMap::new(),
+            offsets: HashMap::new(),
+            lru: BTreeMap::new(),
+            clock: 0,
+        }
+    }
+
+    fn touch(&mut self, offset: u32) {
+        self.clock += 1;
+        if let Some((_, last_used)) = self.offsets.get_mut(&offset) {
+            self.lru.remove(last_used);
+            *last_used = self.clock;
+            self.lru.insert(self.clock, offset);
+        }
+    }
+
+    fn allocate(&mut self, domain: &str) -> Option<u128> {
+        if let Some(&offset) = self.domains.get(domain) {
+            self.touch(offset);
+            return Some(self.base + offset as u128);
+        }
+        let offset = if self.offsets.len() < self.capacity as usize {
+            self.offsets.len() as u32 + 1
+        } else {
+            // Evict the least recently used one
+            let (_, offset) = self.lru.pop_first()?;
+            let (old, _) = self.offsets.remove(&offset)?;
+            trace!(domain = %old, "evict fake IP");
+            self.domains.remove(&old);
+            offset
+        };
+        let domain: SharedStr = domain.into();
+        self.clock += 1;
+        self.domains.insert(domain.clone(), offset);
+        self.offsets.insert(offset, (domain, self.clock));
+        self.lru.insert(self.clock, offset);
+        Some(self.base + offset as u128)
+    }
+
+    fn contains(&self, ip: u128) -> bool {
+        ip.checked_sub(self.base).map_or(false, |offset| {
+            offset > 0 && offset <= self.capacity as u128
+        })
+    }
+
+    fn lookup(&mut self, ip: u128) -> Option<SharedStr> {
+        let offset = u32::try_from(ip.checked_sub(self.base)?).ok()?;
+        let domain = self.offsets.get(&offset)?.0.clone();
+        self.touch(offset);
+        Some(domain)
+    }
+}
+
+/// Fake IP pools for both IPv4 & IPv6, shared by the DNS server and
+/// clients.
+pub struct FakeIpPool {
+    v4: Option<Mutex<FakeIpTable>>,
+    v6: Option<Mutex<FakeIpTable>>,
+}
+
+impl FakeIpPool {
+    /// Create pools on given networks, each holds up to `limit` domains.
+    pub fn new(v4: Option<(Ipv4Addr, u8)>, v6: Option<(Ipv6Addr, u8)>, limit: usize) -> Self {
+        let v4 = v4.map(|(ip, len)| FakeIpTable::new(u32::from(ip) as u128, len, 32, limit));
+        let v6 = v6.map(|(ip, len)| FakeIpTable::new(u128::from(ip), len, 128, limit));
+        Self {
+            v4: v4.map(Mutex::new),
+            v6: v6.map(Mutex::new),
+        }
+    }
+
+    /// Get a fake address for `domain`, allocate one if needed.
+    /// Return `None` if there is no pool for that IP version.
+    pub fn allocate(&self, domain: &str, ipv6: bool) -> Option<IpAddr> {
+        if ipv6 {
+            let ip = self.v6.as_ref()?.lock().allocate(domain)?;
+            Some(Ipv6Addr::from(ip).into())
+        } else {
+            let ip = self.v4.as_ref()?.lock().allocate(domain)?;
+            Some(Ipv4Addr::from(ip as u32).into())
+        }
+    }
+
+    /// Find the domain name that `ip` stands for.
+    pub fn lookup(&self, ip: IpAddr) -> Option<SharedStr> {
+        match ip {
+            IpAddr::V4(ip) => self.v4.as_ref()?.lock().lookup(u32::from(ip) as u128),
+            IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+                Some(ip) => self.lookup(ip.into()),
+                None => self.v6.as_ref()?.lock().lookup(u128::from(ip)),
+            },
+        }
+    }
+
+    /// Could `ip` be allocated by one of the pools?
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match ip {
+            IpAddr::V4(ip) => self
+                .v4
+                .as_ref()
+                .map_or(false, |table| table.lock().contains(u32::from(ip) as u128)),
+            IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+                Some(ip) => self.contains(ip.into()),
+                None => self
+                    .v6
+                    .as_ref()
+                    .map_or(false, |table| table.lock().contains(u128::from(ip))),
+            },
+        }
+    }
+
+    /// Answer a DNS query, return the response.
+    pub fn answer(&self, query: &[u8]) -> Option<Vec<u8>> {
+        let question = match parse_query(query) {
+            Some(question) => question,
+            None => return build_error(query, RCODE_FORMAT_ERROR),
+        };
+        trace!(name = question.name, qtype = question.qtype, "DNS query");
+        let (rcode, answer) = match question.qtype {
+            TYPE_A if !question.name.is_empty() => {
+                (RCODE_NO_ERROR, self.allocate(&question.name, false))
+            }
+            TYPE_AAAA if !question.name.is_empty() => {
+                (RCODE_NO_ERROR, self.allocate(&question.name, true))
+            }
+            _ => (RCODE_NOT_IMPLEMENTED, None),
+        };
+        if let Some(ip) = answer {
+            debug!(name = question.name, %ip, "fake IP answered");
+        }
+        let answers: Vec<_> = answer.into_iter().collect();
+        Some(build_response(query, &question, rcode, &answers, FAKE_TTL))
+    }
+}
+
+/// Serve fake-IP DNS on `socket` forever.
+#[instrument(name = "fake_dns", skip_all)]
+pub async fn serve_fake_dns(socket: UdpSocket, pool: Arc<FakeIpPool>) -> io::Result<()> {
+    let mut buf = vec![0u8; 1500];
+    loop {
+        let (len, from) = socket.recv_from(&mut buf).await?;
+        if let Some(response) = pool.answer(&buf[..len]) {
+            if let Err(err) = socket.send_to(&response, from).await {
+                debug!(%from, ?err, "fail to send DNS response");
+            }
+        }
+    }
+}
+
+#[test]
+fn test_fake_ip_pool() {
+    let pool = FakeIpPool::new(Some(("198.18.0.0".parse().unwrap(), 15)), None, 2);
+    let a = pool.allocate("a.example", false).unwrap();
+    let b = pool.allocate("b.example", false).unwrap();
+    assert_eq!("198.18.0.1".parse::<IpAddr>().unwrap(), a);
+    assert_eq!("198.18.0.2".parse::<IpAddr>().unwrap(), b);
+    assert_eq!(Some(a), pool.allocate("a.example", false));
+    assert_eq!(None, pool.allocate("a.example", true));
+    // b is the least recently used one
+    let c = pool.allocate("c.example", false).unwrap();
+    assert_eq!(b, c);
+    assert_eq!(Some("a.example".into()), pool.lookup(a));
+    assert_eq!(Some("c.example".into()), pool.lookup(c));
+    assert_eq!(None, pool.lookup("198.18.0.3".parse().unwrap()));
+    assert_eq!(None, pool.lookup("192.0.2.1".parse().unwrap()));
+    assert!(pool.contains("198.18.0.2".parse().unwrap()));
+    assert!(!pool.contains("198.18.0.3".parse().unwrap()));
+    assert!(!pool.contains("::ffff:192.0.2.1".parse().unwrap()));
+}
+
+#[test]
+fn test_fake_ip_table_mask() {
+    let mut table = FakeIpTable::new(u128::from(Ipv6Addr::LOCALHOST), 120, 128, 1000);
+    assert_eq!(254, table.capacity);
+    assert_eq!(Some(1), table.allocate("example.com"));
+}
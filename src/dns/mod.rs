@@ -0,0 +1,246 @@
+//! Minimal DNS message handling, just enough for fake-IP DNS server and
+//! DNS snooping.
+pub mod fakeip;
+pub mod snoop;
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+pub const TYPE_A: u16 = 1;
+pub const TYPE_AAAA: u16 = 28;
+const CLASS_IN: u16 = 1;
+
+pub const RCODE_NO_ERROR: u8 = 0;
+pub const RCODE_FORMAT_ERROR: u8 = 1;
+pub const RCODE_NOT_IMPLEMENTED: u8 = 4;
+
+const HEADER_LEN: usize = 12;
+/// Max number of compression pointers to follow in one name.
+const MAX_POINTERS: usize = 16;
+
+/// The (first) question of a DNS query.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Question {
+    /// Lower-cased domain name, without the trailing dot.
+    pub name: String,
+    pub qtype: u16,
+    pub qclass: u16,
+    /// Where the question section ends in the message.
+    end: usize,
+}
+
+fn read_u16(msg: &[u8], pos: usize) -> Option<u16> {
+    Some(u16::from_be_bytes([*msg.get(pos)?, *msg.get(pos + 1)?]))
+}
+
+/// Read a (possibly compressed) domain name at `pos`.
+/// Return the name and the position right after it.
+pub fn read_name(msg: &[u8], mut pos: usize) -> Option<(String, usize)> {
+    let mut name = String::new();
+    let mut end = None;
+    let mut pointers = 0;
+    loop {
+        let len = *msg.get(pos)? as usize;
+        match len {
+            0 => break,
+            // Compression pointer
+            _ if len & 0xc0 == 0xc0 => {
+                pointers += 1;
+                if pointers > MAX_POINTERS {
+                    return None;
+                }
+                end.get_or_insert(pos + 2);
+                pos = (read_u16(msg, pos)? & 0x3fff) as usize;
+                continue;
+            }
+            _ if len > 63 => return None,
+            _ => {
+                let label = msg.get(pos + 1..pos + 1 + len)?;
+                if !name.is_empty() {
+                    name.push('.');
+                }
+                for &c in label {
+                    if !c.is_ascii_graphic() || c == b'.' {
+                        return None;
+                    }
+                    name.push(c.to_ascii_lowercase() as char);
+                }
+                pos += 1 + len;
+            }
+        }
+    }
+    if name.len() > 253 {
+        return None;
+    }
+    Some((name, end.unwrap_or(pos + 1)))
+}
+
+/// Parse a standard query with one question.
+pub fn parse_query(msg: &[u8]) -> Option<Question> {
+    if msg.len() < HEADER_LEN {
+        return None;
+    }
+    let flags = read_u16(msg, 2)?;
+    let qdcount = read_u16(msg, 4)?;
+    // Must be a query (QR = 0) with OPCODE = 0 (QUERY)
+    if flags & 0xf800 != 0 || qdcount != 1 {
+        return None;
+    }
+    read_question(msg, HEADER_LEN)
+}
+
+fn read_question(msg: &[u8], pos: usize) -> Option<Question> {
+    let (name, pos) = read_name(msg, pos)?;
+    Some(Question {
+        name,
+        qtype: read_u16(msg, pos)?,
+        qclass: read_u16(msg, pos + 2)?,
+        end: pos + 4,
+    })
+}
+
+/// A/AAAA records in a response, with the name in question.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Answers {
+    /// Name in question, CNAMEs are not followed.
+    pub name: String,
+    /// Addresses and their TTLs (in seconds).
+    pub addrs: Vec<(IpAddr, u32)>,
+}
+
+/// Parse a successful response with one question, collect its A & AAAA
+/// records.
+pub fn parse_response(msg: &[u8]) -> Option<Answers> {
+    if msg.len() < HEADER_LEN {
+        return None;
+    }
+    let flags = read_u16(msg, 2)?;
+    // Must be a response (QR = 1) to QUERY with no error
+    if flags & 0xf80f != 0x8000 || read_u16(msg, 4)? != 1 {
+        return None;
+    }
+    let ancount = read_u16(msg, 6)?;
+    let question = read_question(msg, HEADER_LEN)?;
+    let mut pos = question.end;
+    let mut addrs = Vec::new();
+    for _ in 0..ancount {
+        let (_, next) = read_name(msg, pos)?;
+        let rtype = read_u16(msg, next)?;
+        let rclass = read_u16(msg, next + 2)?;
+        let ttl = u32::from_be_bytes(msg.get(next + 4..next + 8)?.try_into().ok()?);
+        let rdlen = read_u16(msg, next + 8)? as usize;
+        let rdata = msg.get(next + 10..next + 10 + rdlen)?;
+        pos = next + 10 + rdlen;
+        if rclass != CLASS_IN {
+            continue;
+        }
+        let addr: IpAddr = match (rtype, rdata.len()) {
+            (TYPE_A, 4) => Ipv4Addr::from(<[u8; 4]>::try_from(rdata).ok()?).into(),
+            (TYPE_AAAA, 16) => Ipv6Addr::from(<[u8; 16]>::try_from(rdata).ok()?).into(),
+            _ => continue,
+        };
+        addrs.push((addr, ttl));
+    }
+    Some(Answers {
+        name: question.name,
+        addrs,
+    })
+}
+
+/// Build a response to `query` with IP addresses as answers.
+/// Only the question section of the query is echoed back.
+pub fn build_response(
+    query: &[u8],
+    question: &Question,
+    rcode: u8,
+    answers: &[IpAddr],
+    ttl: u32,
+) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(question.end + answers.len() * 28);
+    // ID
+    buf.extend_from_slice(&query[0..2]);
+    // QR = 1, keep OPCODE & RD, RA = 1
+    buf.push(0x80 | (query[2] & 0x79));
+    buf.push(0x80 | (rcode & 0x0f));
+    buf.extend_from_slice(&[0, 1]); // QDCOUNT
+    buf.extend_from_slice(&(answers.len() as u16).to_be_bytes());
+    buf.extend_from_slice(&[0, 0, 0, 0]); // NSCOUNT, ARCOUNT
+    buf.extend_from_slice(&query[HEADER_LEN..question.end]);
+    for answer in answers {
+        // Pointer to the name in question
+        buf.extend_from_slice(&[0xc0, HEADER_LEN as u8]);
+        match answer {
+            IpAddr::V4(ip) => {
+                buf.extend_from_slice(&TYPE_A.to_be_bytes());
+                buf.extend_from_slice(&CLASS_IN.to_be_bytes());
+                buf.extend_from_slice(&ttl.to_be_bytes());
+                buf.extend_from_slice(&4u16.to_be_bytes());
+                buf.extend_from_slice(&ip.octets());
+            }
+            IpAddr::V6(ip) => {
+                buf.extend_from_slice(&TYPE_AAAA.to_be_bytes());
+                buf.extend_from_slice(&CLASS_IN.to_be_bytes());
+                buf.extend_from_slice(&ttl.to_be_bytes());
+                buf.extend_from_slice(&16u16.to_be_bytes());
+                buf.extend_from_slice(&ip.octets());
+            }
+        }
+    }
+    buf
+}
+
+/// Build an error response to a message that can't be parsed as query.
+pub fn build_error(query: &[u8], rcode: u8) -> Option<Vec<u8>> {
+    let header = query.get(0..HEADER_LEN)?;
+    let mut buf = header.to_vec();
+    buf[2] = 0x80 | (header[2] & 0x79);
+    buf[3] = 0x80 | (rcode & 0x0f);
+    // No sections
+    buf[4..].fill(0);
+    Some(buf)
+}
+
+#[cfg(test)]
+const TEST_QUERY: &[u8] = b"\x12\x34\x01\x00\x00\x01\x00\x00\x00\x00\x00\x00\
+    \x07Example\x03com\x00\x00\x01\x00\x01";
+
+#[test]
+fn test_parse_query() {
+    let question = parse_query(TEST_QUERY).unwrap();
+    assert_eq!("example.com", question.name);
+    assert_eq!(TYPE_A, question.qtype);
+    assert_eq!(CLASS_IN, question.qclass);
+    assert_eq!(TEST_QUERY.len(), question.end);
+    // Response is not a query
+    let response = build_response(TEST_QUERY, &question, RCODE_NO_ERROR, &[], 1);
+    assert_eq!(None, parse_query(&response));
+    assert_eq!(None, parse_query(&TEST_QUERY[..20]));
+}
+
+#[test]
+fn test_build_response() {
+    let question = parse_query(TEST_QUERY).unwrap();
+    let ip = "198.18.0.1".parse().unwrap();
+    let response = build_response(TEST_QUERY, &question, RCODE_NO_ERROR, &[ip], 60);
+    assert_eq!(&[0x12, 0x34, 0x81, 0x80, 0, 1, 0, 1], &response[..8]);
+    let answer = &response[TEST_QUERY.len()..];
+    let (name, pos) = read_name(&response, TEST_QUERY.len()).unwrap();
+    assert_eq!("example.com", name);
+    assert_eq!(TEST_QUERY.len() + 2, pos);
+    assert_eq!(
+        &[0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 198, 18, 0, 1],
+        &answer[2..]
+    );
+}
+
+#[test]
+fn test_parse_response() {
+    let question = parse_query(TEST_QUERY).unwrap();
+    let ips = ["192.0.2.1".parse().unwrap(), "2001:db8::1".parse().unwrap()];
+    let response = build_response(TEST_QUERY, &question, RCODE_NO_ERROR, &ips, 60);
+    let answers = parse_response(&response).unwrap();
+    assert_eq!("example.com", answers.name);
+    assert_eq!(vec![(ips[0], 60), (ips[1], 60)], answers.addrs);
+    // Query is not a response
+    assert_eq!(None, parse_response(TEST_QUERY));
+    assert_eq!(None, parse_response(&response[..response.len() - 1]));
+}
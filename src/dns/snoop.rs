@@ -0,0 +1,261 @@
+//! DNS snooping: forward queries to an upstream resolver, and remember
+//! which domain name the answered addresses belong to.
+use flexstr::SharedStr;
+use parking_lot::Mutex;
+use std::{
+    collections::{BTreeSet, HashMap},
+    io,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream, UdpSocket},
+    sync::Semaphore,
+    time::{timeout, Instant},
+};
+use tracing::{debug, instrument, trace};
+
+use super::parse_response;
+use crate::proxy::BindOptions;
+
+/// Clients may keep using an address after its TTL expires, keep it at
+/// least for this long.
+const MIN_TTL: Duration = Duration::from_secs(300);
+const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_UDP_MESSAGE_LEN: usize = 4096;
+/// Queries beyond this are dropped, clients will retry.
+const MAX_UDP_INFLIGHT: usize = 256;
+
+#[derive(Default)]
+struct Entries {
+    /// IP => (domain, expire at)
+    domains: HashMap<IpAddr, (SharedStr, Instant)>,
+    /// Ordered by expiration time, for eviction.
+    expires: BTreeSet<(Instant, IpAddr)>,
+}
+
+/// Addresses seen in DNS responses, with the domain name they were
+/// queried for.
+pub struct DnsCache {
+    capacity: usize,
+    entries: Mutex<Entries>,
+}
+
+impl DnsCache {
+    /// Create a cache holds up to `capacity` addresses.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: Default::default(),
+        }
+    }
+
+    /// Record `ip` as an answer of `domain` for `ttl` seconds.
+    pub fn insert(&self, ip: IpAddr, domain: SharedStr, ttl: u32) {
+        let now = Instant::now();
+        let expire = now + MIN_TTL.max(Duration::from_secs(ttl.into()));
+        let mut entries = self.entries.lock();
+        if let Some((_, old)) = entries.domains.remove(&ip) {
+            entries.expires.remove(&(old, ip));
+        }
+        // Evict the one closest to expiration, expired ones come first
+        while entries.domains.len() >= self.capacity.max(1) {
+            match entries.expires.pop_first() {
+                Some((_, ip)) => entries.domains.remove(&ip),
+                None => break,
+            };
+        }
+        entries.domains.insert(ip, (domain, expire));
+        entries.expires.insert((expire, ip));
+    }
+
+    /// Find the domain name that `ip` was answered for.
+    pub fn lookup(&self, ip: IpAddr) -> Option<SharedStr> {
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+            IpAddr::V4(_) => ip,
+        };
+        let entries = self.entries.lock();
+        let (domain, expire) = entries.domains.get(&ip)?;
+        (*expire > Instant::now()).then(|| domain.clone())
+    }
+
+    /// Record addresses in a DNS response.
+    fn snoop(&self, response: &[u8]) {
+        if let Some(answers) = parse_response(response) {
+            let domain: SharedStr = answers.name.into();
+            for (ip, ttl) in answers.addrs {
+                trace!(%ip, %domain, ttl, "DNS answer snooped");
+                self.insert(ip, domain.clone(), ttl);
+            }
+        }
+    }
+}
+
+/// Forward DNS queries on `socket` to `upstream` forever, from sockets
+/// bound with `bind`.
+#[instrument(name = "dns_snoop_udp", skip_all)]
+pub async fn serve_dns_udp(
+    socket: UdpSocket,
+    upstream: SocketAddr,
+    cache: Arc<DnsCache>,
+    bind: BindOptions,
+) -> io::Result<()> {
+    let bind = Arc::new(bind);
+    let socket = Arc::new(socket);
+    let inflight = Arc::new(Semaphore::new(MAX_UDP_INFLIGHT));
+    let mut buf = vec![0u8; MAX_UDP_MESSAGE_LEN];
+    loop {
+        let (len, from) = socket.recv_from(&mut buf).await?;
+        if len < 2 {
+            continue;
+        }
+        let permit = match inflight.clone().try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => {
+                debug!(%from, "too many DNS queries in flight, drop query");
+                continue;
+            }
+        };
+        let query = buf[..len].to_vec();
+        let socket = socket.clone();
+        let cache = cache.clone();
+        let bind = bind.clone();
+        tokio::spawn(async move {
+            let _permit = permit;
+            match forward_udp(&query, upstream, &bind).await {
+                Ok(response) => {
+                    cache.snoop(&response);
+                    if let Err(err) = socket.send_to(&response, from).await {
+                        debug!(%from, ?err, "fail to send DNS response");
+                    }
+                }
+                Err(err) => debug!(?err, "fail to forward DNS query"),
+            }
+        });
+    }
+}
+
+async fn forward_udp(
+    query: &[u8],
+    upstream: SocketAddr,
+    bind: &BindOptions,
+) -> io::Result<Vec<u8>> {
+    let socket = bind.bind_udp(&upstream).await?;
+    socket.connect(upstream).await?;
+    socket.send(query).await?;
+    let mut buf = vec![0u8; MAX_UDP_MESSAGE_LEN];
+    // Ignore responses not for this query (by its ID)
+    let len = timeout(UPSTREAM_TIMEOUT, async {
+        loop {
+            let len = socket.recv(&mut buf).await?;
+            if len >= 2 && buf[..2] == query[..2] {
+                return io::Result::Ok(len);
+            }
+            trace!("DNS response ID mismatched, ignored");
+        }
+    })
+    .await??;
+    buf.truncate(len);
+    Ok(buf)
+}
+
+/// Forward DNS-over-TCP connections on `listener` to `upstream` forever,
+/// from sockets bound with `bind`.
+#[instrument(name = "dns_snoop_tcp", skip_all)]
+pub async fn serve_dns_tcp(
+    listener: TcpListener,
+    upstream: SocketAddr,
+    cache: Arc<DnsCache>,
+    bind: BindOptions,
+) -> io::Result<()> {
+    let bind = Arc::new(bind);
+    loop {
+        let (client, from) = listener.accept().await?;
+        let cache = cache.clone();
+        let bind = bind.clone();
+        tokio::spawn(async move {
+            if let Err(err) = forward_tcp(client, upstream, &cache, &bind).await {
+                debug!(%from, ?err, "error on DNS over TCP");
+            }
+        });
+    }
+}
+
+async fn read_tcp_message(stream: &mut TcpStream) -> io::Result<Option<Vec<u8>>> {
+    let len = match stream.read_u16().await {
+        Ok(len) => len as usize,
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    let mut buf = vec![0u8; len];
+    stream.read_exact(&mut buf).await?;
+    Ok(Some(buf))
+}
+
+async fn write_tcp_message(stream: &mut TcpStream, msg: &[u8]) -> io::Result<()> {
+    let mut buf = Vec::with_capacity(msg.len() + 2);
+    buf.extend_from_slice(&(msg.len() as u16).to_be_bytes());
+    buf.extend_from_slice(msg);
+    stream.write_all(&buf).await
+}
+
+/// Relay messages one by one, so that responses can be snooped.
+async fn forward_tcp(
+    mut client: TcpStream,
+    upstream: SocketAddr,
+    cache: &DnsCache,
+    bind: &BindOptions,
+) -> io::Result<()> {
+    let mut server = bind.connect(upstream, UPSTREAM_TIMEOUT).await?;
+    while let Some(query) = read_tcp_message(&mut client).await? {
+        write_tcp_message(&mut server, &query).await?;
+        let response = timeout(UPSTREAM_TIMEOUT, read_tcp_message(&mut server))
+            .await??
+            .ok_or(io::ErrorKind::UnexpectedEof)?;
+        cache.snoop(&response);
+        write_tcp_message(&mut client, &response).await?;
+    }
+    Ok(())
+}
+
+#[test]
+fn test_dns_cache() {
+    let cache = DnsCache::new(2);
+    let ips: Vec<IpAddr> = vec![
+        "192.0.2.1".parse().unwrap(),
+        "192.0.2.2".parse().unwrap(),
+        "192.0.2.3".parse().unwrap(),
+    ];
+    cache.insert(ips[0], "a.example".into(), 3600);
+    cache.insert(ips[1], "b.example".into(), 60);
+    assert_eq!(Some("a.example".into()), cache.lookup(ips[0]));
+    // b expires first, so it's evicted
+    cache.insert(ips[2], "c.example".into(), 60);
+    assert_eq!(None, cache.lookup(ips[1]));
+    assert_eq!(Some("a.example".into()), cache.lookup(ips[0]));
+    assert_eq!(Some("c.example".into()), cache.lookup(ips[2]));
+    // Updated by later answer
+    cache.insert(ips[0], "d.example".into(), 60);
+    assert_eq!(Some("d.example".into()), cache.lookup(ips[0]));
+}
+
+#[tokio::test]
+async fn test_forward_udp_check_id() {
+    let upstream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let upstream_addr = upstream.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut buf = [0u8; 512];
+        let (len, from) = upstream.recv_from(&mut buf).await.unwrap();
+        assert_eq!(b"\x12\x34query", &buf[..len]);
+        upstream.send_to(b"\x43\x21wrong", from).await.unwrap();
+        upstream.send_to(b"\x12\x34right", from).await.unwrap();
+    });
+    let bind = BindOptions::default();
+    let response = forward_udp(b"\x12\x34query", upstream_addr, &bind)
+        .await
+        .unwrap();
+    assert_eq!(b"\x12\x34right", &response[..]);
+}
@@ -1,11 +1,15 @@
+use flexstr::SharedStr;
 use futures_core::{ready, Stream};
 use std::{
+    fs,
     io::Result,
+    path::Path,
     task::{Context, Poll},
 };
 use tokio::net::{TcpListener, TcpStream};
 #[cfg(unix)]
 use tokio::net::{UnixListener, UnixStream};
+use tracing::warn;
 
 macro_rules! impl_stream {
     ($name:ident : $listener:ty => $stream:ty) => {
@@ -29,3 +33,20 @@ impl_stream!(TcpListenerStream: TcpListener => TcpStream);
 
 #[cfg(unix)]
 impl_stream!(UnixListenerStream: UnixListener => UnixStream);
+
+/// File on this path will be removed on `drop()`.
+pub struct AutoRemoveFile(pub SharedStr);
+
+impl Drop for AutoRemoveFile {
+    fn drop(&mut self) {
+        if let Err(err) = fs::remove_file(self.0.as_str()) {
+            warn!("fail to remove {}: {}", self.0, err);
+        }
+    }
+}
+
+impl AsRef<Path> for AutoRemoveFile {
+    fn as_ref(&self) -> &Path {
+        self.0.as_str().as_ref()
+    }
+}
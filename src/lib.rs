@@ -1,9 +1,12 @@
 pub mod client;
+pub mod dns;
 pub mod futures_stream;
 #[cfg(target_os = "linux")]
 pub mod linux;
 pub mod monitor;
 pub mod policy;
 pub mod proxy;
+#[cfg(test)]
+mod test_util;
 #[cfg(feature = "web_console")]
 pub mod web;
@@ -1,3 +1,4 @@
 #[cfg(feature = "systemd")]
 pub mod systemd;
 pub mod tcp;
+pub mod tproxy;
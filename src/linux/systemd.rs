@@ -1,9 +1,30 @@
 use libc::{dev_t as Dev, ino_t as Inode};
-use nix::sys::stat::fstat;
+use nix::sys::{
+    socket::{
+        getsockname, getsockopt, sockopt, AddressFamily, SockType, SockaddrLike, SockaddrStorage,
+    },
+    stat::fstat,
+};
 use sd_notify::{notify, NotifyState};
-use std::{borrow::Cow, env, io, os::unix::prelude::AsRawFd, process, time::Duration};
-use tokio::time::sleep;
-use tracing::{info, instrument, trace, warn};
+use std::{
+    borrow::Cow,
+    env, io, net,
+    os::unix::{
+        net as unix_net,
+        prelude::{AsRawFd, FromRawFd, RawFd},
+    },
+    process,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+use tokio::{
+    net::{TcpListener, UnixListener},
+    time::sleep,
+};
+use tracing::{debug, info, instrument, trace, warn};
+
+/// The first file descriptor passed by socket activation.
+const LISTEN_FDS_START: RawFd = 3;
 
 fn notify_enabled() -> bool {
     env::var_os("NOTIFY_SOCKET").is_some()
@@ -73,3 +94,67 @@ pub fn is_stderr_connected_to_journal() -> bool {
     }
     false
 }
+
+/// Listening socket passed by systemd.
+#[derive(Debug)]
+pub enum ActivatedListener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+/// Take listening sockets passed by systemd socket activation (`LISTEN_FDS`),
+/// along with their names (`FileDescriptorName=` in the .socket unit).
+/// Only the first call takes them. Environment variables are left as-is
+/// (removing them is unsound once threads are running); children ignore them
+/// as `LISTEN_PID` won't match.
+pub fn take_listen_fds() -> io::Result<Vec<(String, ActivatedListener)>> {
+    static TAKEN: AtomicBool = AtomicBool::new(false);
+    if TAKEN.swap(true, Ordering::SeqCst) {
+        return Ok(vec![]);
+    }
+    let pid = env::var("LISTEN_PID").ok().and_then(|pid| pid.parse().ok());
+    let n_fds: Option<RawFd> = env::var("LISTEN_FDS").ok().and_then(|n| n.parse().ok());
+    let names = env::var("LISTEN_FDNAMES").unwrap_or_default();
+
+    let n_fds = match (pid, n_fds) {
+        (Some(pid), Some(n)) if pid == process::id() => n,
+        (None, None) => return Ok(vec![]),
+        _ => {
+            info!("LISTEN_PID is not ours, ignore LISTEN_FDS");
+            return Ok(vec![]);
+        }
+    };
+    let mut names = names.split(':');
+    let mut listeners = Vec::with_capacity(n_fds as usize);
+    for fd in LISTEN_FDS_START..LISTEN_FDS_START + n_fds {
+        let name = names.next().unwrap_or("unknown").to_string();
+        debug!(fd, name, "socket passed by systemd");
+        listeners.push((name, into_listener(fd)?));
+    }
+    Ok(listeners)
+}
+
+fn into_listener(fd: RawFd) -> io::Result<ActivatedListener> {
+    if getsockopt(fd, sockopt::SockType)? != SockType::Stream {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "only stream sockets can be passed by systemd",
+        ));
+    }
+    match getsockname::<SockaddrStorage>(fd)?.family() {
+        Some(AddressFamily::Inet | AddressFamily::Inet6) => {
+            let listener = unsafe { net::TcpListener::from_raw_fd(fd) };
+            listener.set_nonblocking(true)?;
+            Ok(ActivatedListener::Tcp(TcpListener::from_std(listener)?))
+        }
+        Some(AddressFamily::Unix) => {
+            let listener = unsafe { unix_net::UnixListener::from_raw_fd(fd) };
+            listener.set_nonblocking(true)?;
+            Ok(ActivatedListener::Unix(UnixListener::from_std(listener)?))
+        }
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "unsupported socket family passed by systemd",
+        )),
+    }
+}
@@ -1,6 +1,6 @@
 use nix::sys::socket::{
     getsockopt, setsockopt,
-    sockopt::{Ip6tOriginalDst, OriginalDst, TcpCongestion},
+    sockopt::{BindToDevice, Ip6tOriginalDst, Mark, OriginalDst, TcpCongestion},
 };
 use std::{
     ffi::OsStr,
@@ -18,6 +18,14 @@ pub trait TcpListenerExt {
     fn set_congestion<S: AsRef<OsStr>>(&self, alg: S) -> io::Result<()>;
 }
 
+/// Options of outbound sockets, both TCP and UDP.
+pub trait SocketBindExt {
+    /// Bind to the interface (SO_BINDTODEVICE), need CAP_NET_RAW.
+    fn set_bind_device<S: AsRef<OsStr>>(&self, interface: S) -> io::Result<()>;
+    /// Set firewall mark (SO_MARK), need CAP_NET_ADMIN.
+    fn set_mark(&self, mark: u32) -> io::Result<()>;
+}
+
 impl TcpStreamExt for TcpStream {
     fn get_original_dest(&self) -> io::Result<Option<SocketAddr>> {
         match get_original_dest_v4(self) {
@@ -40,6 +48,19 @@ impl TcpListenerExt for TcpListener {
     }
 }
 
+impl<S: AsRawFd> SocketBindExt for S {
+    fn set_bind_device<S: AsRef<OsStr>>(&self, interface: S) -> io::Result<()> {
+        let val = interface.as_ref().into();
+        setsockopt(self.as_raw_fd(), BindToDevice, &val)?;
+        Ok(())
+    }
+
+    fn set_mark(&self, mark: u32) -> io::Result<()> {
+        setsockopt(self.as_raw_fd(), Mark, &mark)?;
+        Ok(())
+    }
+}
+
 fn get_original_dest_v4<F>(fd: &F) -> io::Result<SocketAddrV4>
 where
     F: AsRawFd,
@@ -0,0 +1,150 @@
+//! Sockets for transparent proxying with TPROXY (`iptables -j TPROXY` or
+//! `nft tproxy`), which keeps the original destination as the local address.
+use nix::{
+    cmsg_space,
+    sys::socket::{
+        bind, recvmsg, setsockopt, socket, sockopt::IpTransparent, AddressFamily,
+        ControlMessageOwned, MsgFlags, SockFlag, SockType, SockaddrStorage,
+    },
+};
+use std::{
+    io::{self, ErrorKind, IoSliceMut},
+    mem,
+    net::{SocketAddr, SocketAddrV4, SocketAddrV6},
+    os::unix::io::{AsRawFd, FromRawFd, RawFd},
+};
+use tokio::{
+    io::Interest,
+    net::{TcpListener, TcpSocket, UdpSocket},
+};
+
+fn set_bool_opt(fd: RawFd, level: libc::c_int, name: libc::c_int) -> io::Result<()> {
+    let val: libc::c_int = 1;
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            &val as *const _ as *const libc::c_void,
+            mem::size_of_val(&val) as libc::socklen_t,
+        )
+    };
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+fn set_transparent(fd: RawFd, is_ipv6: bool) -> io::Result<()> {
+    setsockopt(fd, IpTransparent, &true)?;
+    if is_ipv6 {
+        set_bool_opt(fd, libc::SOL_IPV6, libc::IPV6_TRANSPARENT)?;
+    }
+    Ok(())
+}
+
+/// Bind TCP listener with `IP_TRANSPARENT`. Local address of accepted
+/// streams are their original destinations.
+pub fn bind_tcp_listener(addr: SocketAddr) -> io::Result<TcpListener> {
+    let socket = match addr {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    set_transparent(socket.as_raw_fd(), addr.is_ipv6())?;
+    socket.set_reuseaddr(true)?;
+    socket.bind(addr)?;
+    socket.listen(1024)
+}
+
+fn new_udp_socket(addr: SocketAddr) -> io::Result<RawFd> {
+    let family = match addr {
+        SocketAddr::V4(_) => AddressFamily::Inet,
+        SocketAddr::V6(_) => AddressFamily::Inet6,
+    };
+    let flags = SockFlag::SOCK_NONBLOCK | SockFlag::SOCK_CLOEXEC;
+    Ok(socket(family, SockType::Datagram, flags, None)?)
+}
+
+fn into_tokio_udp(fd: RawFd) -> io::Result<UdpSocket> {
+    let socket = unsafe { std::net::UdpSocket::from_raw_fd(fd) };
+    UdpSocket::from_std(socket)
+}
+
+/// Bind UDP socket for receiving TPROXY-ed datagrams.
+/// Use `recv_with_orig_dst()` to read on it.
+pub fn bind_udp(addr: SocketAddr) -> io::Result<UdpSocket> {
+    let fd = new_udp_socket(addr)?;
+    // Take the ownership first, so the fd get closed on error
+    let socket = into_tokio_udp(fd)?;
+    set_transparent(fd, addr.is_ipv6())?;
+    set_bool_opt(fd, libc::SOL_IP, libc::IP_RECVORIGDSTADDR)?;
+    if addr.is_ipv6() {
+        set_bool_opt(fd, libc::SOL_IPV6, libc::IPV6_RECVORIGDSTADDR)?;
+    }
+    bind(fd, &SockaddrStorage::from(addr))?;
+    Ok(socket)
+}
+
+/// Bind UDP socket on a non-local address, used for replying datagrams to
+/// the client as the original destination.
+pub fn bind_udp_nonlocal(addr: SocketAddr) -> io::Result<UdpSocket> {
+    let fd = new_udp_socket(addr)?;
+    let socket = into_tokio_udp(fd)?;
+    set_transparent(fd, addr.is_ipv6())?;
+    set_bool_opt(fd, libc::SOL_SOCKET, libc::SO_REUSEADDR)?;
+    bind(fd, &SockaddrStorage::from(addr))?;
+    Ok(socket)
+}
+
+/// Receive a datagram, return its length, source and original destination.
+pub async fn recv_with_orig_dst(
+    socket: &UdpSocket,
+    buf: &mut [u8],
+) -> io::Result<(usize, SocketAddr, SocketAddr)> {
+    loop {
+        socket.readable().await?;
+        match socket.try_io(Interest::READABLE, || {
+            recvmsg_orig_dst(socket.as_raw_fd(), buf)
+        }) {
+            Err(err) if err.kind() == ErrorKind::WouldBlock => continue,
+            result => return result,
+        }
+    }
+}
+
+fn recvmsg_orig_dst(fd: RawFd, buf: &mut [u8]) -> io::Result<(usize, SocketAddr, SocketAddr)> {
+    let mut cmsg = cmsg_space!(libc::sockaddr_in6);
+    let mut iov = [IoSliceMut::new(buf)];
+    let msg = recvmsg::<SockaddrStorage>(fd, &mut iov, Some(&mut cmsg), MsgFlags::empty())?;
+
+    let src = msg.address.and_then(|addr| {
+        if let Some(addr) = addr.as_sockaddr_in() {
+            Some(SocketAddr::V4((*addr).into()))
+        } else {
+            addr.as_sockaddr_in6()
+                .map(|addr| SocketAddr::V6((*addr).into()))
+        }
+    });
+    let dst = msg.cmsgs().find_map(|cmsg| match cmsg {
+        ControlMessageOwned::Ipv4OrigDstAddr(addr) => Some(SocketAddr::V4(SocketAddrV4::new(
+            u32::from_be(addr.sin_addr.s_addr).into(),
+            u16::from_be(addr.sin_port),
+        ))),
+        ControlMessageOwned::Ipv6OrigDstAddr(addr) => Some(SocketAddr::V6(SocketAddrV6::new(
+            addr.sin6_addr.s6_addr.into(),
+            u16::from_be(addr.sin6_port),
+            addr.sin6_flowinfo,
+            addr.sin6_scope_id,
+        ))),
+        _ => None,
+    });
+    match (src, dst) {
+        (Some(src), Some(dst)) => Ok((msg.bytes, src, dst)),
+        (None, _) => Err(io::Error::new(ErrorKind::Other, "missing source address")),
+        (_, None) => Err(io::Error::new(
+            ErrorKind::Other,
+            "missing original destination, not TPROXY-ed?",
+        )),
+    }
+}
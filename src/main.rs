@@ -68,7 +68,7 @@ async fn main() {
         let mut signals = signal(SignalKind::hangup()).expect("cannot catch signal");
         tokio::spawn(async move {
             while signals.recv().await.is_some() {
-                reload_daemon(&moproxy);
+                reload_daemon(&moproxy).await;
             }
         });
     }
@@ -83,12 +83,18 @@ async fn main() {
                 listen_port,
                 dst_ip,
                 dst_domain,
+                user,
+                protocol,
+                alpn,
             } => {
                 let policy = moproxy.policy.read();
                 let features = RequestFeatures {
                     listen_port: *listen_port,
                     dst_ip: *dst_ip,
                     dst_domain: dst_domain.as_deref(),
+                    username: user.as_deref(),
+                    protocol: *protocol,
+                    alpn: alpn.iter().map(String::as_str).collect(),
                 };
                 let action = policy.matches(&features);
                 println!("Policy: {action}");
@@ -135,7 +141,7 @@ async fn main() {
 }
 
 #[instrument(skip_all)]
-fn reload_daemon(moproxy: &MoProxy) {
+async fn reload_daemon(moproxy: &MoProxy) {
     #[cfg(all(feature = "systemd", target_os = "linux"))]
     systemd::notify_realoding();
 
@@ -154,7 +160,7 @@ fn reload_daemon(moproxy: &MoProxy) {
 
     // actual reload
     debug!("SIGHUP received, reload server list.");
-    if let Err(err) = moproxy.reload() {
+    if let Err(err) = moproxy.reload().await {
         error!("fail to reload servers: {}", err);
     }
 
@@ -1,12 +1,12 @@
 use futures_util::future::join_all;
-use std::{self, io, net::Shutdown, time::Duration};
+use std::{self, io, time::Duration};
 #[cfg(all(feature = "systemd", target_os = "linux"))]
 use std::{
     fmt,
     sync::atomic::{AtomicUsize, Ordering},
 };
 use tokio::{
-    io::AsyncReadExt,
+    io::{AsyncReadExt, AsyncWriteExt},
     time::{timeout, Instant},
 };
 use tracing::{debug, instrument, warn};
@@ -132,9 +132,9 @@ async fn alive_test(server: &ProxyServer) -> io::Result<Duration> {
     let mut buf = [0u8; 12];
     let test_dns = server.test_dns().into();
     let result = timeout(server.max_wait(), async {
-        let mut stream = server.connect(&test_dns, Some(request)).await?;
+        let mut stream = server.connect(&test_dns, Some(request), None).await?;
         stream.read_exact(&mut buf).await?;
-        stream.into_std()?.shutdown(Shutdown::Both)
+        stream.shutdown().await
     })
     .await;
 
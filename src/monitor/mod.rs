@@ -3,6 +3,8 @@ mod graphite;
 use rlua::prelude::*;
 mod alive_test;
 mod traffic;
+use flexstr::SharedStr;
+use futures_util::future::join_all;
 use parking_lot::Mutex;
 use rand::{self, Rng};
 use std::{
@@ -25,7 +27,7 @@ use self::{
 };
 #[cfg(all(feature = "systemd", target_os = "linux"))]
 use crate::linux::systemd;
-use crate::proxy::ProxyServer;
+use crate::proxy::{AtomicTraffic, ProxyServer, Traffic};
 
 static THROUGHPUT_INTERVAL_SECS: u64 = 1;
 
@@ -35,6 +37,7 @@ pub type ServerList = Vec<Arc<ProxyServer>>;
 pub struct Monitor {
     servers: Arc<Mutex<ServerList>>,
     meters: Arc<Mutex<HashMap<Arc<ProxyServer>, Meter>>>,
+    users: Arc<Mutex<HashMap<SharedStr, Arc<AtomicTraffic>>>>,
     graphite: Option<SocketAddr>,
     #[cfg(feature = "score_script")]
     lua: Option<Arc<Mutex<Lua>>>,
@@ -62,6 +65,7 @@ impl Monitor {
         Monitor {
             servers: Arc::new(Mutex::new(servers)),
             meters: Arc::new(Mutex::new(meters)),
+            users: Default::default(),
             graphite,
             #[cfg(feature = "score_script")]
             lua: None,
@@ -118,6 +122,14 @@ impl Monitor {
         // Add brand new server objects
         new_servers.extend(newset.difference(&oldset).cloned());
 
+        // Chain to the server objects in use, rather than the new ones
+        // just dropped.
+        for server in new_servers.iter() {
+            if let Some(via) = server.via() {
+                server.set_via(new_servers.iter().find(|s| **s == *via).cloned());
+            }
+        }
+
         // Create new meters
         let mut meters = self.meters.lock();
         meters.clear();
@@ -160,6 +172,24 @@ impl Monitor {
         }
     }
 
+    /// Re-resolve servers given as host names periodically.
+    /// Returned Future won't return unless error on timer.
+    #[instrument(skip_all)]
+    pub async fn monitor_resolve(self, secs: u64) {
+        let interval = Duration::from_secs(secs);
+        let mut interval = interval_at(Instant::now() + interval, interval);
+        loop {
+            interval.tick().await;
+            self.resolve_servers().await;
+        }
+    }
+
+    /// Resolve servers given as host names.
+    pub async fn resolve_servers(&self) {
+        let servers = self.servers();
+        join_all(servers.iter().map(|server| server.resolve())).await;
+    }
+
     /// Start monitoring throughput.
     /// Returned Future won't return unless error on timer.
     pub async fn monitor_throughput(self) {
@@ -173,6 +203,28 @@ impl Monitor {
         }
     }
 
+    /// Return the traffic counter of given (downstream) user.
+    /// Counters are kept across reloading.
+    pub fn user_traffic(&self, username: &SharedStr) -> Arc<AtomicTraffic> {
+        self.users
+            .lock()
+            .entry(username.clone())
+            .or_default()
+            .clone()
+    }
+
+    /// Return current traffic of all users seen so far.
+    pub fn users_traffic(&self) -> Vec<(SharedStr, Traffic)> {
+        let mut users: Vec<_> = self
+            .users
+            .lock()
+            .iter()
+            .map(|(name, traffic)| (name.clone(), traffic.read()))
+            .collect();
+        users.sort_by(|a, b| a.0.cmp(&b.0));
+        users
+    }
+
     /// Return average throughputs of all servers in the recent monitor
     /// period. Should start `monitor_throughput()` task before call this.
     pub fn throughputs(&self) -> HashMap<Arc<ProxyServer>, Throughput> {
@@ -215,6 +267,7 @@ async fn send_metrics(monitor: &Monitor, graphite: &mut Graphite) -> io::Result<
                 Some(r("conns.total", status.conn_total as u64)),
                 Some(r("conns.alive", status.conn_alive as u64)),
                 Some(r("conns.error", status.conn_error as u64)),
+                Some(r("conns.bad_response", status.conn_bad_response as u64)),
             ]
         })
         .flatten()
@@ -9,6 +9,7 @@ use std::{
     io::{self, BufRead, BufReader},
     net::{IpAddr, Ipv4Addr, Ipv6Addr},
     path::Path,
+    str::FromStr,
 };
 
 use flexstr::{SharedStr, ToSharedStr};
@@ -87,7 +88,10 @@ impl Action {
 struct RuleSet<K: Eq + Hash>(HashMap<K, Action>);
 
 type ListenPortRuleSet = RuleSet<u16>;
+type UserRuleSet = RuleSet<SharedStr>;
 type DstDomainRuleSet = RuleSet<SharedStr>;
+type ProtocolRuleSet = RuleSet<AppProtocol>;
+type AlpnRuleSet = RuleSet<SharedStr>;
 
 impl<K: Eq + Hash> RuleSet<K> {
     fn add(&mut self, key: K, action: Action) {
@@ -148,20 +152,75 @@ impl<A: Address> IpRuleSet<A> {
     }
 }
 
+/// Application layer protocol, told by sniffing the first bytes sent by
+/// client.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AppProtocol {
+    Tls,
+    Http,
+    Ssh,
+    BitTorrent,
+    #[default]
+    Unknown,
+}
+
+impl AppProtocol {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Tls => "tls",
+            Self::Http => "http",
+            Self::Ssh => "ssh",
+            Self::BitTorrent => "bittorrent",
+            Self::Unknown => "unknown",
+        }
+    }
+}
+
+impl Display for AppProtocol {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for AppProtocol {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        [
+            Self::Tls,
+            Self::Http,
+            Self::Ssh,
+            Self::BitTorrent,
+            Self::Unknown,
+        ]
+        .into_iter()
+        .find(|p| p.as_str().eq_ignore_ascii_case(s))
+        .ok_or_else(|| format!("unknown protocol `{}`", s))
+    }
+}
+
 #[derive(Debug, Default, Clone)]
 pub struct RequestFeatures<S: AsRef<str>> {
     pub listen_port: Option<u16>,
     pub dst_ip: Option<IpAddr>,
     pub dst_domain: Option<S>,
+    pub username: Option<S>,
+    /// `None` if not sniffed.
+    pub protocol: Option<AppProtocol>,
+    /// Protocols offered by TLS client via ALPN.
+    pub alpn: Vec<S>,
 }
 
 #[derive(Default)]
 pub struct Policy {
     default_action: Action,
     listen_port_ruleset: ListenPortRuleSet,
+    user_ruleset: UserRuleSet,
     dst_ipv4_ruleset: Ipv4RuleSet,
     dst_ipv6_ruleset: Ipv6RuleSet,
     dst_domain_ruleset: DstDomainRuleSet,
+    protocol_ruleset: ProtocolRuleSet,
+    alpn_ruleset: AlpnRuleSet,
 }
 
 impl Policy {
@@ -192,6 +251,9 @@ impl Policy {
             Filter::ListenPort(port) => {
                 self.listen_port_ruleset.add(port, action);
             }
+            Filter::User(name) => {
+                self.user_ruleset.add(name, action);
+            }
             Filter::DstSni(parts) => {
                 self.dst_domain_ruleset.add(parts.to_shared_str(), action);
             }
@@ -201,6 +263,12 @@ impl Policy {
             Filter::DstIp((IpAddr::V6(ip), len)) => {
                 self.dst_ipv6_ruleset.add((ip, len), action);
             }
+            Filter::Protocol(protocol) => {
+                self.protocol_ruleset.add(protocol, action);
+            }
+            Filter::Alpn(name) => {
+                self.alpn_ruleset.add(name, action);
+            }
         }
     }
 
@@ -208,12 +276,27 @@ impl Policy {
         self.listen_port_ruleset
             .0
             .values()
+            .chain(self.user_ruleset.0.values())
             .chain(self.dst_domain_ruleset.0.values())
+            .chain(self.protocol_ruleset.0.values())
+            .chain(self.alpn_ruleset.0.values())
             .chain(self.dst_ipv4_ruleset.actions())
             .chain(self.dst_ipv6_ruleset.actions())
             .fold(0, |acc, v| acc + v.len())
     }
 
+    /// Whether any rule depends on destination domain name, which may need
+    /// to be sniffed from the connection.
+    pub fn has_dst_domain_rules(&self) -> bool {
+        !self.dst_domain_ruleset.0.is_empty()
+    }
+
+    /// Whether any rule depends on protocol or ALPN, which can only be
+    /// sniffed from the connection.
+    pub fn has_sniff_rules(&self) -> bool {
+        !self.protocol_ruleset.0.is_empty() || !self.alpn_ruleset.0.is_empty()
+    }
+
     pub fn matches<S: AsRef<str>>(&self, features: &RequestFeatures<S>) -> Action {
         let mut action: Action = self.default_action.clone();
         if let Some(port) = features.listen_port {
@@ -221,6 +304,11 @@ impl Policy {
                 .get(&port)
                 .for_each(|a| action.extend(a.clone()))
         }
+        if let Some(name) = &features.username {
+            self.user_ruleset
+                .get(&name.as_ref().into())
+                .for_each(|a| action.extend(a.clone()))
+        }
 
         // Canonicalize IP address
         // Waiting for stablizion of IpAddr::to_canonical()
@@ -248,6 +336,17 @@ impl Policy {
                 .get_recursive(name.as_ref())
                 .for_each(|a| action.extend(a.clone()));
         }
+
+        if let Some(protocol) = &features.protocol {
+            self.protocol_ruleset
+                .get(protocol)
+                .for_each(|a| action.extend(a.clone()))
+        }
+        for name in &features.alpn {
+            self.alpn_ruleset
+                .get(&name.as_ref().into())
+                .for_each(|a| action.extend(a.clone()))
+        }
         action
     }
 }
@@ -309,6 +408,29 @@ fn test_policy_listen_port() {
     assert!(!p2.all_meet_by(&c));
 }
 
+#[test]
+fn test_policy_user() {
+    let rules = "
+        default require def
+        user alice require us
+        user bob direct
+    ";
+    let policy = Policy::load(rules.as_bytes()).unwrap();
+    assert_eq!(2, policy.rule_count());
+    let mut features: RequestFeatures<&'static str> = Default::default();
+    let action = policy.matches(&features).action;
+    assert!(matches!(action, ActionType::Require(a) if a.len() == 1));
+    features.username = Some("alice");
+    let action = policy.matches(&features).action;
+    assert!(matches!(action, ActionType::Require(a) if a.len() == 2));
+    features.username = Some("bob");
+    let action = policy.matches(&features).action;
+    assert!(matches!(action, ActionType::Direct));
+    features.username = Some("Alice");
+    let action = policy.matches(&features).action;
+    assert!(matches!(action, ActionType::Require(a) if a.len() == 1));
+}
+
 #[test]
 fn test_policy_dst_ip() {
     use std::str::FromStr;
@@ -348,6 +470,34 @@ fn test_policy_dst_ip() {
     assert!(matches!(action, ActionType::Require(a) if a.len() == 1));
 }
 
+#[test]
+fn test_policy_protocol_alpn() {
+    let rules = "
+        protocol ssh direct
+        protocol tls require t
+        alpn h2 require fast
+        alpn http/1.1 require slow
+    ";
+    let policy = Policy::load(rules.as_bytes()).unwrap();
+    assert_eq!(4, policy.rule_count());
+    assert!(policy.has_sniff_rules());
+    assert!(!policy.has_dst_domain_rules());
+
+    let mut features: RequestFeatures<&str> = Default::default();
+    let action = policy.matches(&features).action;
+    assert!(matches!(action, ActionType::Require(a) if a.is_empty()));
+    features.protocol = Some(AppProtocol::Ssh);
+    let action = policy.matches(&features).action;
+    assert!(matches!(action, ActionType::Direct));
+    features.protocol = Some(AppProtocol::Tls);
+    features.alpn = vec!["h2"];
+    let action = policy.matches(&features).action;
+    assert!(matches!(action, ActionType::Require(a) if a.len() == 2));
+    features.alpn = vec!["h2", "http/1.1"];
+    let action = policy.matches(&features).action;
+    assert!(matches!(action, ActionType::Require(a) if a.len() == 3));
+}
+
 #[test]
 fn test_policy_get_domain_caps_requirements() {
     let policy = Policy::load(
@@ -359,6 +509,8 @@ fn test_policy_get_domain_caps_requirements() {
         .as_bytes(),
     )
     .unwrap();
+    assert!(policy.has_dst_domain_rules());
+    assert!(!policy.has_sniff_rules());
     let set = policy.dst_domain_ruleset;
     assert_eq!(3, set.get_recursive("test.example.com").count());
     assert_eq!(3, set.get_recursive("example.com").count());
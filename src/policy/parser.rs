@@ -9,20 +9,23 @@ use nom::{
     branch::alt,
     bytes::complete::{tag, tag_no_case, take_till1},
     character::complete::{char, hex_digit1, not_line_ending, space0, space1, u16, u8},
-    combinator::{eof, fail, opt, recognize, verify},
+    combinator::{eof, fail, map_opt, opt, recognize, verify},
     multi::{many0_count, many1, many_m_n, separated_list0, separated_list1},
     sequence::tuple,
     IResult, Parser,
 };
 
-use super::{capabilities::CapSet, Action, ActionType};
+use super::{capabilities::CapSet, Action, ActionType, AppProtocol};
 
 #[derive(Debug, PartialEq, Eq)]
 pub enum Filter {
     Default,
     ListenPort(u16),
+    User(SharedStr),
     DstSni(SharedStr),
     DstIp((IpAddr, u8)),
+    Protocol(AppProtocol),
+    Alpn(SharedStr),
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -67,6 +70,12 @@ fn id_chars(input: &str) -> IResult<&str, &str> {
     take_till1(|c: char| !c.is_alphanumeric() && c != '-' && c != '_')(input)
 }
 
+fn user_name(input: &str) -> IResult<&str, SharedStr> {
+    take_till1(|c: char| c.is_whitespace() || c == '#')
+        .map(SharedStr::from)
+        .parse(input)
+}
+
 fn domain_name_part(input: &str) -> IResult<&str, SharedStr> {
     tuple((id_chars, opt(char('.'))))
         .map(|(name, _)| name.into())
@@ -108,6 +117,30 @@ fn filter_listen_port(input: &str) -> IResult<&str, Filter> {
         .parse(input)
 }
 
+fn filter_user(input: &str) -> IResult<&str, Filter> {
+    tuple((tag_no_case("user"), space1, user_name))
+        .map(|(_, _, name)| Filter::User(name))
+        .parse(input)
+}
+
+fn filter_protocol(input: &str) -> IResult<&str, Filter> {
+    let protocol = map_opt(
+        take_till1(|c: char| c.is_whitespace() || c == '#'),
+        |name| AppProtocol::from_str(name).ok(),
+    );
+    tuple((tag_no_case("protocol"), space1, protocol))
+        .map(|(_, _, protocol)| Filter::Protocol(protocol))
+        .parse(input)
+}
+
+fn filter_alpn(input: &str) -> IResult<&str, Filter> {
+    // ALPN protocol IDs are case-sensitive
+    let name = take_till1(|c: char| c.is_whitespace() || c == '#').map(SharedStr::from);
+    tuple((tag_no_case("alpn"), space1, name))
+        .map(|(_, _, name)| Filter::Alpn(name))
+        .parse(input)
+}
+
 fn filter_default(input: &str) -> IResult<&str, Filter> {
     tag_no_case("default").map(|_| Filter::Default).parse(input)
 }
@@ -117,6 +150,9 @@ fn rule_filter(input: &str) -> IResult<&str, Filter> {
         filter_dst_ip,
         filter_dst_domain,
         filter_listen_port,
+        filter_user,
+        filter_protocol,
+        filter_alpn,
         filter_default,
     ))(input)
 }
@@ -220,6 +256,14 @@ fn test_dst_domain_filter() {
     assert_eq!(Filter::DstSni(shared_str!("test")), parts);
 }
 
+#[test]
+fn test_user_filter() {
+    let (rem, user) = filter_user("user Alice@example.com require a\n").unwrap();
+    assert_eq!(" require a\n", rem);
+    assert_eq!(Filter::User(shared_str!("Alice@example.com")), user);
+    assert!(filter_user("user \n").is_err());
+}
+
 #[test]
 fn test_dst_ip_filter() {
     let (rem, filter) = filter_dst_ip("dst ip ::\n").unwrap();
@@ -227,6 +271,21 @@ fn test_dst_ip_filter() {
     assert!(matches!(filter, Filter::DstIp((_, 128))));
 }
 
+#[test]
+fn test_protocol_filter() {
+    let (rem, filter) = filter_protocol("protocol SSH direct\n").unwrap();
+    assert_eq!(" direct\n", rem);
+    assert_eq!(Filter::Protocol(AppProtocol::Ssh), filter);
+    assert!(filter_protocol("protocol ftp direct\n").is_err());
+}
+
+#[test]
+fn test_alpn_filter() {
+    let (rem, filter) = filter_alpn("alpn http/1.1 require a\n").unwrap();
+    assert_eq!(" require a\n", rem);
+    assert_eq!(Filter::Alpn(shared_str!("http/1.1")), filter);
+}
+
 #[test]
 fn test_dst_default_filter() {
     let (rem, parts) = filter_default("default\n").unwrap();
@@ -12,13 +12,12 @@ use std::{
 };
 use tokio::{
     io::{AsyncRead, AsyncWrite, ReadBuf},
-    net::TcpStream,
     time::{sleep, Instant, Sleep},
 };
 use tracing::{debug, trace};
 
 use self::Side::{Left, Right};
-use crate::proxy::{ProxyServer, Traffic};
+use crate::proxy::{AtomicTraffic, ProxyServer, Traffic};
 
 #[derive(Debug, Clone)]
 enum Side {
@@ -65,8 +64,8 @@ thread_local!(
     static SHARED_BUFFER: RefCell<[u8; SHARED_BUF_SIZE]> = RefCell::new([0u8; SHARED_BUF_SIZE]);
 );
 
-struct StreamWithBuffer {
-    pub stream: TcpStream,
+struct StreamWithBuffer<S> {
+    pub stream: S,
     buf: Option<Box<[u8]>>,
     pos: usize,
     cap: usize,
@@ -74,8 +73,8 @@ struct StreamWithBuffer {
     pub all_done: bool,
 }
 
-impl StreamWithBuffer {
-    pub fn new(stream: TcpStream) -> Self {
+impl<S: AsyncRead + AsyncWrite + Unpin> StreamWithBuffer<S> {
+    pub fn new(stream: S) -> Self {
         StreamWithBuffer {
             stream,
             buf: None,
@@ -118,10 +117,10 @@ impl StreamWithBuffer {
         Poll::Ready(Ok(n))
     }
 
-    pub fn poll_write_buffer_to(
+    pub fn poll_write_buffer_to<W: AsyncWrite + Unpin>(
         &mut self,
         cx: &mut Context,
-        writer: &mut TcpStream,
+        writer: &mut W,
     ) -> Poll<io::Result<usize>> {
         let writer = Pin::new(writer);
 
@@ -173,12 +172,13 @@ impl StreamWithBuffer {
     }
 }
 
-// Pipe two TcpStream in both direction,
-// update traffic amount to ProxyServer on the fly.
-pub struct BiPipe {
-    left: StreamWithBuffer,
-    right: StreamWithBuffer,
+// Pipe two streams in both direction,
+// update traffic amount to ProxyServer (and user, if any) on the fly.
+pub struct BiPipe<L, R> {
+    left: StreamWithBuffer<L>,
+    right: StreamWithBuffer<R>,
     server: Arc<ProxyServer>,
+    user_traffic: Option<Arc<AtomicTraffic>>,
     traffic: Traffic,
     half_close_deadline: Option<Pin<Box<Sleep>>>,
 }
@@ -187,65 +187,103 @@ pub struct BiPipe {
 // after the following duration.
 const HALF_CLOSE_TIMEOUT: Duration = Duration::from_secs(60);
 
-pub fn pipe(left: TcpStream, right: TcpStream, server: Arc<ProxyServer>) -> BiPipe {
+pub fn pipe<L, R>(
+    left: L,
+    right: R,
+    server: Arc<ProxyServer>,
+    user_traffic: Option<Arc<AtomicTraffic>>,
+) -> BiPipe<L, R>
+where
+    L: AsyncRead + AsyncWrite + Unpin,
+    R: AsyncRead + AsyncWrite + Unpin,
+{
     let (left, right) = (StreamWithBuffer::new(left), StreamWithBuffer::new(right));
     BiPipe {
         left,
         right,
         server,
+        user_traffic,
         traffic: Default::default(),
         half_close_deadline: Default::default(),
     }
 }
 
-impl BiPipe {
+impl<L, R> BiPipe<L, R>
+where
+    L: AsyncRead + AsyncWrite + Unpin,
+    R: AsyncRead + AsyncWrite + Unpin,
+{
     fn poll_one_side(&mut self, cx: &mut Context, side: Side) -> Poll<io::Result<()>> {
         let Self {
             ref mut left,
             ref mut right,
             ref mut server,
+            ref user_traffic,
             ref mut traffic,
             ..
         } = *self;
-        let (reader, writer) = match side {
-            Left => (left, right),
-            Right => (right, left),
-        };
-        loop {
-            // read something if buffer is empty
-            if reader.is_empty() && !reader.read_eof {
-                let n = try_poll!(reader.poll_read_to_buffer(cx));
-                let amt = match side {
-                    Left => (n, 0),
-                    Right => (0, n),
-                }
-                .into();
-                server.add_traffic(amt);
-                *traffic += amt;
+        let mut count = |n| {
+            let amt = match side {
+                Left => (n, 0),
+                Right => (0, n),
             }
-
-            // write out if buffer is not empty
-            while !reader.is_empty() {
-                try_poll!(reader.poll_write_buffer_to(cx, &mut writer.stream));
+            .into();
+            server.add_traffic(amt);
+            if let Some(user_traffic) = user_traffic {
+                user_traffic.add(amt);
             }
-            reader.shrink_private_buffer_if_need();
+            *traffic += amt;
+        };
+        match side {
+            Left => copy_one_side(cx, left, right, &mut count),
+            Right => copy_one_side(cx, right, left, &mut count),
+        }
+    }
+}
 
-            // flush and does half close if seen eof
-            if reader.read_eof {
-                // shutdown implies flush
-                match Pin::new(&mut writer.stream).poll_shutdown(cx) {
-                    Poll::Pending => return Poll::Pending,
-                    Poll::Ready(Ok(_)) => (),
-                    Poll::Ready(Err(err)) => debug!("fail to shutdown: {}", err),
-                }
-                reader.all_done = true;
-                return Poll::Ready(Ok(()));
+fn copy_one_side<A, B, F>(
+    cx: &mut Context,
+    reader: &mut StreamWithBuffer<A>,
+    writer: &mut StreamWithBuffer<B>,
+    count: &mut F,
+) -> Poll<io::Result<()>>
+where
+    A: AsyncRead + AsyncWrite + Unpin,
+    B: AsyncRead + AsyncWrite + Unpin,
+    F: FnMut(usize),
+{
+    loop {
+        // read something if buffer is empty
+        if reader.is_empty() && !reader.read_eof {
+            let n = try_poll!(reader.poll_read_to_buffer(cx));
+            count(n);
+        }
+
+        // write out if buffer is not empty
+        while !reader.is_empty() {
+            try_poll!(reader.poll_write_buffer_to(cx, &mut writer.stream));
+        }
+        reader.shrink_private_buffer_if_need();
+
+        // flush and does half close if seen eof
+        if reader.read_eof {
+            // shutdown implies flush
+            match Pin::new(&mut writer.stream).poll_shutdown(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(Ok(_)) => (),
+                Poll::Ready(Err(err)) => debug!("fail to shutdown: {}", err),
             }
+            reader.all_done = true;
+            return Poll::Ready(Ok(()));
         }
     }
 }
 
-impl Future for BiPipe {
+impl<L, R> Future for BiPipe<L, R>
+where
+    L: AsyncRead + AsyncWrite + Unpin,
+    R: AsyncRead + AsyncWrite + Unpin,
+{
     type Output = io::Result<Traffic>;
 
     fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<Traffic>> {
@@ -2,13 +2,10 @@ use base64::prelude::{Engine, BASE64_STANDARD};
 use httparse::{Response, Status, EMPTY_HEADER};
 use std::io::{self, ErrorKind};
 use std::net::IpAddr;
-use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::TcpStream,
-};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tracing::{debug, instrument, trace};
 
-use crate::proxy::{Address, Destination};
+use crate::proxy::{stream::ServerStream, Address, Destination};
 
 use super::UserPassAuthCredential;
 
@@ -27,7 +24,7 @@ const BUF_LEN: usize = 1024;
 
 #[instrument(name = "http_handshake", skip_all)]
 pub async fn handshake<T>(
-    stream: &mut TcpStream,
+    stream: &mut ServerStream,
     addr: &Destination,
     data: Option<T>,
     with_playload: bool,
@@ -54,7 +51,13 @@ where
         let mut headers = [EMPTY_HEADER; 16];
         let mut response = Response::new(&mut headers);
         buf.resize(bytes_read + BUF_LEN, 0);
-        let peek_len = stream.peek(&mut buf).await?;
+        let peek_len = stream.peek(&mut buf[bytes_read..]).await?;
+        if peek_len == 0 {
+            return Err(io::Error::new(
+                ErrorKind::UnexpectedEof,
+                "proxy closed before response",
+            ));
+        }
         bytes_read += peek_len;
         trace!("bytes peek: {}", bytes_read);
 
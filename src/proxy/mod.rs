@@ -1,9 +1,19 @@
 pub mod copy;
 pub mod http;
+pub mod proxy_protocol;
+#[cfg(feature = "shadowsocks")]
+pub mod shadowsocks;
 use flexstr::{shared_fmt, SharedStr};
+use futures_util::future::BoxFuture;
 #[cfg(feature = "score_script")]
 use rlua::prelude::*;
+pub mod socks4;
 pub mod socks5;
+#[cfg(feature = "ssh")]
+pub mod ssh;
+pub mod stream;
+#[cfg(feature = "tls")]
+pub mod tls;
 use parking_lot::{Mutex, RwLock};
 use serde::{Serialize, Serializer};
 use serde_with::{serde_as, DisplayFromStr};
@@ -14,12 +24,25 @@ use std::{
     net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
     ops::{Add, AddAssign},
     str::FromStr,
-    sync::atomic::{AtomicUsize, Ordering},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
     time::Duration,
 };
-use tokio::net::TcpStream;
-use tracing::{debug, instrument};
+use tokio::{
+    io::AsyncWriteExt,
+    net::{lookup_host, TcpSocket, TcpStream, ToSocketAddrs, UdpSocket},
+    time::timeout,
+};
+use tracing::{debug, info, instrument, warn};
 
+#[cfg(feature = "tls")]
+use self::tls::TlsConfig;
+use self::{proxy_protocol::ProxyHeader, stream::ServerStream};
+
+#[cfg(target_os = "linux")]
+use crate::linux::tcp::SocketBindExt;
 use crate::policy::capabilities::CapSet;
 
 const GRAPHITE_PATH_PREFIX: &str = "moproxy.proxy_servers";
@@ -34,7 +57,24 @@ pub enum ProxyProto {
         /// servers.
         fake_handshaking: bool,
         user_pass_auth: Option<UserPassAuthCredential>,
+        /// Server supports UDP ASSOCIATE, allow to relay UDP with it.
+        udp_associate: bool,
+    },
+    #[serde(rename = "SOCKSv4")]
+    Socks4 {
+        /// USERID sent on CONNECT request, empty if not set.
+        userid: Option<SharedStr>,
     },
+    #[cfg(feature = "shadowsocks")]
+    #[serde(rename = "Shadowsocks")]
+    Shadowsocks {
+        method: shadowsocks::Method,
+        #[serde(skip_serializing)]
+        password: SharedStr,
+    },
+    #[cfg(feature = "ssh")]
+    #[serde(rename = "SSH")]
+    Ssh(ssh::SshOptions),
     #[serde(rename = "HTTP")]
     Http {
         /// Allow to send app-level data as payload on CONNECT request.
@@ -70,12 +110,21 @@ impl UserPassAuthCredential {
 #[allow(clippy::mutable_key_type)]
 #[derive(Debug, Serialize)]
 pub struct ProxyServer {
+    /// The address, only its port is meaningful if `host` is set.
     pub addr: SocketAddr,
+    /// Host name of the server, if it's given as a name.
+    pub host: Option<SharedStr>,
+    /// Addresses to try in order, re-resolved from `host` periodically.
+    addrs: RwLock<Vec<SocketAddr>>,
     pub proto: ProxyProto,
     pub tag: SharedStr,
     config: RwLock<ProxyServerConfig>,
     status: Mutex<ProxyServerStatus>,
     traffic: AtomicTraffic,
+    /// Kept across connections, for SSH only.
+    #[cfg(feature = "ssh")]
+    #[serde(skip)]
+    ssh_session: ssh::SshSession,
 }
 
 #[derive(Debug, Serialize, Clone)]
@@ -84,6 +133,114 @@ pub struct ProxyServerConfig {
     pub max_wait: Duration,
     pub capabilities: CapSet,
     score_base: i32,
+    /// Prepend PROXY protocol v2 header carrying client's addresses.
+    pub send_proxy_protocol: bool,
+    /// Wrap the connection with TLS.
+    #[cfg(feature = "tls")]
+    pub tls: Option<TlsConfig>,
+    /// Reach the server through a tunnel on another server.
+    #[serde(serialize_with = "serialize_via")]
+    pub via: Option<Arc<ProxyServer>>,
+    /// Applied on sockets connecting to the server.
+    pub bind: BindOptions,
+}
+
+/// Options of outbound TCP sockets, applied before connecting.
+#[derive(Debug, Serialize, Clone, Default, PartialEq, Eq)]
+pub struct BindOptions {
+    /// Source address, destinations of the other family can't be
+    /// connected once it's set.
+    pub address: Option<IpAddr>,
+    /// Interface to bind (SO_BINDTODEVICE), Linux only.
+    pub interface: Option<SharedStr>,
+    /// Firewall mark (SO_MARK), Linux only.
+    pub fwmark: Option<u32>,
+}
+
+impl BindOptions {
+    /// Connect to each of addresses in order, return the first succeeded,
+    /// like `TcpStream::connect()`. Each attempt is given an equal share
+    /// of `max_wait`, so that a black-holed address doesn't block the rest.
+    pub async fn connect<A: ToSocketAddrs>(
+        &self,
+        addr: A,
+        max_wait: Duration,
+    ) -> io::Result<TcpStream> {
+        let addrs: Vec<_> = lookup_host(addr).await?.collect();
+        let attempt_wait = max_wait / addrs.len().max(1) as u32;
+        let mut last_err = None;
+        for addr in addrs {
+            match timeout(attempt_wait, self.connect_one(addr)).await {
+                Ok(Ok(stream)) => return Ok(stream),
+                Ok(Err(err)) => last_err = Some(err),
+                Err(_) => {
+                    debug!(%addr, "connect timed out");
+                    last_err = Some(io::ErrorKind::TimedOut.into());
+                }
+            }
+        }
+        Err(last_err.unwrap_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "could not resolve to any address",
+            )
+        }))
+    }
+
+    async fn connect_one(&self, addr: SocketAddr) -> io::Result<TcpStream> {
+        let socket = match addr {
+            SocketAddr::V4(_) => TcpSocket::new_v4()?,
+            SocketAddr::V6(_) => TcpSocket::new_v6()?,
+        };
+        if let Some(ip) = self.source_ip(&addr)? {
+            socket.bind((ip, 0).into())?;
+        }
+        #[cfg(target_os = "linux")]
+        self.apply(&socket)?;
+        socket.connect(addr).await
+    }
+
+    /// Bind a UDP socket to send datagrams to `peer`, of the same family.
+    pub async fn bind_udp(&self, peer: &SocketAddr) -> io::Result<UdpSocket> {
+        let ip = match self.source_ip(peer)? {
+            Some(ip) => ip,
+            None if peer.is_ipv4() => Ipv4Addr::UNSPECIFIED.into(),
+            None => Ipv6Addr::UNSPECIFIED.into(),
+        };
+        let socket = UdpSocket::bind((ip, 0)).await?;
+        #[cfg(target_os = "linux")]
+        self.apply(&socket)?;
+        Ok(socket)
+    }
+
+    /// The bind address, fail if it can't reach `peer`.
+    fn source_ip(&self, peer: &SocketAddr) -> io::Result<Option<IpAddr>> {
+        match self.address {
+            Some(ip) if ip.is_ipv4() != peer.is_ipv4() => Err(io::Error::new(
+                io::ErrorKind::AddrNotAvailable,
+                "bind address is of different family",
+            )),
+            ip => Ok(ip),
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn apply<S: SocketBindExt>(&self, socket: &S) -> io::Result<()> {
+        if let Some(interface) = &self.interface {
+            socket.set_bind_device(interface.as_str())?;
+        }
+        if let Some(mark) = self.fwmark {
+            socket.set_mark(mark)?;
+        }
+        Ok(())
+    }
+}
+
+fn serialize_via<S: Serializer>(
+    via: &Option<Arc<ProxyServer>>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    via.as_ref().map(|server| &server.tag).serialize(serializer)
 }
 
 #[cfg(feature = "score_script")]
@@ -136,6 +293,36 @@ impl ToLua<'_> for Delay {
     }
 }
 
+/// Number of recent probe delays kept for percentile estimation.
+const DELAY_HISTORY_LEN: usize = 16;
+
+/// Recent probe delays, time-outs are counted as `max_wait`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DelayHistory {
+    delays: [Duration; DELAY_HISTORY_LEN],
+    len: usize,
+    next: usize,
+}
+
+impl DelayHistory {
+    fn push(&mut self, delay: Duration) {
+        self.delays[self.next] = delay;
+        self.next = (self.next + 1) % DELAY_HISTORY_LEN;
+        self.len = cmp::min(self.len + 1, DELAY_HISTORY_LEN);
+    }
+
+    /// Return the `p`-th percentile (0 to 100) of recorded delays.
+    pub fn percentile(&self, p: u8) -> Option<Duration> {
+        if self.len == 0 {
+            return None;
+        }
+        let mut delays = self.delays[..self.len].to_vec();
+        delays.sort_unstable();
+        let rank = (self.len * cmp::min(p, 100) as usize + 99) / 100;
+        Some(delays[rank.saturating_sub(1)])
+    }
+}
+
 #[serde_as]
 #[derive(Debug, Serialize, Clone, Copy, Default)]
 pub struct ProxyServerStatus {
@@ -144,8 +331,13 @@ pub struct ProxyServerStatus {
     pub conn_alive: u32,
     pub conn_total: u32,
     pub conn_error: u32,
+    /// Number of connections that upstream responded with something
+    /// unexpected, e.g. non-TLS data to a TLS client hello.
+    pub conn_bad_response: u32,
     #[serde_as(as = "DisplayFromStr")]
     pub close_history: u64,
+    #[serde(skip)]
+    pub delay_history: DelayHistory,
 }
 
 #[cfg(feature = "score_script")]
@@ -157,14 +349,20 @@ impl ToLua<'_> for ProxyServerStatus {
         status.set("conn_alive", self.conn_alive)?;
         status.set("conn_total", self.conn_total)?;
         status.set("conn_error", self.conn_error)?;
+        status.set("conn_bad_response", self.conn_bad_response)?;
         status.set("close_history", self.close_history)?;
         status.to_lua(ctx)
     }
 }
 
+// Servers with host name are identified by name rather than the address
+// resolved, so that they're kept across reloading if the address changed.
 impl Hash for ProxyServer {
     fn hash<H: Hasher>(&self, state: &mut H) {
-        self.addr.hash(state);
+        match &self.host {
+            Some(host) => (host, self.addr.port()).hash(state),
+            None => self.addr.hash(state),
+        }
         self.proto.hash(state);
         self.tag.hash(state);
     }
@@ -172,7 +370,12 @@ impl Hash for ProxyServer {
 
 impl PartialEq for ProxyServer {
     fn eq(&self, other: &ProxyServer) -> bool {
-        self.addr == other.addr && self.proto == other.proto && self.tag == other.tag
+        let same_addr = match (&self.host, &other.host) {
+            (Some(a), Some(b)) => a == b && self.addr.port() == other.addr.port(),
+            (None, None) => self.addr == other.addr,
+            _ => false,
+        };
+        same_addr && self.proto == other.proto && self.tag == other.tag
     }
 }
 
@@ -192,7 +395,7 @@ impl ToLua<'_> for &ProxyServer {
     }
 }
 
-#[derive(Hash, Clone)]
+#[derive(Hash, Clone, PartialEq, Eq)]
 pub enum Address {
     Ip(IpAddr),
     Domain(SharedStr),
@@ -233,7 +436,7 @@ impl From<[u8; 16]> for Address {
     }
 }
 
-#[derive(Clone)]
+#[derive(Hash, Clone, PartialEq, Eq)]
 pub struct Destination {
     pub host: Address,
     pub port: u16,
@@ -354,6 +557,7 @@ impl ProxyProto {
         ProxyProto::Socks5 {
             fake_handshaking,
             user_pass_auth: None,
+            udp_associate: false,
         }
     }
 
@@ -361,9 +565,47 @@ impl ProxyProto {
         ProxyProto::Socks5 {
             fake_handshaking: false,
             user_pass_auth: Some(credential),
+            udp_associate: false,
+        }
+    }
+
+    /// Set whether UDP ASSOCIATE is supported. Ignored if not SOCKSv5.
+    pub fn with_udp_associate(mut self, enabled: bool) -> Self {
+        if let ProxyProto::Socks5 { udp_associate, .. } = &mut self {
+            *udp_associate = enabled;
+        }
+        self
+    }
+
+    pub fn support_udp(&self) -> bool {
+        matches!(
+            self,
+            ProxyProto::Socks5 {
+                udp_associate: true,
+                ..
+            }
+        )
+    }
+
+    pub fn socks4(userid: Option<&str>) -> Self {
+        ProxyProto::Socks4 {
+            userid: userid.map(SharedStr::from),
+        }
+    }
+
+    #[cfg(feature = "shadowsocks")]
+    pub fn shadowsocks(method: shadowsocks::Method, password: &str) -> Self {
+        ProxyProto::Shadowsocks {
+            method,
+            password: password.into(),
         }
     }
 
+    #[cfg(feature = "ssh")]
+    pub fn ssh(opts: ssh::SshOptions) -> Self {
+        ProxyProto::Ssh(opts)
+    }
+
     pub fn http(connect_with_payload: bool, credential: Option<UserPassAuthCredential>) -> Self {
         ProxyProto::Http {
             connect_with_payload,
@@ -384,6 +626,11 @@ impl ProxyServerConfig {
             max_wait,
             capabilities: capabilities.unwrap_or_default(),
             score_base: score_base.unwrap_or(0),
+            send_proxy_protocol: false,
+            #[cfg(feature = "tls")]
+            tls: None,
+            via: None,
+            bind: Default::default(),
         }
     }
 }
@@ -414,9 +661,13 @@ impl ProxyServer {
                     SharedStr::from(s)
                 }
             },
+            host: None,
+            addrs: vec![addr].into(),
             config: ProxyServerConfig::new(test_dns, score_base, capabilities, max_wait).into(),
             status: Default::default(),
             traffic: Default::default(),
+            #[cfg(feature = "ssh")]
+            ssh_session: Default::default(),
         }
     }
 
@@ -425,37 +676,193 @@ impl ProxyServer {
         Self {
             addr: stub_addr,
             proto: ProxyProto::Direct,
+            host: None,
+            addrs: vec![stub_addr].into(),
             tag: "__DIRECT__".into(),
             config: ProxyServerConfig::new(stub_addr, None, None, max_wait).into(),
             status: Default::default(),
             traffic: Default::default(),
+            #[cfg(feature = "ssh")]
+            ssh_session: Default::default(),
+        }
+    }
+
+    /// Set the host name which `addrs` are resolved from.
+    pub fn with_host(mut self, host: &str, addrs: Vec<SocketAddr>) -> Self {
+        self.host = Some(host.into());
+        self.addrs = addrs.into();
+        self
+    }
+
+    pub fn addrs(&self) -> Vec<SocketAddr> {
+        self.addrs.read().clone()
+    }
+
+    /// Resolve `host` again if it's set. Keep the last addresses on error.
+    /// Chained servers are resolved by their `via` servers instead.
+    #[instrument(skip_all, fields(proxy = %self.tag))]
+    pub async fn resolve(&self) {
+        let host = match &self.host {
+            Some(host) if self.via().is_none() => host,
+            _ => return,
+        };
+        let result = lookup_host((host.as_str(), self.addr.port())).await;
+        self.update_addrs(host.as_str(), result.map(Iterator::collect));
+    }
+
+    fn update_addrs(&self, host: &str, result: io::Result<Vec<SocketAddr>>) {
+        let addrs = match result {
+            Ok(addrs) => addrs,
+            Err(err) => {
+                warn!(%host, "fail to resolve: {}", err);
+                return;
+            }
+        };
+        if addrs.is_empty() {
+            warn!(%host, "no address resolved");
+        } else if *self.addrs.read() != addrs {
+            info!(%host, ?addrs, "server address changed");
+            *self.addrs.write() = addrs;
+        }
+    }
+
+    /// Where to reach the server, host name is used if it's set.
+    fn destination(&self) -> Destination {
+        match &self.host {
+            Some(host) => (host.as_str(), self.addr.port()).into(),
+            None => self.addr.into(),
         }
     }
 
+    /// Set whether PROXY protocol v2 header is sent on connect.
+    pub fn with_proxy_protocol(self, enabled: bool) -> Self {
+        self.config.write().send_proxy_protocol = enabled;
+        self
+    }
+
+    pub fn send_proxy_protocol(&self) -> bool {
+        self.config.read().send_proxy_protocol
+    }
+
+    /// Set TLS transport to the server.
+    #[cfg(feature = "tls")]
+    pub fn with_tls(self, tls: Option<TlsConfig>) -> Self {
+        self.config.write().tls = tls;
+        self
+    }
+
+    #[cfg(feature = "tls")]
+    pub fn tls(&self) -> Option<TlsConfig> {
+        self.config.read().tls.clone()
+    }
+
+    /// Set the server to be reached through. It's set after all servers
+    /// are created, so it takes `&self`.
+    pub fn set_via(&self, via: Option<Arc<ProxyServer>>) {
+        self.config.write().via = via;
+    }
+
+    pub fn via(&self) -> Option<Arc<ProxyServer>> {
+        self.config.read().via.clone()
+    }
+
+    /// Set options on sockets connecting to the server.
+    pub fn with_bind(self, bind: BindOptions) -> Self {
+        self.config.write().bind = bind;
+        self
+    }
+
+    pub fn bind(&self) -> BindOptions {
+        self.config.read().bind.clone()
+    }
+
     pub fn copy_config_from(&self, from: &Self) {
         if !std::ptr::eq(&from.config, &self.config) {
             *self.config.write() = from.config.read().clone();
         }
     }
 
+    /// Connect to `addr` via this server. `client` is sent as PROXY protocol
+    /// header if it's enabled on the server. If it's chained, the server is
+    /// reached through a tunnel on its `via` server.
     #[instrument(skip_all)]
-    pub async fn connect<T>(&self, addr: &Destination, data: Option<T>) -> io::Result<TcpStream>
+    pub async fn connect<T>(
+        &self,
+        addr: &Destination,
+        data: Option<T>,
+        client: Option<&ProxyHeader>,
+    ) -> io::Result<ServerStream>
     where
         T: AsRef<[u8]> + 'static,
     {
-        let mut stream = TcpStream::connect(&self.addr).await?;
-        debug!(remote = %stream.peer_addr()?, "TCP established");
-        stream.set_nodelay(true)?;
+        #[cfg(feature = "ssh")]
+        if let ProxyProto::Ssh(opts) = &self.proto {
+            // Give up earlier than the caller, so that a stale session
+            // is noticed and reset.
+            let max_wait = self.max_wait() / 2;
+            let channel = self
+                .ssh_session
+                .open_channel(self, opts, addr, client.map(|c| c.src), max_wait)
+                .await;
+            let mut stream = match channel {
+                Ok(channel) => ServerStream::from(channel),
+                Err(err) => {
+                    // Count it as an error since nothing is opened
+                    self.update_stats_conn_open();
+                    self.update_stats_conn_close(true);
+                    return Err(err);
+                }
+            };
+            if let Some(data) = data {
+                stream.write_all(data.as_ref()).await?;
+            }
+            return Ok(stream);
+        }
+
+        let mut stream = match self.via() {
+            Some(via) => via.tunnel(self.destination(), client).await?,
+            None => {
+                // Try each address in order
+                let stream = self
+                    .bind()
+                    .connect(&self.addrs()[..], self.max_wait())
+                    .await?;
+                debug!(remote = %stream.peer_addr()?, "TCP established");
+                stream.set_nodelay(true)?;
+                stream.into()
+            }
+        };
+        match (self.send_proxy_protocol(), client) {
+            (true, Some(client)) => stream.write_all(&proxy_protocol::build_v2(client)).await?,
+            (true, None) => warn!(proxy = %self.tag, "PROXY header unavailable, not sent"),
+            (false, _) => (),
+        }
+        #[cfg(feature = "tls")]
+        if let Some(tls) = self.tls() {
+            stream = tls.connect(stream).await?;
+        }
 
         match &self.proto {
             ProxyProto::Direct => unimplemented!(),
+            #[cfg(feature = "ssh")]
+            ProxyProto::Ssh(_) => unreachable!(),
             ProxyProto::Socks5 {
                 fake_handshaking,
                 user_pass_auth,
+                ..
             } => {
                 socks5::handshake(&mut stream, addr, data, *fake_handshaking, user_pass_auth)
                     .await?
             }
+            ProxyProto::Socks4 { userid } => {
+                socks4::handshake(&mut stream, addr, data, userid.as_deref()).await?
+            }
+            #[cfg(feature = "shadowsocks")]
+            ProxyProto::Shadowsocks { method, password } => {
+                stream = shadowsocks::handshake(stream, addr, data, *method, password)
+                    .await?
+                    .into()
+            }
             ProxyProto::Http {
                 connect_with_payload,
                 user_pass_auth,
@@ -473,6 +880,40 @@ impl ProxyServer {
         Ok(stream)
     }
 
+    /// Open a tunnel to `addr` via this server, used to reach servers
+    /// chained after this one.
+    pub fn tunnel<'a>(
+        &'a self,
+        dest: Destination,
+        client: Option<&'a ProxyHeader>,
+    ) -> BoxFuture<'a, io::Result<ServerStream>> {
+        // Boxed since it's recursive on chains
+        Box::pin(async move { self.connect(&dest, None::<&'static [u8]>, client).await })
+    }
+
+    /// Do UDP ASSOCIATE on the server.
+    #[instrument(skip_all)]
+    pub async fn udp_associate(&self) -> io::Result<socks5::UdpRelay> {
+        let user_pass_auth = match &self.proto {
+            ProxyProto::Socks5 {
+                user_pass_auth,
+                udp_associate: true,
+                ..
+            } => user_pass_auth,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "UDP is not supported by the server",
+                ))
+            }
+        };
+        let bind = self.bind();
+        let stream = bind.connect(&self.addrs()[..], self.max_wait()).await?;
+        debug!(remote = %stream.peer_addr()?, "TCP established");
+        stream.set_nodelay(true)?;
+        socks5::udp_associate(stream, user_pass_auth, &bind).await
+    }
+
     pub fn status_snapshot(&self) -> ProxyServerStatus {
         *self.status.lock()
     }
@@ -496,6 +937,7 @@ impl ProxyServer {
     pub fn update_delay(&self, delay: Option<Duration>) {
         let mut status = self.status.lock();
         let config = self.config.read();
+        status.delay_history.push(delay.unwrap_or(config.max_wait));
 
         if let Some(delay) = delay {
             let last_score = status.score.unwrap_or_else(|| {
@@ -540,12 +982,19 @@ impl ProxyServer {
         let delay_secs = delay.map(|t| t.as_secs_f32());
         let score: Option<i32> = func.call((self, delay_secs))?;
 
+        let max_wait = self.max_wait();
         let mut status = self.status.lock();
         status.score = score;
         status.delay = delay.into();
+        status.delay_history.push(delay.unwrap_or(max_wait));
         Ok(())
     }
 
+    /// 90th percentile of recent probe delays, `None` if never probed.
+    pub fn p90_delay(&self) -> Option<Duration> {
+        self.status.lock().delay_history.percentile(90)
+    }
+
     pub fn add_traffic(&self, traffic: Traffic) {
         self.traffic.add(traffic);
     }
@@ -566,6 +1015,15 @@ impl ProxyServer {
         }
     }
 
+    /// Count a connection dropped due to bad response, it's also treated
+    /// as an error for scoring.
+    pub fn update_stats_bad_response(&self) {
+        let mut status = self.status.lock();
+        status.conn_bad_response += 1;
+        status.close_history <<= 1;
+        status.close_history += 1;
+    }
+
     pub fn graphite_path(&self, suffix: &str) -> String {
         format!(
             "{}.{}.{}",
@@ -596,7 +1054,17 @@ impl fmt::Display for ProxyServer {
         if self.proto == ProxyProto::Direct {
             f.write_str("DIRECT")
         } else {
-            write!(f, "{} ({} {})", self.tag, self.proto, self.addr)
+            match &self.host {
+                Some(host) => write!(
+                    f,
+                    "{} ({} {}:{})",
+                    self.tag,
+                    self.proto,
+                    host,
+                    self.addr.port()
+                ),
+                None => write!(f, "{} ({} {})", self.tag, self.proto, self.addr),
+            }
         }
     }
 }
@@ -605,6 +1073,11 @@ impl fmt::Display for ProxyProto {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
             ProxyProto::Socks5 { .. } => write!(f, "SOCKSv5"),
+            ProxyProto::Socks4 { .. } => write!(f, "SOCKSv4"),
+            #[cfg(feature = "ssh")]
+            ProxyProto::Ssh(_) => write!(f, "SSH"),
+            #[cfg(feature = "shadowsocks")]
+            ProxyProto::Shadowsocks { .. } => write!(f, "Shadowsocks"),
             ProxyProto::Http { .. } => write!(f, "HTTP"),
             ProxyProto::Direct { .. } => write!(f, "DIRECT"),
         }
@@ -632,9 +1105,84 @@ impl FromStr for ProxyProto {
         match s.to_lowercase().as_str() {
             // default to disable fake handshaking
             "socks5" | "socksv5" => Ok(ProxyProto::socks5(false)),
+            "socks4" | "socksv4" | "socks4a" => Ok(ProxyProto::socks4(None)),
             // default to disable connect with payload
             "http" => Ok(ProxyProto::http(false, None)),
             _ => Err(()),
         }
     }
 }
+
+#[test]
+fn test_delay_history_percentile() {
+    let mut history = DelayHistory::default();
+    assert_eq!(None, history.percentile(90));
+    for ms in (1..=20).rev() {
+        history.push(Duration::from_millis(ms * 10));
+    }
+    // Only the last 16 (160ms to 10ms) are kept
+    assert_eq!(Some(Duration::from_millis(150)), history.percentile(90));
+    assert_eq!(Some(Duration::from_millis(10)), history.percentile(0));
+    assert_eq!(Some(Duration::from_millis(160)), history.percentile(100));
+}
+
+#[test]
+fn test_update_addrs() {
+    let last: SocketAddr = "192.0.2.1:1080".parse().unwrap();
+    let server = ProxyServer::new(
+        last,
+        ProxyProto::socks5(false),
+        "127.0.0.1:53".parse().unwrap(),
+        Duration::from_secs(1),
+        None,
+        None,
+        None,
+    )
+    .with_host("proxy.example", vec![last]);
+
+    // Keep the last addresses on failure
+    let err = io::Error::new(io::ErrorKind::Other, "lookup failed");
+    server.update_addrs("proxy.example", Err(err));
+    assert_eq!(vec![last], server.addrs());
+    server.update_addrs("proxy.example", Ok(vec![]));
+    assert_eq!(vec![last], server.addrs());
+
+    // Replace on change
+    let new: SocketAddr = "192.0.2.2:1080".parse().unwrap();
+    server.update_addrs("proxy.example", Ok(vec![new, last]));
+    assert_eq!(vec![new, last], server.addrs());
+}
+
+#[tokio::test]
+async fn test_bind_connect_fallback() {
+    use crate::test_util::{refused_addr, stalled_addr};
+    use tokio::{net::TcpListener, time::Instant};
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let alive = listener.local_addr().unwrap();
+    let (refused, _socket) = refused_addr();
+    let (stalled, _guard) = stalled_addr().await;
+    let bind = BindOptions::default();
+
+    // Stalled one is given up after its share of the wait
+    let max_wait = Duration::from_millis(600);
+    let start = Instant::now();
+    let stream = bind
+        .connect(&[stalled, refused, alive][..], max_wait)
+        .await
+        .unwrap();
+    assert_eq!(alive, stream.peer_addr().unwrap());
+    assert!(start.elapsed() < max_wait);
+
+    // Error of the last attempt is returned
+    let err = bind
+        .connect(&[stalled, refused][..], max_wait)
+        .await
+        .unwrap_err();
+    assert_eq!(io::ErrorKind::ConnectionRefused, err.kind());
+    let err = bind
+        .connect(&[refused, stalled][..], max_wait)
+        .await
+        .unwrap_err();
+    assert_eq!(io::ErrorKind::TimedOut, err.kind());
+}
@@ -0,0 +1,213 @@
+//! HAProxy PROXY protocol v1 & v2, which carries the original source and
+//! destination of a connection through load balancers.
+//! See <https://www.haproxy.org/download/2.8/doc/proxy-protocol.txt>
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    str::from_utf8,
+};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+/// Max length of v1 header including CRLF.
+const V1_MAX_LEN: usize = 107;
+
+/// Addresses carried by PROXY protocol header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProxyHeader {
+    pub src: SocketAddr,
+    pub dst: SocketAddr,
+}
+
+fn error_invalid_data<T>(msg: &'static str) -> io::Result<T> {
+    Err(io::Error::new(io::ErrorKind::InvalidData, msg))
+}
+
+/// Read PROXY protocol v1 or v2 header from `stream`, nothing beyond the
+/// header is consumed.
+/// Return `None` if the header carries no address, i.e. `LOCAL` command
+/// (v2) or `UNKNOWN` protocol (v1), usually health checks from the proxy.
+pub async fn read_header<T>(stream: &mut T) -> io::Result<Option<ProxyHeader>>
+where
+    T: AsyncRead + Unpin,
+{
+    // Shortest v1 header is "PROXY UNKNOWN\r\n", longer than v2 signature.
+    let mut buf = vec![0u8; V2_SIGNATURE.len()];
+    stream.read_exact(&mut buf).await?;
+    if buf[..] == V2_SIGNATURE[..] {
+        buf.resize(16, 0);
+        stream.read_exact(&mut buf[12..]).await?;
+        let len = u16::from_be_bytes([buf[14], buf[15]]) as usize;
+        buf.resize(16 + len, 0);
+        stream.read_exact(&mut buf[16..]).await?;
+        parse_v2(&buf)
+    } else if buf.starts_with(b"PROXY ") {
+        while !buf.ends_with(b"\r\n") {
+            if buf.len() >= V1_MAX_LEN {
+                return error_invalid_data("PROXY protocol: v1 header too long");
+            }
+            buf.push(stream.read_u8().await?);
+        }
+        parse_v1(&buf)
+    } else {
+        error_invalid_data("PROXY protocol: header is required")
+    }
+}
+
+/// Parse v1 header, e.g. `PROXY TCP4 192.0.2.1 192.0.2.2 56324 443\r\n`.
+fn parse_v1(line: &[u8]) -> io::Result<Option<ProxyHeader>> {
+    let line = from_utf8(line)
+        .ok()
+        .and_then(|line| line.strip_suffix("\r\n"))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "PROXY protocol: bad v1"))?;
+    let parts: Vec<_> = line.split(' ').collect();
+    match parts[..] {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", proto @ ("TCP4" | "TCP6"), src, dst, src_port, dst_port] => {
+            let parse_ip = |s: &str| match (proto, s.parse()) {
+                ("TCP4", Ok(ip @ IpAddr::V4(_))) | ("TCP6", Ok(ip @ IpAddr::V6(_))) => Some(ip),
+                _ => None,
+            };
+            match (
+                parse_ip(src),
+                parse_ip(dst),
+                src_port.parse(),
+                dst_port.parse(),
+            ) {
+                (Some(src), Some(dst), Ok(src_port), Ok(dst_port)) => Ok(Some(ProxyHeader {
+                    src: (src, src_port).into(),
+                    dst: (dst, dst_port).into(),
+                })),
+                _ => error_invalid_data("PROXY protocol: bad v1 address"),
+            }
+        }
+        _ => error_invalid_data("PROXY protocol: bad v1"),
+    }
+}
+
+/// Parse v2 header, `data` must be the whole header including signature.
+fn parse_v2(data: &[u8]) -> io::Result<Option<ProxyHeader>> {
+    let ver_cmd = data[12];
+    if ver_cmd >> 4 != 2 {
+        return error_invalid_data("PROXY protocol: unknown version");
+    }
+    match ver_cmd & 0x0f {
+        0x00 => return Ok(None), // LOCAL
+        0x01 => (),              // PROXY
+        _ => return error_invalid_data("PROXY protocol: unknown command"),
+    }
+    let addrs = &data[16..];
+    // High 4 bits is address family, low is transport protocol (ignored).
+    match data[13] >> 4 {
+        0x1 if addrs.len() >= 12 => {
+            let ip = |i: usize| Ipv4Addr::new(addrs[i], addrs[i + 1], addrs[i + 2], addrs[i + 3]);
+            let port = |i: usize| u16::from_be_bytes([addrs[i], addrs[i + 1]]);
+            Ok(Some(ProxyHeader {
+                src: (ip(0), port(8)).into(),
+                dst: (ip(4), port(10)).into(),
+            }))
+        }
+        0x2 if addrs.len() >= 36 => {
+            let ip = |i: usize| {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(&addrs[i..i + 16]);
+                Ipv6Addr::from(octets)
+            };
+            let port = |i: usize| u16::from_be_bytes([addrs[i], addrs[i + 1]]);
+            Ok(Some(ProxyHeader {
+                src: (ip(0), port(32)).into(),
+                dst: (ip(16), port(34)).into(),
+            }))
+        }
+        // AF_UNSPEC or AF_UNIX, nothing useful for us
+        0x0 | 0x3 => Ok(None),
+        _ => error_invalid_data("PROXY protocol: bad v2 address"),
+    }
+}
+
+/// Build v2 header with PROXY command over TCP.
+pub fn build_v2(header: &ProxyHeader) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(16 + 36);
+    buf.extend_from_slice(V2_SIGNATURE);
+    buf.push(0x21); // v2, PROXY
+    match (header.src, header.dst) {
+        (SocketAddr::V4(src), SocketAddr::V4(dst)) => {
+            buf.extend_from_slice(&[0x11, 0, 12]);
+            buf.extend_from_slice(&src.ip().octets());
+            buf.extend_from_slice(&dst.ip().octets());
+        }
+        (src, dst) => {
+            // Mixed families are sent as IPv4-mapped IPv6 addresses.
+            let to_v6 = |addr: SocketAddr| match addr.ip() {
+                IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+                IpAddr::V6(ip) => ip,
+            };
+            buf.extend_from_slice(&[0x21, 0, 36]);
+            buf.extend_from_slice(&to_v6(src).octets());
+            buf.extend_from_slice(&to_v6(dst).octets());
+        }
+    }
+    buf.extend_from_slice(&header.src.port().to_be_bytes());
+    buf.extend_from_slice(&header.dst.port().to_be_bytes());
+    buf
+}
+
+#[tokio::test]
+async fn test_read_proxy_header_v1() {
+    let mut data: &[u8] = b"PROXY TCP4 192.0.2.1 192.0.2.2 56324 443\r\nGET /";
+    let header = read_header(&mut data).await.unwrap().unwrap();
+    assert_eq!("192.0.2.1:56324".parse::<SocketAddr>().unwrap(), header.src);
+    assert_eq!("192.0.2.2:443".parse::<SocketAddr>().unwrap(), header.dst);
+    assert_eq!(b"GET /", data);
+
+    let mut data: &[u8] = b"PROXY UNKNOWN\r\n";
+    assert_eq!(None, read_header(&mut data).await.unwrap());
+
+    let mut data: &[u8] = b"PROXY TCP4 ::1 ::1 1 2\r\n";
+    assert!(read_header(&mut data).await.is_err());
+    let mut data: &[u8] = b"\x05\x01\x00\x05\x01\x00\x01\x7f\x00\x00\x01\x00\x50";
+    assert!(read_header(&mut data).await.is_err());
+}
+
+#[tokio::test]
+async fn test_read_proxy_header_v2() {
+    let mut header = V2_SIGNATURE.to_vec();
+    header.extend_from_slice(&[0x21, 0x11, 0, 12 + 3]);
+    header.extend_from_slice(&[192, 0, 2, 1, 192, 0, 2, 2, 0xdc, 0x04, 0x01, 0xbb]);
+    // Some TLV, ignored
+    header.extend_from_slice(&[0x04, 0, 0]);
+    header.extend_from_slice(b"data");
+    let mut data = &header[..];
+    let header = read_header(&mut data).await.unwrap().unwrap();
+    assert_eq!("192.0.2.1:56324".parse::<SocketAddr>().unwrap(), header.src);
+    assert_eq!("192.0.2.2:443".parse::<SocketAddr>().unwrap(), header.dst);
+    assert_eq!(b"data", data);
+
+    let mut header = V2_SIGNATURE.to_vec();
+    header.extend_from_slice(&[0x20, 0x00, 0, 0]);
+    assert_eq!(None, read_header(&mut &header[..]).await.unwrap());
+}
+
+#[tokio::test]
+async fn test_build_proxy_header_v2() {
+    let header = ProxyHeader {
+        src: "192.0.2.1:56324".parse().unwrap(),
+        dst: "[2001:db8::1]:443".parse().unwrap(),
+    };
+    let buf = build_v2(&header);
+    assert_eq!(16 + 36, buf.len());
+    let parsed = read_header(&mut &buf[..]).await.unwrap().unwrap();
+    assert_eq!(
+        "[::ffff:192.0.2.1]:56324".parse::<SocketAddr>().unwrap(),
+        parsed.src
+    );
+    assert_eq!(header.dst, parsed.dst);
+
+    let header = ProxyHeader {
+        src: "192.0.2.1:56324".parse().unwrap(),
+        dst: "192.0.2.2:443".parse().unwrap(),
+    };
+    let buf = build_v2(&header);
+    assert_eq!(16 + 12, buf.len());
+    assert_eq!(Some(header), read_header(&mut &buf[..]).await.unwrap());
+}
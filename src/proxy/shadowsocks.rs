@@ -0,0 +1,451 @@
+//! Shadowsocks client with AEAD ciphers, see
+//! <https://shadowsocks.org/doc/aead.html>.
+use futures_util::future::poll_fn;
+use md5::{Digest, Md5};
+use rand::Rng;
+use ring::{
+    aead::{self, Aad, LessSafeKey, Nonce, UnboundKey, NONCE_LEN},
+    hkdf,
+};
+use serde::Serialize;
+use std::{
+    fmt,
+    io::{self, ErrorKind},
+    pin::Pin,
+    str::FromStr,
+    task::{ready, Context, Poll},
+};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tracing::{instrument, trace};
+
+use super::{socks5::build_address, Destination};
+
+const TAG_LEN: usize = 16;
+const MAX_PAYLOAD_LEN: usize = 0x3fff;
+const SUBKEY_INFO: &[u8] = b"ss-subkey";
+
+#[derive(Hash, Eq, PartialEq, Clone, Copy, Debug, Serialize)]
+pub enum Method {
+    #[serde(rename = "aes-128-gcm")]
+    Aes128Gcm,
+    #[serde(rename = "aes-256-gcm")]
+    Aes256Gcm,
+    #[serde(rename = "chacha20-ietf-poly1305")]
+    Chacha20IetfPoly1305,
+}
+
+impl Method {
+    fn algorithm(self) -> &'static aead::Algorithm {
+        match self {
+            Self::Aes128Gcm => &aead::AES_128_GCM,
+            Self::Aes256Gcm => &aead::AES_256_GCM,
+            Self::Chacha20IetfPoly1305 => &aead::CHACHA20_POLY1305,
+        }
+    }
+
+    /// Length of key, so is salt.
+    fn key_len(self) -> usize {
+        self.algorithm().key_len()
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Aes128Gcm => "aes-128-gcm",
+            Self::Aes256Gcm => "aes-256-gcm",
+            Self::Chacha20IetfPoly1305 => "chacha20-ietf-poly1305",
+        }
+    }
+}
+
+impl fmt::Display for Method {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Method {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, ()> {
+        match s.to_lowercase().as_str() {
+            "aes-128-gcm" => Ok(Self::Aes128Gcm),
+            "aes-256-gcm" => Ok(Self::Aes256Gcm),
+            "chacha20-ietf-poly1305" => Ok(Self::Chacha20IetfPoly1305),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Derive master key from password, as OpenSSL's `EVP_BytesToKey()` with
+/// MD5 and no salt.
+fn bytes_to_key(password: &[u8], len: usize) -> Vec<u8> {
+    let mut key = Vec::with_capacity(len + 16);
+    let mut last: Vec<u8> = Vec::new();
+    while key.len() < len {
+        let mut md5 = Md5::new();
+        md5.update(&last);
+        md5.update(password);
+        last = md5.finalize().to_vec();
+        key.extend_from_slice(&last);
+    }
+    key.truncate(len);
+    key
+}
+
+/// AEAD cipher with per-session subkey and counting nonce.
+struct Cipher {
+    key: LessSafeKey,
+    nonce: [u8; NONCE_LEN],
+}
+
+impl Cipher {
+    fn new(method: Method, master_key: &[u8], salt: &[u8]) -> Self {
+        let subkey: UnboundKey = hkdf::Salt::new(hkdf::HKDF_SHA1_FOR_LEGACY_USE_ONLY, salt)
+            .extract(master_key)
+            .expand(&[SUBKEY_INFO], method.algorithm())
+            .expect("invalid subkey length")
+            .into();
+        Self {
+            key: LessSafeKey::new(subkey),
+            nonce: [0; NONCE_LEN],
+        }
+    }
+
+    /// Return the current nonce, then increase it (little-endian).
+    fn next_nonce(&mut self) -> Nonce {
+        let nonce = Nonce::assume_unique_for_key(self.nonce);
+        for byte in self.nonce.iter_mut() {
+            *byte = byte.wrapping_add(1);
+            if *byte != 0 {
+                break;
+            }
+        }
+        nonce
+    }
+
+    /// Append encrypted `data` and its tag to `buf`.
+    fn encrypt(&mut self, buf: &mut Vec<u8>, data: &[u8]) {
+        let start = buf.len();
+        buf.extend_from_slice(data);
+        let nonce = self.next_nonce();
+        let tag = self
+            .key
+            .seal_in_place_separate_tag(nonce, Aad::empty(), &mut buf[start..])
+            .expect("payload too large");
+        buf.extend_from_slice(tag.as_ref());
+    }
+
+    /// Decrypt `data` (ciphertext followed by tag) in place.
+    fn decrypt<'a>(&mut self, data: &'a mut [u8]) -> io::Result<&'a [u8]> {
+        let nonce = self.next_nonce();
+        self.key
+            .open_in_place(nonce, Aad::empty(), data)
+            .map(|plain| &*plain)
+            .map_err(|_| io::Error::new(ErrorKind::InvalidData, "shadowsocks: decryption failed"))
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum ReadState {
+    Salt,
+    Length,
+    Payload(usize),
+}
+
+/// Shadowsocks AEAD stream on top of `S`.
+pub struct AeadStream<S> {
+    stream: S,
+    method: Method,
+    master_key: Vec<u8>,
+    encrypter: Cipher,
+    /// Set once server's salt is received.
+    decrypter: Option<Cipher>,
+    /// Our salt, taken on the first write.
+    salt: Option<Vec<u8>>,
+    /// Encrypted data not yet written.
+    write_buf: Vec<u8>,
+    write_pos: usize,
+    read_state: ReadState,
+    /// Raw data of the chunk being read.
+    read_buf: Vec<u8>,
+    /// Decrypted payload not yet consumed.
+    plain: Vec<u8>,
+    plain_pos: usize,
+}
+
+impl<S> fmt::Debug for AeadStream<S>
+where
+    S: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("AeadStream")
+            .field("stream", &self.stream)
+            .field("method", &self.method)
+            .finish()
+    }
+}
+
+fn unexpected_eof<T>() -> Poll<io::Result<T>> {
+    Poll::Ready(Err(io::Error::new(
+        ErrorKind::UnexpectedEof,
+        "shadowsocks: incomplete chunk",
+    )))
+}
+
+impl<S> AeadStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    pub fn new(stream: S, method: Method, password: &str) -> Self {
+        let master_key = bytes_to_key(password.as_bytes(), method.key_len());
+        let mut salt = vec![0u8; method.key_len()];
+        rand::thread_rng().fill(&mut salt[..]);
+        Self {
+            stream,
+            method,
+            encrypter: Cipher::new(method, &master_key, &salt),
+            master_key,
+            decrypter: None,
+            salt: Some(salt),
+            write_buf: Vec::new(),
+            write_pos: 0,
+            read_state: ReadState::Salt,
+            read_buf: Vec::new(),
+            plain: Vec::new(),
+            plain_pos: 0,
+        }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+
+    /// Receive data without removing it. Return 0 if the peer closed.
+    pub async fn peek(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.plain_pos == self.plain.len() {
+            if !poll_fn(|cx| self.poll_next_chunk(cx)).await? {
+                return Ok(0);
+            }
+        }
+        let plain = &self.plain[self.plain_pos..];
+        let n = plain.len().min(buf.len());
+        buf[..n].copy_from_slice(&plain[..n]);
+        Ok(n)
+    }
+
+    /// Read until `read_buf` has `n` bytes or EOF, return its length.
+    fn poll_fill(&mut self, cx: &mut Context, n: usize) -> Poll<io::Result<usize>> {
+        while self.read_buf.len() < n {
+            let start = self.read_buf.len();
+            self.read_buf.resize(n, 0);
+            let mut buf = ReadBuf::new(&mut self.read_buf[start..]);
+            let result = Pin::new(&mut self.stream).poll_read(cx, &mut buf);
+            let filled = buf.filled().len();
+            self.read_buf.truncate(start + filled);
+            ready!(result)?;
+            if filled == 0 {
+                break;
+            }
+        }
+        Poll::Ready(Ok(self.read_buf.len()))
+    }
+
+    /// Read & decrypt the next chunk into `plain`.
+    /// Return `false` if the peer closed.
+    fn poll_next_chunk(&mut self, cx: &mut Context) -> Poll<io::Result<bool>> {
+        loop {
+            match self.read_state {
+                ReadState::Salt => {
+                    let len = self.method.key_len();
+                    match ready!(self.poll_fill(cx, len))? {
+                        0 => return Poll::Ready(Ok(false)),
+                        n if n < len => return unexpected_eof(),
+                        _ => (),
+                    }
+                    let cipher = Cipher::new(self.method, &self.master_key, &self.read_buf);
+                    self.decrypter = Some(cipher);
+                    self.read_buf.clear();
+                    self.read_state = ReadState::Length;
+                }
+                ReadState::Length => {
+                    match ready!(self.poll_fill(cx, 2 + TAG_LEN))? {
+                        0 => return Poll::Ready(Ok(false)),
+                        n if n < 2 + TAG_LEN => return unexpected_eof(),
+                        _ => (),
+                    }
+                    let cipher = self.decrypter.as_mut().expect("missing decrypter");
+                    let len = match *cipher.decrypt(&mut self.read_buf)? {
+                        [high, low] => u16::from_be_bytes([high, low]) as usize & MAX_PAYLOAD_LEN,
+                        _ => unreachable!(),
+                    };
+                    self.read_buf.clear();
+                    self.read_state = ReadState::Payload(len);
+                }
+                ReadState::Payload(len) => {
+                    if ready!(self.poll_fill(cx, len + TAG_LEN))? < len + TAG_LEN {
+                        return unexpected_eof();
+                    }
+                    let cipher = self.decrypter.as_mut().expect("missing decrypter");
+                    cipher.decrypt(&mut self.read_buf)?;
+                    trace!("shadowsocks: {} bytes chunk read", len);
+                    std::mem::swap(&mut self.plain, &mut self.read_buf);
+                    self.plain.truncate(len);
+                    self.plain_pos = 0;
+                    self.read_buf.clear();
+                    self.read_state = ReadState::Length;
+                    return Poll::Ready(Ok(true));
+                }
+            }
+        }
+    }
+
+    /// Write out all encrypted data in `write_buf`.
+    fn poll_write_buf(&mut self, cx: &mut Context) -> Poll<io::Result<()>> {
+        while self.write_pos < self.write_buf.len() {
+            let n = ready!(
+                Pin::new(&mut self.stream).poll_write(cx, &self.write_buf[self.write_pos..])
+            )?;
+            if n == 0 {
+                return Poll::Ready(Err(ErrorKind::WriteZero.into()));
+            }
+            self.write_pos += n;
+        }
+        self.write_buf.clear();
+        self.write_pos = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S> AsyncRead for AeadStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        while this.plain_pos == this.plain.len() {
+            if !ready!(this.poll_next_chunk(cx))? {
+                return Poll::Ready(Ok(()));
+            }
+        }
+        let plain = &this.plain[this.plain_pos..];
+        let n = plain.len().min(buf.remaining());
+        buf.put_slice(&plain[..n]);
+        this.plain_pos += n;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S> AsyncWrite for AeadStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_buf(cx))?;
+        // The salt is sent along with the first chunk
+        if let Some(salt) = this.salt.take() {
+            this.write_buf.extend_from_slice(&salt);
+        }
+        let n = buf.len().min(MAX_PAYLOAD_LEN);
+        let len = (n as u16).to_be_bytes();
+        this.encrypter.encrypt(&mut this.write_buf, &len);
+        this.encrypter.encrypt(&mut this.write_buf, &buf[..n]);
+        // Data has been taken, the rest is written on next write or flush
+        if let Poll::Ready(Err(err)) = this.poll_write_buf(cx) {
+            return Poll::Ready(Err(err));
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_buf(cx))?;
+        Pin::new(&mut this.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_buf(cx))?;
+        Pin::new(&mut this.stream).poll_shutdown(cx)
+    }
+}
+
+/// Start a Shadowsocks session on `stream`. Target address is sent in the
+/// first chunk, along with `data` if any.
+#[instrument(name = "shadowsocks_handshake", skip_all)]
+pub async fn handshake<S, T>(
+    stream: S,
+    addr: &Destination,
+    data: Option<T>,
+    method: Method,
+    password: &str,
+) -> io::Result<AeadStream<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    T: AsRef<[u8]>,
+{
+    let mut stream = AeadStream::new(stream, method, password);
+    let mut buf = Vec::with_capacity(512);
+    build_address(&mut buf, addr);
+    if let Some(data) = data {
+        buf.extend_from_slice(data.as_ref());
+    }
+    trace!("shadowsocks: write first chunk of {} bytes", buf.len());
+    stream.write_all(&buf).await?;
+    stream.flush().await?;
+    Ok(stream)
+}
+
+#[test]
+fn test_bytes_to_key() {
+    let key = bytes_to_key(b"password", 32);
+    assert_eq!([0x5f, 0x4d, 0xcc, 0x3b, 0x5a, 0xa7, 0x65, 0xd6], key[..8]);
+    assert_eq!(32, key.len());
+}
+
+#[tokio::test]
+async fn test_aead_stream() {
+    use tokio::io::AsyncReadExt;
+
+    let (left, right) = tokio::io::duplex(1024 * 64);
+    let dest = ("example.com", 443).into();
+    let data = vec![0x16u8; 20_000];
+    let mut client = handshake(left, &dest, Some(&data), Method::Aes256Gcm, "secret")
+        .await
+        .unwrap();
+    // Server side speaks the same framing
+    let mut server = AeadStream::new(right, Method::Aes256Gcm, "secret");
+    let mut buf = vec![0u8; 15 + data.len()];
+    server.read_exact(&mut buf).await.unwrap();
+    assert_eq!(b"\x03\x0bexample.com\x01\xbb", &buf[..15]);
+    assert_eq!(data, buf[15..]);
+
+    server.write_all(b"\x16\x03\x03hello").await.unwrap();
+    server.flush().await.unwrap();
+    let mut peeked = [0u8; 3];
+    assert_eq!(3, client.peek(&mut peeked).await.unwrap());
+    assert_eq!(b"\x16\x03\x03", &peeked);
+    let mut buf = [0u8; 8];
+    client.read_exact(&mut buf).await.unwrap();
+    assert_eq!(b"\x16\x03\x03hello", &buf);
+
+    // Wrong password
+    let (left, right) = tokio::io::duplex(1024);
+    handshake(left, &dest, None::<&[u8]>, Method::Aes128Gcm, "secret")
+        .await
+        .unwrap();
+    let mut server = AeadStream::new(right, Method::Aes128Gcm, "wrong");
+    assert!(server.read_u8().await.is_err());
+}
@@ -0,0 +1,108 @@
+use crate::proxy::{Address, Destination};
+use std::io::{self, ErrorKind};
+use std::net::IpAddr;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tracing::{instrument, trace};
+
+const CMD_CONNECT: u8 = 0x01;
+
+// Reply codes
+const REP_GRANTED: u8 = 0x5a;
+const REP_REJECTED: u8 = 0x5b;
+const REP_IDENTD_UNREACHABLE: u8 = 0x5c;
+const REP_IDENTD_MISMATCH: u8 = 0x5d;
+
+/// Send CONNECT request, and then `data` once it's granted.
+/// Domain names are sent as per SOCKS4a.
+#[instrument(name = "socks4_handshake", skip_all)]
+pub async fn handshake<S, T>(
+    stream: &mut S,
+    addr: &Destination,
+    data: Option<T>,
+    userid: Option<&str>,
+) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    T: AsRef<[u8]>,
+{
+    let buf = build_request(addr, userid.unwrap_or_default())?;
+    trace!("socks4: write request {:?}", buf);
+    stream.write_all(&buf).await?;
+
+    let mut buf = [0u8; 8];
+    stream.read_exact(&mut buf).await?;
+    trace!("socks4: read reply {:?}", buf);
+    let msg = match buf[..2] {
+        [0, REP_GRANTED] => None,
+        [0, REP_REJECTED] => Some("request rejected or failed"),
+        [0, REP_IDENTD_UNREACHABLE] => Some("identd unreachable"),
+        [0, REP_IDENTD_MISMATCH] => Some("userid mismatched with identd"),
+        _ => Some("unrecognized reply"),
+    };
+    if let Some(msg) = msg {
+        return Err(io::Error::new(
+            ErrorKind::Other,
+            format!("socks4 server reply error: {}", msg),
+        ));
+    }
+
+    if let Some(data) = data {
+        trace!("socks4: write payload {:?}", data.as_ref());
+        stream.write_all(data.as_ref()).await?;
+    }
+    Ok(())
+}
+
+fn build_request(addr: &Destination, userid: &str) -> io::Result<Vec<u8>> {
+    if userid.contains('\0') {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            "socks4: NUL in userid",
+        ));
+    }
+    let mut buf = Vec::with_capacity(16);
+    buf.extend_from_slice(&[4, CMD_CONNECT]);
+    buf.extend_from_slice(&addr.port.to_be_bytes());
+    match &addr.host {
+        Address::Ip(ip) => {
+            let ip = match ip {
+                IpAddr::V4(ip) => *ip,
+                IpAddr::V6(ip) => ip.to_ipv4_mapped().ok_or_else(|| {
+                    io::Error::new(
+                        ErrorKind::Unsupported,
+                        "socks4: IPv6 destination is not supported",
+                    )
+                })?,
+            };
+            buf.extend_from_slice(&ip.octets());
+            buf.extend_from_slice(userid.as_bytes());
+            buf.push(0);
+        }
+        Address::Domain(name) => {
+            // SOCKS4a: 0.0.0.x (x != 0) indicates a domain name follows
+            buf.extend_from_slice(&[0, 0, 0, 1]);
+            buf.extend_from_slice(userid.as_bytes());
+            buf.push(0);
+            buf.extend_from_slice(name.as_bytes());
+            buf.push(0);
+        }
+    }
+    Ok(buf)
+}
+
+#[test]
+fn test_build_socks4_request() {
+    let addr = "192.0.2.1:80".parse::<std::net::SocketAddr>().unwrap();
+    assert_eq!(
+        b"\x04\x01\x00\x50\xc0\x00\x02\x01\x00",
+        build_request(&addr.into(), "").unwrap().as_slice()
+    );
+    assert_eq!(
+        b"\x04\x01\x01\xbb\x00\x00\x00\x01user\x00example.com\x00",
+        build_request(&("example.com", 443).into(), "user")
+            .unwrap()
+            .as_slice()
+    );
+    let addr = "[2001:db8::1]:80".parse::<std::net::SocketAddr>().unwrap();
+    assert!(build_request(&addr.into(), "").is_err());
+}
@@ -1,23 +1,25 @@
 use crate::proxy::{Address, Destination};
 use std::io::{self, ErrorKind};
-use std::net::IpAddr;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::{error::Error, fmt};
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::TcpStream,
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpStream, UdpSocket},
 };
 use tracing::{instrument, trace};
 
-use super::UserPassAuthCredential;
+use super::{BindOptions, UserPassAuthCredential};
 
 #[instrument(name = "socks5_handshake", skip_all)]
-pub async fn handshake<T>(
-    stream: &mut TcpStream,
+pub async fn handshake<S, T>(
+    stream: &mut S,
     addr: &Destination,
     data: Option<T>,
     fake_handshaking: bool,
     user_pass_auth: &Option<UserPassAuthCredential>,
 ) -> io::Result<()>
 where
+    S: AsyncRead + AsyncWrite + Unpin,
     T: AsRef<[u8]>,
 {
     if fake_handshaking && user_pass_auth.is_none() {
@@ -29,17 +31,18 @@ where
     }
 }
 
-pub async fn fake_handshake<T>(
-    stream: &mut TcpStream,
+pub async fn fake_handshake<S, T>(
+    stream: &mut S,
     addr: &Destination,
     data: Option<T>,
 ) -> io::Result<()>
 where
+    S: AsyncRead + AsyncWrite + Unpin,
     T: AsRef<[u8]>,
 {
     let mut buf = Vec::with_capacity(16);
     buf.extend_from_slice(&[5, 1, 0]);
-    build_request(&mut buf, addr);
+    build_request(&mut buf, CMD_CONNECT, addr);
     stream.write_all(&buf).await?;
     if let Some(data) = data {
         stream.write_all(data.as_ref()).await?;
@@ -55,14 +58,160 @@ macro_rules! err {
     };
 }
 
-pub async fn full_handshake<T>(
-    stream: &mut TcpStream,
+const CMD_CONNECT: u8 = 0x01;
+const CMD_UDP_ASSOCIATE: u8 = 0x03;
+
+// Reply codes (RFC 1928 section 6)
+pub const REP_SUCCEEDED: u8 = 0x00;
+pub const REP_GENERAL_FAILURE: u8 = 0x01;
+pub const REP_NOT_ALLOWED: u8 = 0x02;
+pub const REP_NETWORK_UNREACHABLE: u8 = 0x03;
+pub const REP_HOST_UNREACHABLE: u8 = 0x04;
+pub const REP_CONNECTION_REFUSED: u8 = 0x05;
+pub const REP_TTL_EXPIRED: u8 = 0x06;
+pub const REP_COMMAND_NOT_SUPPORTED: u8 = 0x07;
+
+/// Non-succeeded reply from SOCKSv5 server, carried by `io::Error`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReplyError(pub u8);
+
+impl fmt::Display for ReplyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let msg = match self.0 {
+            REP_GENERAL_FAILURE => "general failure",
+            REP_NOT_ALLOWED => "connection not allowed by ruleset",
+            REP_NETWORK_UNREACHABLE => "network unreachable",
+            REP_HOST_UNREACHABLE => "host unreachable",
+            REP_CONNECTION_REFUSED => "connection refused",
+            REP_TTL_EXPIRED => "TTL expired",
+            REP_COMMAND_NOT_SUPPORTED => "command not supported",
+            _ => "unknown error",
+        };
+        write!(f, "socks server reply error: {} ({:#04x})", msg, self.0)
+    }
+}
+
+impl Error for ReplyError {}
+
+impl From<ReplyError> for io::Error {
+    fn from(err: ReplyError) -> Self {
+        let kind = match err.0 {
+            REP_NOT_ALLOWED => ErrorKind::PermissionDenied,
+            REP_CONNECTION_REFUSED => ErrorKind::ConnectionRefused,
+            REP_HOST_UNREACHABLE => ErrorKind::TimedOut,
+            _ => ErrorKind::Other,
+        };
+        io::Error::new(kind, err)
+    }
+}
+
+/// Pick the reply code that best describes `err` for SOCKSv5 client.
+/// Reply from upstream SOCKSv5 server is passed through as it is.
+pub fn reply_code(err: &io::Error) -> u8 {
+    if let Some(ReplyError(rep)) = err.get_ref().and_then(|err| err.downcast_ref()) {
+        return *rep;
+    }
+    match err.kind() {
+        ErrorKind::PermissionDenied => REP_NOT_ALLOWED,
+        ErrorKind::ConnectionRefused => REP_CONNECTION_REFUSED,
+        ErrorKind::TimedOut => REP_HOST_UNREACHABLE,
+        _ => match err.raw_os_error() {
+            #[cfg(target_os = "linux")]
+            Some(libc::ENETUNREACH) => REP_NETWORK_UNREACHABLE,
+            #[cfg(target_os = "linux")]
+            Some(libc::EHOSTUNREACH) => REP_HOST_UNREACHABLE,
+            _ => REP_GENERAL_FAILURE,
+        },
+    }
+}
+
+pub async fn full_handshake<S, T>(
+    stream: &mut S,
     addr: &Destination,
     data: Option<T>,
     user_pass_auth: &Option<UserPassAuthCredential>,
 ) -> io::Result<()>
 where
+    S: AsyncRead + AsyncWrite + Unpin,
     T: AsRef<[u8]>,
+{
+    negotiate_auth(stream, user_pass_auth).await?;
+
+    // Write the actual request
+    let mut buf = vec![];
+    build_request(&mut buf, CMD_CONNECT, addr);
+    trace!("socks: write request {:?}", buf);
+    stream.write_all(&buf).await?;
+
+    // Check server's reply
+    read_reply(stream).await?;
+
+    // Write out payload if exist
+    if let Some(data) = data {
+        trace!("socks: write payload {:?}", data.as_ref());
+        stream.write_all(data.as_ref()).await?;
+    }
+    Ok(())
+}
+
+/// A UDP association on SOCKSv5 server.
+/// The association terminates once `control` is closed.
+#[derive(Debug)]
+pub struct UdpRelay {
+    pub control: TcpStream,
+    /// Socket that has been connected to server's relay address.
+    /// Each datagram must be encapsulated with UDP request header.
+    pub socket: UdpSocket,
+}
+
+/// Do UDP ASSOCIATE on `stream`, return the relay.
+#[instrument(name = "socks5_udp_associate", skip_all)]
+pub async fn udp_associate(
+    mut stream: TcpStream,
+    user_pass_auth: &Option<UserPassAuthCredential>,
+    bind: &BindOptions,
+) -> io::Result<UdpRelay> {
+    negotiate_auth(&mut stream, user_pass_auth).await?;
+
+    // We don't know which address & port will be used to send datagrams
+    let mut buf = vec![];
+    let unspecified = SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0);
+    build_request(&mut buf, CMD_UDP_ASSOCIATE, &unspecified.into());
+    trace!("socks: write udp associate {:?}", buf);
+    stream.write_all(&buf).await?;
+
+    let relay_addr = match read_reply(&mut stream).await? {
+        Destination {
+            host: Address::Ip(ip),
+            port,
+        } if ip.is_unspecified() => {
+            // Relay on the same address of the server
+            SocketAddr::new(stream.peer_addr()?.ip(), port)
+        }
+        Destination {
+            host: Address::Ip(ip),
+            port,
+        } => SocketAddr::new(ip, port),
+        Destination {
+            host: Address::Domain(_),
+            ..
+        } => err!("domain name as relay address is not supported"),
+    };
+    trace!("socks: udp relay on {}", relay_addr);
+    let socket = bind.bind_udp(&relay_addr).await?;
+    socket.connect(relay_addr).await?;
+    Ok(UdpRelay {
+        control: stream,
+        socket,
+    })
+}
+
+async fn negotiate_auth<S>(
+    stream: &mut S,
+    user_pass_auth: &Option<UserPassAuthCredential>,
+) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
 {
     let mut buf = vec![];
     if user_pass_auth.is_none() {
@@ -113,35 +262,89 @@ where
         _ => err!("unrecognized reply from socks server"),
     }
 
-    // Write the actual request
-    buf.clear();
-    build_request(&mut buf, addr);
-    trace!("socks: write request {:?}", buf);
-    stream.write_all(&buf).await?;
+    Ok(())
+}
 
-    // Check server's reply
-    buf.resize(10, 0);
+/// Read server's reply, return the bound address.
+async fn read_reply<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<Destination> {
+    let mut buf = [0u8; 4];
     stream.read_exact(&mut buf).await?;
     trace!("socks: read reply {:?}", buf);
-    if !buf.starts_with(&[0x05, 0x00]) {
-        err!("socks server reply error");
+    if buf[0] != 0x05 {
+        err!("unrecognized reply from socks server");
     }
-    if buf[3] == 4 {
-        // Consume truncted IPv6 address
-        buf.resize(16 - 4, 0);
-        stream.read_exact(&mut buf).await?;
+    if buf[1] != REP_SUCCEEDED {
+        return Err(ReplyError(buf[1]).into());
     }
+    let host = match buf[3] {
+        0x01 => {
+            let mut buf = [0u8; 4];
+            stream.read_exact(&mut buf).await?;
+            buf.into()
+        }
+        0x04 => {
+            let mut buf = [0u8; 16];
+            stream.read_exact(&mut buf).await?;
+            buf.into()
+        }
+        0x03 => {
+            let len = stream.read_u8().await? as usize;
+            let mut buf = vec![0u8; len];
+            stream.read_exact(&mut buf).await?;
+            Address::Domain(String::from_utf8_lossy(&buf).as_ref().into())
+        }
+        _ => err!("unknown address type in socks server reply"),
+    };
+    let port = stream.read_u16().await?;
+    Ok((host, port).into())
+}
 
-    // Write out payload if exist
-    if let Some(data) = data {
-        trace!("socks: write payload {:?}", data.as_ref());
-        stream.write_all(data.as_ref()).await?;
+fn build_request(buffer: &mut Vec<u8>, cmd: u8, addr: &Destination) {
+    buffer.extend_from_slice(&[5, cmd, 0]);
+    build_address(buffer, addr);
+}
+
+/// Build the reply to SOCKSv5 client.
+pub fn build_reply(buffer: &mut Vec<u8>, rep: u8, addr: &Destination) {
+    build_request(buffer, rep, addr);
+}
+
+/// Build the header of UDP request/reply, which prepends to each datagram.
+pub fn build_udp_header(buffer: &mut Vec<u8>, addr: &Destination) {
+    // RSV & FRAG
+    buffer.extend_from_slice(&[0, 0, 0]);
+    build_address(buffer, addr);
+}
+
+/// Parse the header of UDP request/reply, return the address and the
+/// length of the header. Fragmented datagrams are not supported.
+pub fn parse_udp_header(data: &[u8]) -> Option<(Destination, usize)> {
+    // RSV & FRAG
+    if data.get(..3)? != [0, 0, 0] {
+        return None;
     }
-    Ok(())
+    let (host, len) = match *data.get(3)? {
+        0x01 => {
+            let buf: [u8; 4] = data.get(4..8)?.try_into().ok()?;
+            (buf.into(), 4)
+        }
+        0x04 => {
+            let buf: [u8; 16] = data.get(4..20)?.try_into().ok()?;
+            (buf.into(), 16)
+        }
+        0x03 => {
+            let len = *data.get(4)? as usize;
+            let name = std::str::from_utf8(data.get(5..5 + len)?).ok()?;
+            (Address::Domain(name.into()), len + 1)
+        }
+        _ => return None,
+    };
+    let port = data.get(4 + len..6 + len)?;
+    let port = (port[0] as u16) << 8 | port[1] as u16;
+    Some(((host, port).into(), 6 + len))
 }
 
-fn build_request(buffer: &mut Vec<u8>, addr: &Destination) {
-    buffer.extend_from_slice(&[5, 1, 0]);
+pub(super) fn build_address(buffer: &mut Vec<u8>, addr: &Destination) {
     match addr.host {
         Address::Ip(ip) => match ip {
             IpAddr::V4(ip) => {
@@ -162,3 +365,16 @@ fn build_request(buffer: &mut Vec<u8>, addr: &Destination) {
     buffer.push((addr.port >> 8) as u8);
     buffer.push(addr.port as u8);
 }
+
+#[test]
+fn test_reply_code() {
+    let timed_out = io::Error::from(ErrorKind::TimedOut);
+    assert_eq!(REP_HOST_UNREACHABLE, reply_code(&timed_out));
+    let err = io::Error::from(ReplyError(REP_HOST_UNREACHABLE));
+    assert_eq!(ErrorKind::TimedOut, err.kind());
+    assert_eq!(REP_HOST_UNREACHABLE, reply_code(&err));
+    // Passed through as it is
+    let err = io::Error::from(ReplyError(REP_TTL_EXPIRED));
+    assert_eq!(ErrorKind::Other, err.kind());
+    assert_eq!(REP_TTL_EXPIRED, reply_code(&err));
+}
@@ -0,0 +1,314 @@
+//! SSH upstream: one persistent session per server, a `direct-tcpip`
+//! channel per connection.
+use async_trait::async_trait;
+use flexstr::SharedStr;
+use russh::{
+    client::{self, Handle, Msg},
+    ChannelStream,
+};
+use russh_keys::{agent::client::AgentClient, key::PublicKey};
+use serde::Serialize;
+use std::{
+    fmt, io,
+    net::SocketAddr,
+    path::PathBuf,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    sync::Mutex,
+    task::spawn_blocking,
+    time::timeout,
+};
+use tracing::{debug, info, instrument, warn};
+
+use super::{stream::ServerStream, Address, Destination, ProxyServer};
+
+/// Server-side options of an SSH upstream.
+#[derive(Hash, Eq, PartialEq, Clone, Debug, Serialize)]
+pub struct SshOptions {
+    pub user: SharedStr,
+    /// Host name to look up in known_hosts.
+    pub host: SharedStr,
+    /// Private key, or keys from ssh-agent (`$SSH_AUTH_SOCK`) if not set.
+    pub key_file: Option<PathBuf>,
+    /// Default to `~/.ssh/known_hosts`.
+    pub known_hosts: Option<PathBuf>,
+}
+
+fn other_error<E>(err: E) -> io::Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    io::Error::new(io::ErrorKind::Other, err)
+}
+
+struct Client {
+    host: SharedStr,
+    port: u16,
+    known_hosts: Option<PathBuf>,
+}
+
+#[async_trait]
+impl client::Handler for Client {
+    type Error = russh::Error;
+
+    async fn check_server_key(&mut self, key: &PublicKey) -> Result<bool, Self::Error> {
+        // known_hosts is read from disk
+        let (host, port, key) = (self.host.clone(), self.port, key.clone());
+        let known_hosts = self.known_hosts.clone();
+        let result = spawn_blocking(move || match known_hosts {
+            Some(path) => russh_keys::check_known_hosts_path(&host, port, &key, path),
+            None => russh_keys::check_known_hosts(&host, port, &key),
+        })
+        .await;
+        let result = match result {
+            Ok(result) => result,
+            Err(err) => {
+                warn!(?err, "SSH host key verification panicked");
+                return Ok(false);
+            }
+        };
+        match result {
+            Ok(true) => Ok(true),
+            Ok(false) => {
+                warn!(host = %self.host, "SSH host key not found in known_hosts");
+                Ok(false)
+            }
+            Err(err) => {
+                warn!(host = %self.host, ?err, "SSH host key verification failed");
+                Ok(false)
+            }
+        }
+    }
+}
+
+struct Session {
+    handle: Handle<Client>,
+    local_addr: SocketAddr,
+    peer_addr: SocketAddr,
+}
+
+/// The (re)connectable SSH session to a server.
+#[derive(Default)]
+pub struct SshSession {
+    session: Mutex<Option<Arc<Session>>>,
+}
+
+impl fmt::Debug for SshSession {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("SshSession")
+    }
+}
+
+impl SshSession {
+    /// Return the current session, or connect a new one if it's closed.
+    /// Others wait for the lock meanwhile, so connecting is limited to
+    /// `max_wait`.
+    async fn get(
+        &self,
+        server: &ProxyServer,
+        opts: &SshOptions,
+        max_wait: Duration,
+    ) -> io::Result<Arc<Session>> {
+        let mut session = self.session.lock().await;
+        if let Some(current) = &*session {
+            if !current.handle.is_closed() {
+                return Ok(current.clone());
+            }
+        }
+        let new = timeout(max_wait, connect_session(server, opts))
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "SSH connect timed out"))??;
+        let new = Arc::new(new);
+        *session = Some(new.clone());
+        Ok(new)
+    }
+
+    /// Drop `session` if it's still the current one, so that the next
+    /// channel will be opened on a new session.
+    async fn reset(&self, session: &Arc<Session>) {
+        let mut current = self.session.lock().await;
+        if current.as_ref().map_or(false, |s| Arc::ptr_eq(s, session)) {
+            *current = None;
+        }
+    }
+
+    /// Open a `direct-tcpip` channel to `dest`. `originator` is reported
+    /// to the server as the source of the channel. A new session, if any,
+    /// is established to `server`, through its `via` server if chained.
+    #[instrument(name = "ssh_open_channel", skip_all)]
+    pub async fn open_channel(
+        &self,
+        server: &ProxyServer,
+        opts: &SshOptions,
+        dest: &Destination,
+        originator: Option<SocketAddr>,
+        max_wait: Duration,
+    ) -> io::Result<SshChannel> {
+        let session = self.get(server, opts, max_wait).await?;
+        let host = match &dest.host {
+            Address::Ip(ip) => ip.to_string(),
+            Address::Domain(name) => name.to_string(),
+        };
+        let originator = originator.unwrap_or_else(|| ([127, 0, 0, 1], 0).into());
+        let result = timeout(
+            max_wait,
+            session.handle.channel_open_direct_tcpip(
+                host,
+                dest.port.into(),
+                originator.ip().to_string(),
+                originator.port().into(),
+            ),
+        )
+        .await;
+        match result {
+            Ok(Ok(channel)) => {
+                debug!("SSH channel opened");
+                Ok(SshChannel {
+                    stream: Box::pin(channel.into_stream()),
+                    local_addr: session.local_addr,
+                    peer_addr: session.peer_addr,
+                })
+            }
+            Ok(Err(err)) => {
+                if session.handle.is_closed() {
+                    self.reset(&session).await;
+                }
+                Err(other_error(err))
+            }
+            Err(_) => {
+                // Session may be broken without being noticed
+                info!("SSH channel open timed out, reset session");
+                self.reset(&session).await;
+                Err(io::ErrorKind::TimedOut.into())
+            }
+        }
+    }
+}
+
+#[instrument(name = "ssh_connect", skip_all, fields(proxy = %server.tag))]
+async fn connect_session(server: &ProxyServer, opts: &SshOptions) -> io::Result<Session> {
+    let stream: ServerStream = match server.via() {
+        Some(via) => via.tunnel(server.destination(), None).await?,
+        None => {
+            let stream = server
+                .bind()
+                .connect(&server.addrs()[..], server.max_wait())
+                .await?;
+            stream.set_nodelay(true)?;
+            stream.into()
+        }
+    };
+    let local_addr = stream.local_addr()?;
+    let peer_addr = stream.peer_addr()?;
+    let handler = Client {
+        host: opts.host.clone(),
+        port: server.addr.port(),
+        known_hosts: opts.known_hosts.clone(),
+    };
+    let config = Arc::new(client::Config::default());
+    let mut handle = client::connect_stream(config, stream, handler)
+        .await
+        .map_err(other_error)?;
+
+    let authenticated = match &opts.key_file {
+        Some(path) => {
+            let path = path.clone();
+            let key = spawn_blocking(move || russh_keys::load_secret_key(path, None))
+                .await?
+                .map_err(other_error)?;
+            handle
+                .authenticate_publickey(opts.user.as_ref(), Arc::new(key))
+                .await
+                .map_err(other_error)?
+        }
+        None => {
+            let mut agent = AgentClient::connect_env().await.map_err(other_error)?;
+            let keys = agent.request_identities().await.map_err(other_error)?;
+            let mut authenticated = false;
+            for key in keys {
+                let (returned, result) = handle
+                    .authenticate_future(opts.user.as_ref(), key, agent)
+                    .await;
+                agent = returned;
+                if result.map_err(other_error)? {
+                    authenticated = true;
+                    break;
+                }
+            }
+            authenticated
+        }
+    };
+    if !authenticated {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "SSH authentication failed",
+        ));
+    }
+    info!(user = %opts.user, "SSH session established");
+    Ok(Session {
+        handle,
+        local_addr,
+        peer_addr,
+    })
+}
+
+/// A `direct-tcpip` channel.
+pub struct SshChannel {
+    stream: Pin<Box<ChannelStream<Msg>>>,
+    local_addr: SocketAddr,
+    peer_addr: SocketAddr,
+}
+
+impl SshChannel {
+    /// Local address of the SSH session.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Address of the SSH server.
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer_addr
+    }
+}
+
+impl fmt::Debug for SshChannel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SshChannel")
+            .field("local_addr", &self.local_addr)
+            .field("peer_addr", &self.peer_addr)
+            .finish()
+    }
+}
+
+impl AsyncRead for SshChannel {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        self.get_mut().stream.as_mut().poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for SshChannel {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.get_mut().stream.as_mut().poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().stream.as_mut().poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().stream.as_mut().poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,239 @@
+use std::{
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    task::{Context, Poll},
+};
+#[cfg(any(feature = "tls", feature = "ssh"))]
+use tokio::io::AsyncReadExt;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::TcpStream,
+};
+#[cfg(feature = "tls")]
+use tokio_rustls::client::TlsStream;
+
+#[cfg(feature = "shadowsocks")]
+use super::shadowsocks::AeadStream;
+#[cfg(feature = "ssh")]
+use super::ssh::SshChannel;
+
+/// Connection to upstream proxy server (or destination if direct).
+#[derive(Debug)]
+pub enum ServerStream {
+    Tcp(TcpStream),
+    #[cfg(feature = "tls")]
+    /// TLS over TCP, or over a tunnel if chained.
+    Tls(Box<Peekable<TlsStream<ServerStream>>>),
+    /// Shadowsocks over TCP, TLS or a tunnel.
+    #[cfg(feature = "shadowsocks")]
+    Shadowsocks(Box<AeadStream<ServerStream>>),
+    /// `direct-tcpip` channel on a SSH session.
+    #[cfg(feature = "ssh")]
+    Ssh(Box<Peekable<SshChannel>>),
+}
+
+impl ServerStream {
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            Self::Tcp(stream) => stream.local_addr(),
+            #[cfg(feature = "tls")]
+            Self::Tls(stream) => stream.get_ref().get_ref().0.local_addr(),
+            #[cfg(feature = "shadowsocks")]
+            Self::Shadowsocks(stream) => stream.get_ref().local_addr(),
+            #[cfg(feature = "ssh")]
+            Self::Ssh(stream) => Ok(stream.get_ref().local_addr()),
+        }
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            Self::Tcp(stream) => stream.peer_addr(),
+            #[cfg(feature = "tls")]
+            Self::Tls(stream) => stream.get_ref().get_ref().0.peer_addr(),
+            #[cfg(feature = "shadowsocks")]
+            Self::Shadowsocks(stream) => stream.get_ref().peer_addr(),
+            #[cfg(feature = "ssh")]
+            Self::Ssh(stream) => Ok(stream.get_ref().peer_addr()),
+        }
+    }
+
+    /// Receive data without removing it, like `TcpStream::peek()`.
+    /// Return 0 if the peer closed.
+    pub async fn peek(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Self::Tcp(stream) => stream.peek(buf).await,
+            #[cfg(feature = "tls")]
+            Self::Tls(stream) => stream.peek(buf).await,
+            #[cfg(feature = "shadowsocks")]
+            Self::Shadowsocks(stream) => stream.peek(buf).await,
+            #[cfg(feature = "ssh")]
+            Self::Ssh(stream) => stream.peek(buf).await,
+        }
+    }
+}
+
+impl From<TcpStream> for ServerStream {
+    fn from(stream: TcpStream) -> Self {
+        Self::Tcp(stream)
+    }
+}
+
+#[cfg(feature = "tls")]
+impl From<TlsStream<ServerStream>> for ServerStream {
+    fn from(stream: TlsStream<ServerStream>) -> Self {
+        Self::Tls(Box::new(stream.into()))
+    }
+}
+
+#[cfg(feature = "shadowsocks")]
+impl From<AeadStream<ServerStream>> for ServerStream {
+    fn from(stream: AeadStream<ServerStream>) -> Self {
+        Self::Shadowsocks(Box::new(stream))
+    }
+}
+
+#[cfg(feature = "ssh")]
+impl From<SshChannel> for ServerStream {
+    fn from(stream: SshChannel) -> Self {
+        Self::Ssh(Box::new(stream.into()))
+    }
+}
+
+macro_rules! delegate {
+    ($self:ident, $stream:ident => $expr:expr) => {
+        match $self.get_mut() {
+            ServerStream::Tcp($stream) => $expr,
+            #[cfg(feature = "tls")]
+            ServerStream::Tls($stream) => $expr,
+            #[cfg(feature = "shadowsocks")]
+            ServerStream::Shadowsocks($stream) => $expr,
+            #[cfg(feature = "ssh")]
+            ServerStream::Ssh($stream) => $expr,
+        }
+    };
+}
+
+impl AsyncRead for ServerStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        delegate!(self, stream => Pin::new(stream).poll_read(cx, buf))
+    }
+}
+
+impl AsyncWrite for ServerStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        delegate!(self, stream => Pin::new(stream).poll_write(cx, buf))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        delegate!(self, stream => Pin::new(stream).poll_flush(cx))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        delegate!(self, stream => Pin::new(stream).poll_shutdown(cx))
+    }
+}
+
+/// Stream without native `peek()`, data peeked is buffered until read.
+#[cfg(any(feature = "tls", feature = "ssh"))]
+#[derive(Debug)]
+pub struct Peekable<S> {
+    stream: S,
+    peeked: Vec<u8>,
+}
+
+#[cfg(any(feature = "tls", feature = "ssh"))]
+impl<S> From<S> for Peekable<S> {
+    fn from(stream: S) -> Self {
+        Self {
+            stream,
+            peeked: Vec::new(),
+        }
+    }
+}
+
+#[cfg(any(feature = "tls", feature = "ssh"))]
+impl<S: AsyncRead + AsyncWrite + Unpin> Peekable<S> {
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    async fn peek(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.peeked.is_empty() {
+            let mut tmp = vec![0u8; buf.len()];
+            let n = self.stream.read(&mut tmp).await?;
+            self.peeked.extend_from_slice(&tmp[..n]);
+        }
+        let n = self.peeked.len().min(buf.len());
+        buf[..n].copy_from_slice(&self.peeked[..n]);
+        Ok(n)
+    }
+}
+
+#[cfg(any(feature = "tls", feature = "ssh"))]
+impl<S: AsyncRead + Unpin> AsyncRead for Peekable<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.peeked.is_empty() {
+            return Pin::new(&mut this.stream).poll_read(cx, buf);
+        }
+        let n = this.peeked.len().min(buf.remaining());
+        buf.put_slice(&this.peeked[..n]);
+        this.peeked.drain(..n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(any(feature = "tls", feature = "ssh"))]
+impl<S: AsyncWrite + Unpin> AsyncWrite for Peekable<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_shutdown(cx)
+    }
+}
+
+#[cfg(any(feature = "tls", feature = "ssh"))]
+#[tokio::test]
+async fn test_peekable() {
+    use tokio::io::{duplex, AsyncWriteExt};
+
+    let (mut remote, local) = duplex(64);
+    let mut stream = Peekable::from(local);
+    remote.write_all(b"hello world").await.unwrap();
+    drop(remote);
+    let mut buf = [0u8; 5];
+    assert_eq!(5, stream.peek(&mut buf).await.unwrap());
+    assert_eq!(b"hello", &buf);
+    // Peek again without consuming anything
+    let mut buf = [0u8; 3];
+    assert_eq!(3, stream.peek(&mut buf).await.unwrap());
+    assert_eq!(b"hel", &buf);
+    // Read the peeked first, then the rest
+    let mut data = Vec::new();
+    stream.read_to_end(&mut data).await.unwrap();
+    assert_eq!(b"hello world", &data[..]);
+    assert_eq!(0, stream.peek(&mut buf).await.unwrap());
+}
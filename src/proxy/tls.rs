@@ -0,0 +1,173 @@
+use flexstr::SharedStr;
+use rustls::{
+    client::{ServerCertVerified, ServerCertVerifier},
+    Certificate, ClientConfig, OwnedTrustAnchor, PrivateKey, RootCertStore, ServerName,
+};
+use rustls_pemfile::Item;
+use serde::Serialize;
+use std::{
+    fmt,
+    fs::File,
+    io::{self, BufReader, ErrorKind},
+    net::IpAddr,
+    path::Path,
+    sync::Arc,
+    time::SystemTime,
+};
+use tokio_rustls::TlsConnector;
+use tracing::{debug, instrument};
+
+use super::stream::ServerStream;
+
+/// TLS transport to upstream proxy server, i.e. HTTPS proxy or
+/// SOCKSv5-over-TLS.
+#[derive(Clone, Serialize)]
+pub struct TlsConfig {
+    /// Name for SNI and certificate verification.
+    pub server_name: SharedStr,
+    pub skip_verify: bool,
+    #[serde(skip)]
+    name: ServerName,
+    #[serde(skip)]
+    connector: TlsConnector,
+}
+
+impl fmt::Debug for TlsConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TlsConfig")
+            .field("server_name", &self.server_name)
+            .field("skip_verify", &self.skip_verify)
+            .finish()
+    }
+}
+
+fn invalid_input<E: Into<Box<dyn std::error::Error + Send + Sync>>>(err: E) -> io::Error {
+    io::Error::new(ErrorKind::InvalidInput, err)
+}
+
+impl TlsConfig {
+    /// Server's certificate is verified against `ca_file` (PEM), or
+    /// built-in Mozilla's root certificates if not given.
+    /// `client_cert` is (certificate chain, private key) in PEM files.
+    /// `server_name` must be a domain name, IP address is not supported.
+    pub fn new(
+        server_name: &str,
+        ca_file: Option<&Path>,
+        client_cert: Option<(&Path, &Path)>,
+        skip_verify: bool,
+    ) -> io::Result<Self> {
+        if server_name.parse::<IpAddr>().is_ok() {
+            return Err(invalid_input("IP address is not supported as server name"));
+        }
+        let name = ServerName::try_from(server_name).map_err(invalid_input)?;
+        let mut roots = RootCertStore::empty();
+        match ca_file {
+            Some(path) => {
+                for cert in rustls_pemfile::certs(&mut BufReader::new(File::open(path)?))? {
+                    roots
+                        .add(&Certificate(cert))
+                        .map_err(|err| invalid_input(format!("bad CA certificate: {:?}", err)))?;
+                }
+                if roots.is_empty() {
+                    return Err(invalid_input("no certificate found in CA file"));
+                }
+            }
+            None => {
+                roots.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|ta| {
+                    OwnedTrustAnchor::from_subject_spki_name_constraints(
+                        ta.subject,
+                        ta.spki,
+                        ta.name_constraints,
+                    )
+                }));
+            }
+        }
+        let builder = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots);
+        let mut config = match client_cert {
+            None => builder.with_no_client_auth(),
+            Some((cert, key)) => {
+                let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(cert)?))?;
+                builder
+                    .with_single_cert(
+                        certs.into_iter().map(Certificate).collect(),
+                        load_private_key(key)?,
+                    )
+                    .map_err(invalid_input)?
+            }
+        };
+        if skip_verify {
+            config
+                .dangerous()
+                .set_certificate_verifier(Arc::new(NoVerification));
+        }
+        Ok(Self {
+            server_name: server_name.into(),
+            skip_verify,
+            name,
+            connector: Arc::new(config).into(),
+        })
+    }
+
+    /// Do TLS handshake on `stream`.
+    #[instrument(name = "tls_handshake", skip_all)]
+    pub async fn connect(&self, stream: ServerStream) -> io::Result<ServerStream> {
+        let stream = self.connector.connect(self.name.clone(), stream).await?;
+        debug!(sni = %self.server_name, "TLS established");
+        Ok(stream.into())
+    }
+}
+
+/// Load the first private key (PKCS #8, PKCS #1 or SEC1) in a PEM file.
+fn load_private_key(path: &Path) -> io::Result<PrivateKey> {
+    let mut reader = BufReader::new(File::open(path)?);
+    while let Some(item) = rustls_pemfile::read_one(&mut reader)? {
+        match item {
+            Item::PKCS8Key(key) | Item::RSAKey(key) | Item::ECKey(key) => {
+                return Ok(PrivateKey(key))
+            }
+            _ => continue,
+        }
+    }
+    Err(invalid_input("no private key found"))
+}
+
+/// Accept any server certificate.
+struct NoVerification;
+
+impl ServerCertVerifier for NoVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+#[test]
+fn test_tls_config_errors() {
+    let err = |result: io::Result<TlsConfig>| result.unwrap_err().to_string();
+    assert!(TlsConfig::new("example.com", None, None, false).is_ok());
+    assert!(err(TlsConfig::new("192.0.2.1", None, None, false)).contains("IP address"));
+    assert!(TlsConfig::new("::1", None, None, true).is_err());
+    assert!(TlsConfig::new("bad name!", None, None, false).is_err());
+    let missing = Path::new("/nonexistent/ca.pem");
+    let not_found = TlsConfig::new("example.com", Some(missing), None, false).unwrap_err();
+    assert_eq!(ErrorKind::NotFound, not_found.kind());
+    let empty = Path::new("/dev/null");
+    assert!(err(TlsConfig::new("example.com", Some(empty), None, false))
+        .contains("no certificate found"));
+    assert!(err(TlsConfig::new(
+        "example.com",
+        None,
+        Some((empty, empty)),
+        false
+    ))
+    .contains("no private key found"));
+}
@@ -1,22 +1,54 @@
 use anyhow::{anyhow, bail, Context};
-use futures_util::{stream, StreamExt};
+use futures_util::{
+    future::join_all,
+    stream::{self, BoxStream},
+    StreamExt,
+};
 use ini::Ini;
+#[cfg(target_os = "linux")]
+use moproxy::client::serve_tproxy_udp;
 use parking_lot::RwLock;
-use std::{collections::HashSet, io, net::SocketAddr, net::ToSocketAddrs, path::PathBuf, sync::Arc, time::Duration};
-use tokio::net::{TcpListener, TcpStream};
-use tracing::{error, info, instrument, warn};
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tokio::net::{TcpListener, UdpSocket};
+use tracing::{error, field, info, instrument, warn, Span};
 
-use crate::{cli::CliArgs, FromOptionStr};
+use crate::{
+    cli::{parse_fwmark, CliArgs, PortOrPath},
+    FromOptionStr,
+};
+#[cfg(unix)]
+use moproxy::futures_stream::{AutoRemoveFile, UnixListenerStream};
+#[cfg(all(feature = "systemd", target_os = "linux"))]
+use moproxy::linux::systemd::{self, ActivatedListener};
+#[cfg(feature = "ssh")]
+use moproxy::proxy::ssh::SshOptions;
+#[cfg(feature = "tls")]
+use moproxy::proxy::tls::TlsConfig;
 #[cfg(feature = "web_console")]
 use moproxy::web::WebServer;
 use moproxy::{
-    client::{FailedClient, NewClient},
+    client::{users::UserList, ClientStream, Command, FailedClient, NewClient, UdpRoute},
+    dns::{
+        fakeip::{serve_fake_dns, FakeIpPool},
+        snoop::{serve_dns_tcp, serve_dns_udp, DnsCache},
+    },
     futures_stream::TcpListenerStream,
     monitor::Monitor,
-    policy::{parser, ActionType, Policy},
-    proxy::{ProxyProto, ProxyServer, UserPassAuthCredential},
+    policy::{parser, ActionType, Policy, RequestFeatures},
+    proxy::{BindOptions, ProxyProto, ProxyServer, UserPassAuthCredential},
     web::WebServerListener,
 };
+#[cfg(feature = "tls")]
+use std::path::Path;
 
 #[derive(Clone)]
 pub(crate) struct MoProxy {
@@ -25,17 +57,44 @@ pub(crate) struct MoProxy {
     pub(crate) monitor: Monitor,
     direct_server: Arc<ProxyServer>,
     pub(crate) policy: Arc<RwLock<Policy>>,
+    users: Arc<RwLock<Option<Arc<UserList>>>>,
+    /// Kept across reloads, so are the domains mapped.
+    fake_ip: Option<Arc<FakeIpPool>>,
+    /// Also kept across reloads.
+    dns_cache: Option<Arc<DnsCache>>,
     #[cfg(feature = "web_console")]
     web_server: Option<WebServer>,
 }
 
+type ClientStreams = BoxStream<'static, io::Result<ClientStream>>;
+
 pub(crate) struct MoProxyListener {
     moproxy: MoProxy,
-    listeners: Vec<TcpListenerStream>,
+    listeners: Vec<(ClientStreams, ListenMode)>,
+    /// Unix sockets to remove on exit.
+    #[cfg(unix)]
+    _unix_files: Vec<AutoRemoveFile>,
+    #[cfg(target_os = "linux")]
+    tproxy_udp_sockets: Vec<UdpSocket>,
+    fake_dns_socket: Option<UdpSocket>,
+    dns_snoop_sockets: Option<(UdpSocket, TcpListener)>,
     #[cfg(feature = "web_console")]
     web_server: Option<WebServerListener>,
 }
 
+/// How destination is retrieved from connections on a listener.
+#[derive(Debug, Clone, Copy)]
+enum ListenMode {
+    /// NAT (`REDIRECT`), SOCKS or HTTP proxy. Only the latter two on Unix
+    /// socket.
+    Normal,
+    /// PROXY protocol header is required, destination is retrieved from it.
+    ProxyProtocol,
+    /// TPROXY, with the listening port.
+    #[cfg(target_os = "linux")]
+    Tproxy(u16),
+}
+
 #[derive(Debug)]
 enum PolicyResult {
     Filtered(Vec<Arc<ProxyServer>>),
@@ -48,7 +107,11 @@ impl MoProxy {
         // Load proxy server list
         let server_list_config = ServerListConfig::new(&args);
         let servers = server_list_config.load().context("fail to load servers")?;
-        let direct_server = Arc::new(ProxyServer::direct(args.max_wait));
+        let direct_server = Arc::new(
+            ProxyServer::direct(args.max_wait)
+                .with_proxy_protocol(args.direct_proxy_protocol)
+                .with_bind(server_list_config.default_bind.clone()),
+        );
 
         // Load policy
         let policy = {
@@ -60,6 +123,29 @@ impl MoProxy {
             }
         };
 
+        // Load SOCKSv5 users
+        let users = match &args.socks_users {
+            Some(path) => {
+                let users = UserList::load_from_file(path).context("cannot load users")?;
+                Some(Arc::new(users))
+            }
+            None => None,
+        };
+
+        // Setup fake-IP DNS
+        let fake_ip = args.fake_dns_bind.map(|_| {
+            Arc::new(FakeIpPool::new(
+                Some(args.fake_ip_pool),
+                args.fake_ipv6_pool,
+                args.fake_ip_capacity,
+            ))
+        });
+
+        // Setup DNS snooping
+        let dns_cache = args
+            .dns_snoop_bind
+            .map(|_| Arc::new(DnsCache::new(args.dns_cache_capacity)));
+
         // Setup proxy monitor
         let graphite = args.graphite;
         #[cfg(feature = "score_script")]
@@ -84,9 +170,13 @@ impl MoProxy {
         };
 
         // Launch monitor
+        monitor.resolve_servers().await;
         if args.probe_secs > 0 {
             tokio::spawn(monitor.clone().monitor_delay(args.probe_secs));
         }
+        if args.resolve_secs > 0 {
+            tokio::spawn(monitor.clone().monitor_resolve(args.resolve_secs));
+        }
 
         Ok(Self {
             cli_args: Arc::new(args),
@@ -94,12 +184,15 @@ impl MoProxy {
             direct_server,
             monitor,
             policy,
+            users: Arc::new(RwLock::new(users)),
+            fake_ip,
+            dns_cache,
             #[cfg(feature = "web_console")]
             web_server,
         })
     }
 
-    pub(crate) fn reload(&self) -> anyhow::Result<()> {
+    pub(crate) async fn reload(&self) -> anyhow::Result<()> {
         // Load proxy server list
         let servers = self.server_list_config.load()?;
         // Load policy
@@ -107,52 +200,197 @@ impl MoProxy {
             Some(path) => Policy::load_from_file(path).context("cannot to load policy")?,
             _ => Default::default(),
         };
+        // Load SOCKSv5 users
+        let users = match &self.cli_args.socks_users {
+            Some(path) => Some(UserList::load_from_file(path).context("cannot load users")?),
+            None => None,
+        };
         // TODO: reload lua script
 
+        // Resolve host names before they're put into use
+        join_all(servers.iter().map(|server| server.resolve())).await;
+
         // Apply only if no error occur
         self.monitor.update_servers(servers);
         *self.policy.write() = policy;
+        *self.users.write() = users.map(Arc::new);
         Ok(())
     }
 
+    async fn bind_tcp(&self, port: u16, mode: ListenMode) -> anyhow::Result<TcpListener> {
+        let addr = SocketAddr::new(self.cli_args.host, port);
+        let listener = TcpListener::bind(&addr)
+            .await
+            .context("cannot bind to port")?;
+        match mode {
+            ListenMode::ProxyProtocol => info!("listen on {} (PROXY protocol)", addr),
+            _ => info!("listen on {}", addr),
+        }
+        #[cfg(target_os = "linux")]
+        if let Some(ref alg) = self.cli_args.cong_local {
+            use moproxy::linux::tcp::TcpListenerExt;
+
+            info!("set {} on {}", alg, addr);
+            listener.set_congestion(alg).expect(
+                "fail to set tcp congestion algorithm. \
+                check tcp_allowed_congestion_control?",
+            );
+        }
+        Ok(listener)
+    }
+
     pub(crate) async fn listen(&self) -> anyhow::Result<MoProxyListener> {
-        let ports: HashSet<_> = self.cli_args.port.iter().collect();
-        let mut listeners = Vec::with_capacity(ports.len());
-        for port in ports {
+        let normal_ports: HashSet<_> = self.cli_args.port.iter().collect();
+        let proxy_protocol_ports: HashSet<_> = self.cli_args.proxy_protocol_port.iter().collect();
+        if let Some(port) = proxy_protocol_ports
+            .iter()
+            .find(|port| normal_ports.contains(&PortOrPath::Port(***port)))
+        {
+            bail!("port {} cannot be both w/ and w/o PROXY protocol", port);
+        }
+        let mut listeners: Vec<(ClientStreams, ListenMode)> = Vec::new();
+        #[cfg(unix)]
+        let mut unix_files = vec![];
+        for port in normal_ports {
+            match port {
+                PortOrPath::Port(port) => {
+                    let listener = self.bind_tcp(*port, ListenMode::Normal).await?;
+                    let stream = TcpListenerStream(listener).map(|s| s.map(ClientStream::from));
+                    listeners.push((stream.boxed(), ListenMode::Normal));
+                }
+                #[cfg(unix)]
+                PortOrPath::Path(path) => {
+                    let listener = UnixListener::bind(path).context("cannot bind to path")?;
+                    // Remove it on exit, only if it's ours
+                    let file = AutoRemoveFile(path.to_string_lossy().as_ref().into());
+                    info!("listen on unix:{}", path.display());
+                    let stream = UnixListenerStream(listener).map(|s| s.map(ClientStream::from));
+                    listeners.push((stream.boxed(), ListenMode::Normal));
+                    unix_files.push(file);
+                }
+            }
+        }
+        for port in proxy_protocol_ports {
+            let listener = self.bind_tcp(*port, ListenMode::ProxyProtocol).await?;
+            let stream = TcpListenerStream(listener).map(|s| s.map(ClientStream::from));
+            listeners.push((stream.boxed(), ListenMode::ProxyProtocol));
+        }
+
+        #[cfg(target_os = "linux")]
+        let mut tproxy_udp_sockets = vec![];
+        #[cfg(target_os = "linux")]
+        for port in self.cli_args.tproxy_port.iter().collect::<HashSet<_>>() {
+            use moproxy::linux::tproxy;
+
             let addr = SocketAddr::new(self.cli_args.host, *port);
-            let listener = TcpListener::bind(&addr)
-                .await
-                .context("cannot bind to port")?;
-            info!("listen on {}", addr);
-            #[cfg(target_os = "linux")]
-            if let Some(ref alg) = self.cli_args.cong_local {
-                use moproxy::linux::tcp::TcpListenerExt;
+            let listener = tproxy::bind_tcp_listener(addr)
+                .context("cannot bind to port for TPROXY, need CAP_NET_ADMIN")?;
+            let socket = tproxy::bind_udp(addr)
+                .context("cannot bind to UDP port for TPROXY, need CAP_NET_ADMIN")?;
+            info!("listen on {} (TPROXY)", addr);
+            let stream = TcpListenerStream(listener).map(|s| s.map(ClientStream::from));
+            listeners.push((stream.boxed(), ListenMode::Tproxy(*port)));
+            tproxy_udp_sockets.push(socket);
+        }
 
-                info!("set {} on {}", alg, addr);
-                listener.set_congestion(alg).expect(
-                    "fail to set tcp congestion algorithm. \
-                    check tcp_allowed_congestion_control?",
-                );
-            }
-            listeners.push(TcpListenerStream(listener));
+        // Sockets passed by systemd (socket activation), mapped by their names
+        #[cfg(all(feature = "web_console", feature = "systemd", target_os = "linux"))]
+        let mut activated_web = None;
+        #[cfg(all(feature = "systemd", target_os = "linux"))]
+        for (name, listener) in
+            systemd::take_listen_fds().context("cannot take sockets passed by systemd")?
+        {
+            let mode = match name.as_str() {
+                #[cfg(feature = "web_console")]
+                "stats" => {
+                    activated_web = Some(listener);
+                    continue;
+                }
+                #[cfg(not(feature = "web_console"))]
+                "stats" => {
+                    warn!("web console is not enabled, ignore socket \"stats\"");
+                    continue;
+                }
+                "proxy-protocol" => ListenMode::ProxyProtocol,
+                _ => ListenMode::Normal,
+            };
+            info!("listen on socket \"{}\" passed by systemd", name);
+            let stream = match listener {
+                ActivatedListener::Tcp(listener) => TcpListenerStream(listener)
+                    .map(|s| s.map(ClientStream::from))
+                    .boxed(),
+                ActivatedListener::Unix(listener) => UnixListenerStream(listener)
+                    .map(|s| s.map(ClientStream::from))
+                    .boxed(),
+            };
+            listeners.push((stream, mode));
+        }
+        if listeners.is_empty() {
+            bail!("no port to listen on, see --port");
         }
+
+        let fake_dns_socket = match self.cli_args.fake_dns_bind {
+            Some(addr) => {
+                let socket = UdpSocket::bind(addr)
+                    .await
+                    .context("cannot bind to address for fake-IP DNS")?;
+                info!("fake-IP DNS listen on {}", addr);
+                Some(socket)
+            }
+            None => None,
+        };
+        let dns_snoop_sockets = match self.cli_args.dns_snoop_bind {
+            Some(addr) => {
+                let socket = UdpSocket::bind(addr)
+                    .await
+                    .context("cannot bind to UDP address for DNS snooping")?;
+                let listener = TcpListener::bind(addr)
+                    .await
+                    .context("cannot bind to TCP address for DNS snooping")?;
+                info!("DNS forwarder listen on {}", addr);
+                Some((socket, listener))
+            }
+            None => None,
+        };
+
+        #[cfg(all(feature = "web_console", feature = "systemd", target_os = "linux"))]
+        let web_server = match activated_web {
+            Some(ActivatedListener::Tcp(listener)) => {
+                Some(WebServerListener::from_tcp(self.monitor.clone(), listener))
+            }
+            Some(ActivatedListener::Unix(listener)) => {
+                Some(WebServerListener::from_unix(self.monitor.clone(), listener))
+            }
+            None => None,
+        };
+        #[cfg(all(
+            feature = "web_console",
+            not(all(feature = "systemd", target_os = "linux"))
+        ))]
+        let web_server = None;
         #[cfg(feature = "web_console")]
-        let web_server = if let Some(web) = &self.web_server {
-            Some(web.listen().await?)
-        } else {
-            None
+        let web_server = match (web_server, &self.web_server) {
+            (Some(activated), _) => Some(activated),
+            (None, Some(web)) => Some(web.listen().await?),
+            (None, None) => None,
         };
 
         Ok(MoProxyListener {
             moproxy: self.clone(),
             listeners,
+            #[cfg(unix)]
+            _unix_files: unix_files,
+            #[cfg(target_os = "linux")]
+            tproxy_udp_sockets,
+            fake_dns_socket,
+            dns_snoop_sockets,
             #[cfg(feature = "web_console")]
             web_server,
         })
     }
 
-    fn apply_policy(&self, client: &NewClient) -> PolicyResult {
-        let action = self.policy.read().matches(&client.features());
+    fn apply_policy<S: AsRef<str>>(&self, features: &RequestFeatures<S>) -> PolicyResult {
+        let action = self.policy.read().matches(features);
         match action.action {
             ActionType::Reject => PolicyResult::Reject,
             ActionType::Direct => PolicyResult::Direct,
@@ -168,21 +406,79 @@ impl MoProxy {
         }
     }
 
-    #[instrument(level = "error", skip_all, fields(on_port=sock.local_addr()?.port(), peer=?sock.peer_addr()?))]
-    async fn handle_client(&self, sock: TcpStream) -> io::Result<()> {
-        let mut client = NewClient::from_socket(sock).await?;
+    fn udp_route<S: AsRef<str>>(&self, features: &RequestFeatures<S>) -> UdpRoute {
+        match self.apply_policy(features) {
+            PolicyResult::Reject => UdpRoute::Reject,
+            PolicyResult::Direct => UdpRoute::Direct(self.direct_server.clone()),
+            PolicyResult::Filtered(servers) => {
+                let servers: Vec<_> = servers
+                    .into_iter()
+                    .filter(|s| s.proto.support_udp())
+                    .collect();
+                if servers.is_empty() && self.cli_args.allow_direct {
+                    UdpRoute::Direct(self.direct_server.clone())
+                } else {
+                    UdpRoute::Proxy(servers)
+                }
+            }
+        }
+    }
+
+    #[instrument(level = "error", skip_all, fields(on_port=field::Empty, peer=%sock.peer_name(), src=field::Empty, user=field::Empty))]
+    async fn handle_client(&self, sock: ClientStream, mode: ListenMode) -> io::Result<()> {
+        if let Some(port) = sock.local_port() {
+            Span::current().record("on_port", port);
+        }
+        let mut client = match mode {
+            ListenMode::Normal => {
+                let users = self.users.read().clone();
+                NewClient::from_socket(sock, users.as_deref()).await?
+            }
+            ListenMode::ProxyProtocol => NewClient::from_proxy_protocol_socket(sock).await?,
+            #[cfg(target_os = "linux")]
+            ListenMode::Tproxy(port) => NewClient::from_tproxy_socket(sock, port)?,
+        };
         let args = &self.cli_args;
 
-        if (args.remote_dns || args.n_parallel > 1) && client.dest.port == 443 {
-            // Try parse TLS client hello
-            client.retrieve_dest_from_sni().await?;
+        if let Some(pool) = &self.fake_ip {
+            client.translate_fake_ip(pool);
+        }
+        if let Some(cache) = &self.dns_cache {
+            client.lookup_dns_cache(cache);
+        }
+        if let Some(src) = client.src_addr {
+            Span::current().record("src", field::debug(src));
+        }
+        if let Some(username) = client.username.clone() {
+            Span::current().record("user", username.as_str());
+            client.count_traffic_into(self.monitor.user_traffic(&username));
+        }
+
+        if client.command == Command::UdpAssociate {
+            return client
+                .serve_udp_associate(|features| self.udp_route(features))
+                .await;
+        }
+
+        let need_sniff = {
+            let policy = self.policy.read();
+            client.should_sniff(
+                args.remote_dns || args.n_parallel > 1 || policy.has_dst_domain_rules(),
+                policy.has_sniff_rules(),
+            )
+        };
+        if need_sniff && args.is_sniff_port(client.dest.port) {
+            // Try parse TLS client hello or HTTP request
+            client.sniff_dest().await?;
             if args.remote_dns {
-                client.override_dest_with_sni();
+                client.override_dest_with_sniffed();
             }
         }
-        let result = match self.apply_policy(&client) {
+        let result = match self.apply_policy(&client.features()) {
             PolicyResult::Reject => {
                 info!("rejected by policy");
+                let err = io::Error::new(io::ErrorKind::PermissionDenied, "rejected by policy");
+                client.reply_err(&err).await;
                 return Ok(());
             }
             PolicyResult::Direct => client
@@ -190,15 +486,21 @@ impl MoProxy {
                 .await
                 .map_err(|err| err.into()),
             PolicyResult::Filtered(proxies) => {
-                client.connect_server(proxies, args.n_parallel).await
+                client
+                    .connect_server(proxies, args.n_parallel, args.max_retries, args.hedge)
+                    .await
             }
         };
         let client = match result {
             Ok(client) => client,
-            Err(FailedClient::Recoverable(client)) if args.allow_direct => {
+            Err(FailedClient::Recoverable(client, _)) if args.allow_direct => {
                 client.direct_connect(self.direct_server.clone()).await?
             }
-            Err(_) => return Ok(()),
+            Err(FailedClient::Recoverable(mut client, err)) => {
+                client.reply_err(&err).await;
+                return Ok(());
+            }
+            Err(FailedClient::Unrecoverable(_)) => return Ok(()),
         };
         client.serve().await
     }
@@ -211,13 +513,58 @@ impl MoProxyListener {
             web.run_background()
         }
 
-        let mut clients = stream::select_all(self.listeners.iter_mut());
-        while let Some(sock) = clients.next().await {
+        #[cfg(target_os = "linux")]
+        for socket in self.tproxy_udp_sockets {
+            let moproxy = self.moproxy.clone();
+            tokio::spawn(async move {
+                let fake_ip = moproxy.fake_ip.clone();
+                let result =
+                    serve_tproxy_udp(socket, fake_ip, |features| moproxy.udp_route(features)).await;
+                if let Err(err) = result {
+                    error!("error on serve TPROXY UDP: {}", err);
+                }
+            });
+        }
+
+        if let (Some(socket), Some(pool)) = (self.fake_dns_socket, &self.moproxy.fake_ip) {
+            let pool = pool.clone();
+            tokio::spawn(async move {
+                if let Err(err) = serve_fake_dns(socket, pool).await {
+                    error!("error on serve fake-IP DNS: {}", err);
+                }
+            });
+        }
+
+        if let (Some((socket, listener)), Some(cache), Some(upstream)) = (
+            self.dns_snoop_sockets,
+            &self.moproxy.dns_cache,
+            self.moproxy.cli_args.dns_upstream,
+        ) {
+            let bind = self.moproxy.direct_server.bind();
+            let (udp_cache, udp_bind) = (cache.clone(), bind.clone());
+            tokio::spawn(async move {
+                if let Err(err) = serve_dns_udp(socket, upstream, udp_cache, udp_bind).await {
+                    error!("error on serve DNS forwarder (UDP): {}", err);
+                }
+            });
+            let cache = cache.clone();
+            tokio::spawn(async move {
+                if let Err(err) = serve_dns_tcp(listener, upstream, cache, bind).await {
+                    error!("error on serve DNS forwarder (TCP): {}", err);
+                }
+            });
+        }
+
+        let mut clients = stream::select_all(self.listeners.iter_mut().map(|(listener, mode)| {
+            let mode = *mode;
+            listener.map(move |sock| (sock, mode))
+        }));
+        while let Some((sock, mode)) = clients.next().await {
             let moproxy = self.moproxy.clone();
             match sock {
                 Ok(sock) => {
                     tokio::spawn(async move {
-                        if let Err(e) = moproxy.handle_client(sock).await {
+                        if let Err(e) = moproxy.handle_client(sock, mode).await {
                             info!("error on hanle client: {}", e);
                         }
                     });
@@ -231,6 +578,7 @@ impl MoProxyListener {
 struct ServerListConfig {
     default_test_dns: SocketAddr,
     default_max_wait: Duration,
+    default_bind: BindOptions,
     cli_servers: Vec<Arc<ProxyServer>>,
     path: Option<PathBuf>,
     allow_direct: bool,
@@ -240,36 +588,54 @@ impl ServerListConfig {
     fn new(args: &CliArgs) -> Self {
         let default_test_dns = args.test_dns;
         let default_max_wait = args.max_wait;
+        #[cfg(target_os = "linux")]
+        let default_bind = BindOptions {
+            address: args.bind_address,
+            interface: args.bind_interface.as_deref().map(Into::into),
+            fwmark: args.fwmark,
+        };
+        #[cfg(not(target_os = "linux"))]
+        let default_bind = BindOptions {
+            address: args.bind_address,
+            ..Default::default()
+        };
 
         let mut cli_servers = vec![];
         for addr in &args.socks5_servers {
-            cli_servers.push(Arc::new(ProxyServer::new(
-                *addr,
-                ProxyProto::socks5(false),
-                default_test_dns,
-                default_max_wait,
-                None,
-                None,
-                None,
-            )));
+            cli_servers.push(Arc::new(
+                ProxyServer::new(
+                    *addr,
+                    ProxyProto::socks5(false),
+                    default_test_dns,
+                    default_max_wait,
+                    None,
+                    None,
+                    None,
+                )
+                .with_bind(default_bind.clone()),
+            ));
         }
 
         for addr in &args.http_servers {
-            cli_servers.push(Arc::new(ProxyServer::new(
-                *addr,
-                ProxyProto::http(false, None),
-                default_test_dns,
-                default_max_wait,
-                None,
-                None,
-                None,
-            )));
+            cli_servers.push(Arc::new(
+                ProxyServer::new(
+                    *addr,
+                    ProxyProto::http(false, None),
+                    default_test_dns,
+                    default_max_wait,
+                    None,
+                    None,
+                    None,
+                )
+                .with_bind(default_bind.clone()),
+            ));
         }
 
         let path = args.server_list.clone();
         Self {
             default_test_dns,
             default_max_wait,
+            default_bind,
             cli_servers,
             path,
             allow_direct: args.allow_direct,
@@ -281,14 +647,28 @@ impl ServerListConfig {
         let mut servers = self.cli_servers.clone();
         if let Some(path) = &self.path {
             let ini = Ini::load_from_file(path).context("cannot read server list file")?;
+            let mut loaded = Vec::new();
+            // (server, tag of server it goes via)
+            let mut chained = Vec::new();
             for (tag, props) in ini.iter() {
                 let tag = props.get("tag").or(tag);
-                let addr: SocketAddr = props
+                let address = props
                     .get("address")
-                    .ok_or(anyhow!("address not specified"))?
-                    .to_socket_addrs()
-                    .context("not a valid socket address")?
-                    .next().unwrap();
+                    .ok_or(anyhow!("address not specified"))?;
+                let (host, port) = address.rsplit_once(':').context("port not specified")?;
+                let port: u16 = port.parse().context("not a valid port number")?;
+                let host = host.trim_start_matches('[').trim_end_matches(']');
+                let via = props.get("via");
+                // Host name is resolved later without blocking, or by the
+                // via server if chained. Kept even if resolving fails.
+                let addrs: Vec<SocketAddr> = match address.parse() {
+                    Ok(addr) => vec![addr],
+                    Err(_) => vec![],
+                };
+                let addr = addrs
+                    .first()
+                    .copied()
+                    .unwrap_or_else(|| (Ipv4Addr::UNSPECIFIED, port).into());
                 let base = props
                     .get("score base")
                     .parse()
@@ -308,6 +688,30 @@ impl ServerListConfig {
                     // TODO: add a link to how-to --policy
                     error!("`listen ports` is not longer supported, use --policy instead");
                 }
+                let send_proxy_protocol = props
+                    .get("send proxy protocol")
+                    .parse()
+                    .context("not a boolean value")?
+                    .unwrap_or(false);
+                let bind = BindOptions {
+                    address: props
+                        .get("bind address")
+                        .parse()
+                        .context("not a valid IP address")?
+                        .or(self.default_bind.address),
+                    interface: props
+                        .get("bind interface")
+                        .map(Into::into)
+                        .or_else(|| self.default_bind.interface.clone()),
+                    fwmark: match props.get("fwmark") {
+                        Some(mark) => Some(parse_fwmark(mark).map_err(|err| anyhow!(err))?),
+                        None => self.default_bind.fwmark,
+                    },
+                };
+                #[cfg(not(target_os = "linux"))]
+                if bind.interface.is_some() || bind.fwmark.is_some() {
+                    bail!("bind interface & fwmark are only supported on Linux");
+                }
                 let (_, capabilities) =
                     parser::capabilities(props.get("capabilities").unwrap_or_default())
                         .map_err(|e| e.to_owned())
@@ -324,9 +728,14 @@ impl ServerListConfig {
                             .parse()
                             .context("not a boolean value")?
                             .unwrap_or(false);
+                        let udp = props
+                            .get("socks udp")
+                            .parse()
+                            .context("not a boolean value")?
+                            .unwrap_or(false);
                         let username = props.get("socks username").unwrap_or("");
                         let password = props.get("socks password").unwrap_or("");
-                        match (username.len(), password.len()) {
+                        let proto = match (username.len(), password.len()) {
                             (0, 0) => ProxyProto::socks5(fake_hs),
                             (0, _) | (_, 0) => bail!("socks username/password is empty"),
                             (u, p) if u > 255 || p > 255 => {
@@ -335,7 +744,40 @@ impl ServerListConfig {
                             _ => ProxyProto::socks5_with_auth(UserPassAuthCredential::new(
                                 username, password,
                             )),
+                        };
+                        proto.with_udp_associate(udp)
+                    }
+                    "socks4" | "socksv4" | "socks4a" => {
+                        let userid = props.get("socks userid");
+                        if userid.map_or(false, |id| id.contains('\0')) {
+                            bail!("NUL in socks userid")
                         }
+                        ProxyProto::socks4(userid)
+                    }
+                    #[cfg(feature = "shadowsocks")]
+                    "shadowsocks" | "ss" => {
+                        let method = props
+                            .get("method")
+                            .context("shadowsocks method not specified")?
+                            .parse()
+                            .map_err(|_| anyhow!("unsupported shadowsocks method"))?;
+                        let password = props
+                            .get("password")
+                            .context("shadowsocks password not specified")?;
+                        ProxyProto::shadowsocks(method, password)
+                    }
+                    #[cfg(feature = "ssh")]
+                    "ssh" => {
+                        let user = props.get("ssh user").context("ssh user not specified")?;
+                        if send_proxy_protocol {
+                            bail!("send proxy protocol is not supported with ssh");
+                        }
+                        ProxyProto::ssh(SshOptions {
+                            user: user.into(),
+                            host: host.into(),
+                            key_file: props.get("ssh key file").map(PathBuf::from),
+                            known_hosts: props.get("ssh known hosts").map(PathBuf::from),
+                        })
                     }
                     "http" => {
                         let cwp = props
@@ -358,6 +800,54 @@ impl ServerListConfig {
                     }
                     _ => bail!("unknown proxy protocol"),
                 };
+                let tls = props
+                    .get("tls")
+                    .parse()
+                    .context("not a boolean value")?
+                    .unwrap_or(false);
+                if tls && proto.support_udp() {
+                    bail!("socks udp is not supported over TLS");
+                }
+                #[cfg(feature = "ssh")]
+                if tls && matches!(proto, ProxyProto::Ssh(_)) {
+                    bail!("tls is not supported with ssh");
+                }
+                #[cfg(not(feature = "tls"))]
+                if tls {
+                    bail!("TLS support is not enabled on compile time");
+                }
+                #[cfg(feature = "tls")]
+                let tls = if tls {
+                    // Default to the host part of address
+                    let sni = match props.get("tls sni") {
+                        Some(sni) => sni,
+                        None if host.parse::<IpAddr>().is_ok() => {
+                            bail!("tls sni must be set if address is an IP address")
+                        }
+                        None => host,
+                    };
+                    let client_cert = match (props.get("tls cert file"), props.get("tls key file"))
+                    {
+                        (None, None) => None,
+                        (Some(cert), Some(key)) => Some((Path::new(cert), Path::new(key))),
+                        _ => bail!("tls cert file & tls key file must be set together"),
+                    };
+                    let skip_verify = props
+                        .get("tls skip verify")
+                        .parse()
+                        .context("not a boolean value")?
+                        .unwrap_or(false);
+                    let config = TlsConfig::new(
+                        sni,
+                        props.get("tls ca file").map(Path::new),
+                        client_cert,
+                        skip_verify,
+                    )
+                    .context("fail to setup TLS")?;
+                    Some(config)
+                } else {
+                    None
+                };
                 let server = ProxyServer::new(
                     addr,
                     proto,
@@ -366,9 +856,27 @@ impl ServerListConfig {
                     Some(capabilities),
                     tag,
                     base,
-                );
-                servers.push(Arc::new(server));
+                )
+                .with_proxy_protocol(send_proxy_protocol)
+                .with_bind(bind);
+                #[cfg(feature = "tls")]
+                let server = server.with_tls(tls);
+                // Re-resolved periodically if it's a host name
+                let server = match address.parse::<SocketAddr>() {
+                    Ok(_) => server,
+                    Err(_) => server.with_host(host, addrs),
+                };
+                let server = Arc::new(server);
+                if let Some(via) = via {
+                    if server.proto.support_udp() {
+                        bail!("socks udp is not supported with via");
+                    }
+                    chained.push((server.clone(), via));
+                }
+                loaded.push(server);
             }
+            link_chains(&loaded, &chained)?;
+            servers.extend(loaded);
         }
         if servers.is_empty() && !self.allow_direct {
             bail!("missing server list");
@@ -377,3 +885,79 @@ impl ServerListConfig {
         Ok(servers)
     }
 }
+
+/// Set `via` of servers in `chained` to the servers with given tags.
+/// Fail if any tag not found, duplicated (only those referred by `via`),
+/// or there is a loop.
+fn link_chains(
+    servers: &[Arc<ProxyServer>],
+    chained: &[(Arc<ProxyServer>, &str)],
+) -> anyhow::Result<()> {
+    let vias: HashMap<&str, &str> = chained
+        .iter()
+        .map(|(server, via)| (server.tag.as_str(), *via))
+        .collect();
+    for (server, via) in chained {
+        let mut visited = vec![server.tag.as_str()];
+        let mut next = Some(*via);
+        while let Some(tag) = next {
+            if visited.contains(&tag) {
+                bail!(
+                    "loop in via of server {}: {} -> {}",
+                    server.tag,
+                    visited.join(" -> "),
+                    tag
+                );
+            }
+            visited.push(tag);
+            next = vias.get(tag).copied();
+        }
+        let mut parents = servers.iter().filter(|s| s.tag.as_str() == *via);
+        let parent = parents
+            .next()
+            .with_context(|| format!("via server {} of {} not found", via, server.tag))?;
+        if parents.next().is_some() {
+            bail!(
+                "via server {} of {} is ambiguous, duplicate tag",
+                via,
+                server.tag
+            );
+        }
+        server.set_via(Some(parent.clone()));
+    }
+    Ok(())
+}
+
+#[test]
+fn test_link_chains() {
+    let server = |tag: &str| {
+        Arc::new(ProxyServer::new(
+            "127.0.0.1:1080".parse().unwrap(),
+            ProxyProto::socks5(false),
+            "127.0.0.1:53".parse().unwrap(),
+            Duration::from_secs(1),
+            None,
+            Some(tag),
+            None,
+        ))
+    };
+    let (a, b, c) = (server("a"), server("b"), server("c"));
+    let servers = vec![a.clone(), b.clone(), c.clone()];
+    link_chains(&servers, &[(a.clone(), "b"), (b.clone(), "c")]).unwrap();
+    assert!(Arc::ptr_eq(&b, &a.via().unwrap()));
+    assert!(Arc::ptr_eq(&c, &b.via().unwrap()));
+    assert!(c.via().is_none());
+
+    let err = link_chains(&servers, &[(a.clone(), "b"), (b.clone(), "a")]).unwrap_err();
+    assert!(err.to_string().contains("loop"));
+    let err = link_chains(&servers, &[(a.clone(), "a")]).unwrap_err();
+    assert!(err.to_string().contains("loop"));
+    let err = link_chains(&servers, &[(a.clone(), "x")]).unwrap_err();
+    assert!(err.to_string().contains("not found"));
+    let servers = vec![a.clone(), b.clone(), server("b")];
+    let err = link_chains(&servers, &[(a.clone(), "b")]).unwrap_err();
+    assert!(err.to_string().contains("duplicate"));
+    // Fine unless referred by via
+    let servers = vec![a.clone(), b.clone(), c.clone(), server("c")];
+    link_chains(&servers, &[(a.clone(), "b")]).unwrap();
+}
@@ -0,0 +1,75 @@
+//! Fixtures shared by tests across modules.
+use std::{future::Future, net::SocketAddr, sync::Arc, time::Duration};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpSocket, TcpStream},
+    task::JoinHandle,
+    time::timeout,
+};
+
+use crate::proxy::{ProxyProto, ProxyServer};
+
+pub fn socks4_server(addr: SocketAddr) -> Arc<ProxyServer> {
+    ProxyServer::new(
+        addr,
+        ProxyProto::Socks4 { userid: None },
+        "127.0.0.1:53".parse().unwrap(),
+        Duration::from_secs(5),
+        None,
+        None,
+        None,
+    )
+    .into()
+}
+
+/// SOCKSv4 upstream that accepts one request, grants it only if `grant`,
+/// then hands the connection over to `then`.
+pub async fn fake_socks4<F, Fut, T>(grant: bool, then: F) -> (Arc<ProxyServer>, JoinHandle<T>)
+where
+    F: FnOnce(TcpStream) -> Fut + Send + 'static,
+    Fut: Future<Output = T> + Send,
+    T: Send + 'static,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let server = socks4_server(listener.local_addr().unwrap());
+    let task = tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut buf = [0u8; 9];
+        stream.read_exact(&mut buf).await.unwrap();
+        if grant {
+            stream
+                .write_all(&[0, 0x5a, 0, 0, 0, 0, 0, 0])
+                .await
+                .unwrap();
+        }
+        then(stream).await
+    });
+    (server, task)
+}
+
+/// Address that refuses connections for as long as the returned socket
+/// is kept: it's bound but never listening, so no one else can take it.
+pub fn refused_addr() -> (SocketAddr, TcpSocket) {
+    let socket = TcpSocket::new_v4().unwrap();
+    socket.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+    (socket.local_addr().unwrap(), socket)
+}
+
+/// Address that never completes TCP handshake, as long as the returned
+/// guard is kept: its listener never accepts and the backlog is filled up,
+/// so further SYNs are dropped.
+pub async fn stalled_addr() -> (SocketAddr, (TcpListener, Vec<TcpStream>)) {
+    let socket = TcpSocket::new_v4().unwrap();
+    socket.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+    let listener = socket.listen(1).unwrap();
+    let addr = listener.local_addr().unwrap();
+    let mut queued = Vec::new();
+    loop {
+        match timeout(Duration::from_millis(100), TcpStream::connect(addr)).await {
+            Ok(stream) => queued.push(stream.unwrap()),
+            Err(_) => break,
+        }
+        assert!(queued.len() < 64, "backlog never filled up");
+    }
+    (addr, (listener, queued))
+}
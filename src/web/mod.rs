@@ -18,9 +18,7 @@ use serde_derive::Serialize;
 use std::{
     error::Error,
     fmt::Write,
-    fs,
     net::SocketAddr,
-    path::Path,
     sync::Arc,
     time::{Duration, Instant},
 };
@@ -32,7 +30,7 @@ use tokio::{
 use tracing::{info, instrument, warn};
 
 use crate::{
-    futures_stream::{TcpListenerStream, UnixListenerStream},
+    futures_stream::{AutoRemoveFile, TcpListenerStream, UnixListenerStream},
     monitor::{Monitor, Throughput},
     proxy::{Delay, ProxyServer},
 };
@@ -240,7 +238,8 @@ enum Listener {
     #[cfg(unix)]
     Unix {
         listener: UnixListener,
-        file: AutoRemoveFile,
+        /// `None` if it's not created by us.
+        file: Option<AutoRemoveFile>,
     },
 }
 
@@ -282,7 +281,10 @@ impl WebServer {
                 info!("Web console listen on unix:{}", addr);
                 let file = AutoRemoveFile(addr.clone());
                 let listener = UnixListener::bind(&file).context("fail to bind web server")?;
-                Listener::Unix { listener, file }
+                Listener::Unix {
+                    listener,
+                    file: Some(file),
+                }
             }
         };
         Ok(WebServerListener {
@@ -293,6 +295,31 @@ impl WebServer {
 }
 
 impl WebServerListener {
+    /// Serve on a TCP listener opened elsewhere, e.g. passed by systemd.
+    pub fn from_tcp(monitor: Monitor, listener: TcpListener) -> Self {
+        if let Ok(addr) = listener.local_addr() {
+            info!("Web console listen on tcp:{}", addr);
+        }
+        Self {
+            monitor,
+            listener: Listener::Tcp(listener),
+        }
+    }
+
+    /// Serve on a Unix listener opened elsewhere, e.g. passed by systemd.
+    /// Its file won't be removed by us.
+    #[cfg(unix)]
+    pub fn from_unix(monitor: Monitor, listener: UnixListener) -> Self {
+        info!("Web console listen on pre-opened unix socket");
+        Self {
+            monitor,
+            listener: Listener::Unix {
+                listener,
+                file: None,
+            },
+        }
+    }
+
     pub fn run_background(self) {
         match self.listener {
             Listener::Tcp(tcp) => {
@@ -336,20 +363,3 @@ where
     }
     warn!("web server stopped");
 }
-
-/// File on this path will be removed on `drop()`.
-struct AutoRemoveFile(SharedStr);
-
-impl Drop for AutoRemoveFile {
-    fn drop(&mut self) {
-        if let Err(err) = fs::remove_file(self.0.as_str()) {
-            warn!("fail to remove {}: {}", self.0, err);
-        }
-    }
-}
-
-impl AsRef<Path> for AutoRemoveFile {
-    fn as_ref(&self) -> &Path {
-        self.0.as_str().as_ref()
-    }
-}
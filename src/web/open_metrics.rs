@@ -4,8 +4,13 @@ use std::{
     time::Instant,
 };
 
+use flexstr::SharedStr;
+
 use super::{ServerStatus, Status};
-use crate::{monitor::Monitor, proxy::Delay};
+use crate::{
+    monitor::Monitor,
+    proxy::{Delay, Traffic},
+};
 
 const CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
 
@@ -31,6 +36,29 @@ where
     }
 }
 
+fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn each_user<F>(buf: &mut String, name: &str, users: &[(SharedStr, Traffic)], metric: F)
+where
+    F: Fn(&Traffic) -> usize,
+{
+    for (user, traffic) in users {
+        writeln!(
+            buf,
+            "moproxy_{}{{user=\"{}\"}} {}",
+            name,
+            escape_label_value(user),
+            metric(traffic)
+        )
+        .unwrap();
+    }
+}
+
 pub fn exporter(start_time: &Instant, monitor: &Monitor) -> Response<Body> {
     let status = Status::from(start_time, monitor);
     let mut buf = String::new();
@@ -62,6 +90,11 @@ pub fn exporter(start_time: &Instant, monitor: &Monitor) -> Response<Body> {
         "Current number of connections closed with error",
         |s| Some(s.server.status_snapshot().conn_error)
     );
+    server_gauge!(
+        "proxy_server_connections_bad_response",
+        "Current number of connections dropped due to unexpected response",
+        |s| Some(s.server.status_snapshot().conn_bad_response)
+    );
     server_gauge!(
         "proxy_server_connections_total",
         "Current total number of connections",
@@ -81,6 +114,24 @@ pub fn exporter(start_time: &Instant, monitor: &Monitor) -> Response<Body> {
         |s| s.server.status_snapshot().score
     );
 
+    let users = monitor.users_traffic();
+    if !users.is_empty() {
+        new_metric(
+            &mut buf,
+            "user_bytes_tx_total",
+            "gauge",
+            "Current total of outgoing bytes from the user",
+        );
+        each_user(&mut buf, "user_bytes_tx_total", &users, |t| t.tx_bytes);
+        new_metric(
+            &mut buf,
+            "user_bytes_rx_total",
+            "gauge",
+            "Current total of incoming bytes to the user",
+        );
+        each_user(&mut buf, "user_bytes_rx_total", &users, |t| t.rx_bytes);
+    }
+
     writeln!(buf, "# EOF").unwrap();
     Response::builder()
         .header("Content-Type", CONTENT_TYPE)
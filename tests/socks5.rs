@@ -1,9 +1,15 @@
-use moproxy::proxy::socks5::handshake;
+use moproxy::proxy::{
+    socks5::{
+        build_udp_header, handshake, parse_udp_header, reply_code, udp_associate, UdpRelay,
+        REP_CONNECTION_REFUSED,
+    },
+    BindOptions, Destination,
+};
 use std::net::SocketAddr;
 use tokio::{
     self,
     io::{AsyncReadExt, AsyncWriteExt},
-    net::{TcpListener, TcpStream},
+    net::{TcpListener, TcpStream, UdpSocket},
 };
 
 #[tokio::test]
@@ -43,6 +49,33 @@ async fn test_socks5_domain() {
     assert_eq!(&buf[..n], b"response");
 }
 
+#[tokio::test]
+async fn test_socks5_reply_error() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut buf = [0u8; 128];
+        stream.read_exact(&mut buf[..3]).await.unwrap();
+        stream.write_all(&[5, 0]).await.unwrap(); // no auth
+        stream.read_exact(&mut buf[..10]).await.unwrap();
+        stream
+            .write_all(&[5, REP_CONNECTION_REFUSED, 0, 1, 0, 0, 0, 0, 0, 0])
+            .await
+            .unwrap();
+    });
+
+    let mut stream = TcpStream::connect(&addr).await.unwrap();
+    let dest = "192.0.2.1:80".parse::<SocketAddr>().unwrap().into();
+    let err = handshake(&mut stream, &dest, None::<&[u8]>, false, &None)
+        .await
+        .unwrap_err();
+    assert_eq!(std::io::ErrorKind::ConnectionRefused, err.kind());
+    // Passed through to our client as it is
+    assert_eq!(REP_CONNECTION_REFUSED, reply_code(&err));
+}
+
 #[tokio::test]
 async fn test_socks5_ipv6() {
     let listener = TcpListener::bind("[::1]:0").await.unwrap();
@@ -87,3 +120,58 @@ async fn test_socks5_ipv6() {
     let n = stream.read(&mut buf).await.unwrap();
     assert_eq!(&buf[..n], b"response");
 }
+
+#[tokio::test]
+async fn test_socks5_udp_associate() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let relay = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let relay_port = relay.local_addr().unwrap().port();
+
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut buf = [0u8; 128];
+        stream.read_exact(&mut buf[..3]).await.unwrap();
+        assert_eq!(&[5, 1, 0], &buf[..3]); // ver 5, no auth
+        stream.write_all(&[5, 0]).await.unwrap(); // no auth
+
+        stream.read_exact(&mut buf[..10]).await.unwrap();
+        assert_eq!(&[5, 3, 0, 1, 0, 0, 0, 0, 0, 0], &buf[..10]);
+        let port = relay_port.to_be_bytes();
+        stream
+            .write_all(&[5, 0, 0, 1, 0, 0, 0, 0, port[0], port[1]])
+            .await
+            .unwrap();
+
+        let (len, from) = relay.recv_from(&mut buf).await.unwrap();
+        let (dest, header_len) = parse_udp_header(&buf[..len]).unwrap();
+        assert_eq!(Destination::from(("example.com", 53)), dest);
+        assert_eq!(b"request", &buf[header_len..len]);
+
+        let mut reply = vec![];
+        build_udp_header(
+            &mut reply,
+            &"192.0.2.1:53".parse::<SocketAddr>().unwrap().into(),
+        );
+        reply.extend_from_slice(b"response");
+        relay.send_to(&reply, from).await.unwrap();
+        // keep the control connection open
+        let _ = stream.read(&mut buf).await;
+    });
+
+    let stream = TcpStream::connect(&addr).await.unwrap();
+    let UdpRelay { socket, .. } = udp_associate(stream, &None, &BindOptions::default())
+        .await
+        .unwrap();
+    let mut buf = vec![];
+    build_udp_header(&mut buf, &("example.com", 53).into());
+    buf.extend_from_slice(b"request");
+    socket.send(&buf).await.unwrap();
+
+    let mut buf = [0u8; 128];
+    let len = socket.recv(&mut buf).await.unwrap();
+    let (src, header_len) = parse_udp_header(&buf[..len]).unwrap();
+    let expected: SocketAddr = "192.0.2.1:53".parse().unwrap();
+    assert_eq!(Destination::from(expected), src);
+    assert_eq!(b"response", &buf[header_len..len]);
+}